@@ -0,0 +1,36 @@
+use std::collections::HashMap;
+
+use algorithm::buf::{Bt, BtMut};
+use criterion::{criterion_group, criterion_main, Criterion};
+use hcproto::{decode_proto, encode_proto, Buffer, Value};
+
+fn map_10k() -> Value {
+    let map: HashMap<Value, Value> = (0..10_000)
+        .map(|i| (Value::Str(format!("key_{}", i)), Value::Varint(i)))
+        .collect();
+    Value::Map(map)
+}
+
+fn bench_map(c: &mut Criterion) {
+    let map = map_10k();
+    let mut buffer = Buffer::new();
+    encode_proto(&mut buffer, "cmd", vec![map.clone()]).unwrap();
+    let bytes = buffer.chunk().to_vec();
+
+    c.bench_function("decode map 10k", |b| {
+        b.iter(|| {
+            let mut buffer = Buffer::new();
+            buffer.put_slice(&bytes);
+            decode_proto(&mut buffer).unwrap()
+        })
+    });
+
+    let Value::Map(inner) = &map else { unreachable!() };
+    let keys: Vec<Value> = inner.keys().cloned().collect();
+    c.bench_function("lookup map 10k", |b| {
+        b.iter(|| keys.iter().filter(|k| inner.contains_key(*k)).count())
+    });
+}
+
+criterion_group!(benches, bench_map);
+criterion_main!(benches);
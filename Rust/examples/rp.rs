@@ -1,9 +1,8 @@
 use algorithm::buf::Bt;
-use hcproto::{Buffer, Value};
+use hcproto::{value, Buffer};
 use std::time::SystemTime;
 
 mod test_data;
-use std::collections::HashMap;
 
 fn test_level4_json() {
     let mut now = SystemTime::now();
@@ -12,7 +11,7 @@ fn test_level4_json() {
     now = SystemTime::now();
     // println!("ok!!! parsed= {:?}", parsed);
     let mut buffer = Buffer::new();
-    hcproto::encode_proto(&mut buffer, &"cmd_level4_full".to_string(), vec![parsed]).unwrap();
+    hcproto::encode_proto(&mut buffer, "cmd_level4_full", vec![parsed]).unwrap();
     println!(
         "用tunm_proto压缩test_level4_json的长度 = {}k，耗时：{:?}",
         buffer.remaining() / 1024,
@@ -23,30 +22,33 @@ fn test_level4_json() {
     now = SystemTime::now();
     let read = hcproto::decode_proto(&mut buffer).unwrap();
     println!("解析buffer耗时 = {:?}", now.elapsed());
-    match read {
-        (name, _val) => {
-            assert_eq!(name, "cmd_level4_full".to_string());
-            // println!("value === {:?}", val);
-        }
-    }
+    let (name, _val) = read;
+    assert_eq!(name, "cmd_level4_full".to_string());
+    // println!("value === {:?}", val);
+
+    now = SystemTime::now();
+    let streamed = test_data::stream_json();
+    println!(
+        "流式转换JSON的长度 = {}k，耗时：{:?}",
+        streamed.len() / 1024,
+        now.elapsed()
+    );
 }
 
 fn main() {
     println!("welcome to tickdream rust protocol");
     test_level4_json();
-    let mut hash_value = HashMap::<Value, Value>::new();
-    hash_value.insert(
-        Value::Str("name".to_string()),
-        Value::Str("tunm_proto".to_string()),
-    );
-    hash_value.insert(Value::Str("tunm_proto".to_string()), Value::U16(1 as u16));
+    let hash_value = value! {
+        "name": "tunm_proto",
+        "tunm_proto": 1u16,
+    };
 
     {
         let mut buffer = Buffer::new();
         hcproto::encode_proto(
             &mut buffer,
-            &"cmd_test_op".to_string(),
-            vec![Value::Map(hash_value.clone())],
+            "cmd_test_op",
+            vec![hash_value.clone()],
         )
         .unwrap();
         let just_str = "
@@ -56,12 +58,9 @@ fn main() {
         println!("用tunm_proto的长度 = {}", buffer.remaining());
         // just read field
         let read = hcproto::decode_proto(&mut buffer).unwrap();
-        match read {
-            (name, val) => {
-                assert_eq!(name, "cmd_test_op".to_string());
-                assert_eq!(val[0], Value::Map(hash_value));
-                assert_eq!(val.len(), 1);
-            }
-        }
+        let (name, val) = read;
+        assert_eq!(name, "cmd_test_op".to_string());
+        assert_eq!(val[0], hash_value);
+        assert_eq!(val.len(), 1);
     }
 }
@@ -0,0 +1,383 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::ops;
+
+use crate::values::{get_type_by_value, integer, type_not_match};
+use crate::{HpResult, Value, ValueDescType};
+
+static NIL: Value = Value::Nil;
+
+/// A key to look up in a [`Value`]: a `&str` or `String` for a string key of
+/// a `Map`, a `usize` for an item of an `Arr` or an integer key of a `Map`,
+/// or any `Value` as a `Map` key.
+pub trait ValueIndex {
+    /// The item at this key, if `value` has one.
+    fn index_into<'v>(&self, value: &'v Value) -> Option<&'v Value>;
+
+    fn index_into_mut<'v>(&self, value: &'v mut Value) -> Option<&'v mut Value>;
+
+    /// The item at this key, inserting `Nil` first if it is missing.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `value` cannot hold this key, or for an index past the end
+    /// of an `Arr`.
+    fn index_or_insert<'v>(&self, value: &'v mut Value) -> &'v mut Value;
+
+    fn remove_from(&self, value: &mut Value) -> Option<Value>;
+}
+
+impl ValueIndex for str {
+    fn index_into<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        match value {
+            Value::Map(map) => map.get(&Value::from(self)),
+            _ => None,
+        }
+    }
+
+    fn index_into_mut<'v>(&self, value: &'v mut Value) -> Option<&'v mut Value> {
+        match value {
+            Value::Map(map) => map.get_mut(&Value::from(self)),
+            _ => None,
+        }
+    }
+
+    fn index_or_insert<'v>(&self, value: &'v mut Value) -> &'v mut Value {
+        if value.is_nil() {
+            *value = Value::Map(HashMap::new());
+        }
+        match value {
+            Value::Map(map) => map.entry(Value::from(self)).or_insert(Value::Nil),
+            _ => panic!("cannot index {} with a str key", kind(value)),
+        }
+    }
+
+    fn remove_from(&self, value: &mut Value) -> Option<Value> {
+        match value {
+            Value::Map(map) => map.remove(&Value::from(self)),
+            _ => None,
+        }
+    }
+}
+
+impl ValueIndex for String {
+    fn index_into<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        self.as_str().index_into(value)
+    }
+
+    fn index_into_mut<'v>(&self, value: &'v mut Value) -> Option<&'v mut Value> {
+        self.as_str().index_into_mut(value)
+    }
+
+    fn index_or_insert<'v>(&self, value: &'v mut Value) -> &'v mut Value {
+        self.as_str().index_or_insert(value)
+    }
+
+    fn remove_from(&self, value: &mut Value) -> Option<Value> {
+        self.as_str().remove_from(value)
+    }
+}
+
+/// Looks up the item of an `Arr`, or the integer key of a `Map`, whatever
+/// its width, since integer keys hash by value.
+impl ValueIndex for usize {
+    fn index_into<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        match value {
+            Value::Arr(arr) => arr.get(*self),
+            Value::Map(map) => map.get(&int_key(*self)),
+            _ => None,
+        }
+    }
+
+    fn index_into_mut<'v>(&self, value: &'v mut Value) -> Option<&'v mut Value> {
+        match value {
+            Value::Arr(arr) => arr.get_mut(*self),
+            Value::Map(map) => map.get_mut(&int_key(*self)),
+            _ => None,
+        }
+    }
+
+    fn index_or_insert<'v>(&self, value: &'v mut Value) -> &'v mut Value {
+        match value {
+            Value::Arr(arr) => {
+                let len = arr.len();
+                arr.get_mut(*self)
+                    .unwrap_or_else(|| panic!("index {} out of range for an arr of {}", self, len))
+            }
+            Value::Map(map) => {
+                map.entry(int_key(*self)).or_insert(Value::Nil)
+            }
+            _ => panic!("cannot index {} with a usize", kind(value)),
+        }
+    }
+
+    fn remove_from(&self, value: &mut Value) -> Option<Value> {
+        match value {
+            Value::Arr(arr) if *self < arr.len() => Some(arr.remove(*self)),
+            Value::Map(map) => map.remove(&int_key(*self)),
+            _ => None,
+        }
+    }
+}
+
+/// The `Map` key of an index, equal to an integer key of any width.
+fn int_key(index: usize) -> Value {
+    Value::U64(index as u64)
+}
+
+/// Looks up a key of any type in a `Map`.
+impl ValueIndex for Value {
+    fn index_into<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        match value {
+            Value::Map(map) => map.get(self),
+            _ => None,
+        }
+    }
+
+    fn index_into_mut<'v>(&self, value: &'v mut Value) -> Option<&'v mut Value> {
+        match value {
+            Value::Map(map) => map.get_mut(self),
+            _ => None,
+        }
+    }
+
+    fn index_or_insert<'v>(&self, value: &'v mut Value) -> &'v mut Value {
+        if value.is_nil() {
+            *value = Value::Map(HashMap::new());
+        }
+        match value {
+            Value::Map(map) => map.entry(self.clone()).or_insert(Value::Nil),
+            _ => panic!("cannot index {} with a value key", kind(value)),
+        }
+    }
+
+    fn remove_from(&self, value: &mut Value) -> Option<Value> {
+        match value {
+            Value::Map(map) => map.remove(self),
+            _ => None,
+        }
+    }
+}
+
+impl<T: ValueIndex + ?Sized> ValueIndex for &T {
+    fn index_into<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        (**self).index_into(value)
+    }
+
+    fn index_into_mut<'v>(&self, value: &'v mut Value) -> Option<&'v mut Value> {
+        (**self).index_into_mut(value)
+    }
+
+    fn index_or_insert<'v>(&self, value: &'v mut Value) -> &'v mut Value {
+        (**self).index_or_insert(value)
+    }
+
+    fn remove_from(&self, value: &mut Value) -> Option<Value> {
+        (**self).remove_from(value)
+    }
+}
+
+fn kind(value: &Value) -> ValueDescType {
+    ValueDescType::from(get_type_by_value(value))
+}
+
+/// `value["players"][2]["name"]`, which is `Nil` when any step is missing.
+impl<I: ValueIndex> ops::Index<I> for Value {
+    type Output = Value;
+
+    fn index(&self, index: I) -> &Value {
+        index.index_into(self).unwrap_or(&NIL)
+    }
+}
+
+/// `value["name"] = ...`, inserting the key if it is missing, and turning a
+/// `Nil` into a `Map` for a string key.
+///
+/// # Panics
+///
+/// Panics if the value cannot hold the key, or for an index past the end of
+/// an `Arr`.
+impl<I: ValueIndex> ops::IndexMut<I> for Value {
+    fn index_mut(&mut self, index: I) -> &mut Value {
+        index.index_or_insert(self)
+    }
+}
+
+/// Accessors, with the numeric coercion of `TryFrom`: integers of any width
+/// convert when the value fits.
+impl Value {
+    /// An integer of any width that fits in `i64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        integer(self).and_then(|v| i64::try_from(v).ok())
+    }
+
+    /// An integer of any width that fits in `u64`.
+    pub fn as_u64(&self) -> Option<u64> {
+        integer(self).and_then(|v| u64::try_from(v).ok())
+    }
+
+    /// An `F32`, an `F64`, or an integer, rounded to the nearest `f64` past
+    /// 2^53.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::F32(v) => Some(v as f64),
+            Value::F64(v) => Some(v),
+            _ => integer(self).map(|v| v as f64),
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Raw(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Value>> {
+        match self {
+            Value::Arr(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array_mut(&mut self) -> Option<&mut Vec<Value>> {
+        match self {
+            Value::Arr(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&HashMap<Value, Value>> {
+        match self {
+            Value::Map(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_map_mut(&mut self) -> Option<&mut HashMap<Value, Value>> {
+        match self {
+            Value::Map(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The item at `index`, see [`ValueIndex`].
+    pub fn get<I: ValueIndex>(&self, index: I) -> Option<&Value> {
+        index.index_into(self)
+    }
+
+    pub fn get_mut<I: ValueIndex>(&mut self, index: I) -> Option<&mut Value> {
+        index.index_into_mut(self)
+    }
+
+    /// Removes the item at `index`, shifting the rest of an `Arr` down.
+    pub fn remove<I: ValueIndex>(&mut self, index: I) -> Option<Value> {
+        index.remove_from(self)
+    }
+
+    /// Inserts into a `Map`, giving back the old value of the key. A `Nil`
+    /// becomes an empty `Map` first.
+    pub fn insert<K: Into<Value>, V: Into<Value>>(&mut self, key: K, value: V) -> HpResult<Option<Value>> {
+        Ok(self.map_or_init()?.insert(key.into(), value.into()))
+    }
+
+    /// The entry of `key` in a `Map`. A `Nil` becomes an empty `Map` first.
+    pub fn entry<K: Into<Value>>(&mut self, key: K) -> HpResult<Entry<'_, Value, Value>> {
+        Ok(self.map_or_init()?.entry(key.into()))
+    }
+
+    /// Appends to an `Arr`. A `Nil` becomes an empty `Arr` first.
+    pub fn push<V: Into<Value>>(&mut self, value: V) -> HpResult<()> {
+        if self.is_nil() {
+            *self = Value::Arr(Vec::new());
+        }
+        match self {
+            Value::Arr(arr) => {
+                arr.push(value.into());
+                Ok(())
+            }
+            _ => Err(type_not_match("arr", self)),
+        }
+    }
+
+    /// Takes the value out, leaving `Nil` in its place.
+    pub fn take(&mut self) -> Value {
+        std::mem::replace(self, Value::Nil)
+    }
+
+    fn map_or_init(&mut self) -> HpResult<&mut HashMap<Value, Value>> {
+        if self.is_nil() {
+            *self = Value::Map(HashMap::new());
+        }
+        match self {
+            Value::Map(map) => Ok(map),
+            _ => Err(type_not_match("map", self)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ErrorKind, Value};
+
+    #[test]
+    fn test_access() {
+        let mut data: Value = r#"{players: [{name: "a"}, {name: "b"}, {name: "c", hp: 300u16}], 7u8: true}"#
+            .parse()
+            .unwrap();
+        assert_eq!(data["players"][2]["name"].as_str(), Some("c"));
+        assert_eq!(data["players"][2]["hp"].as_u64(), Some(300));
+        assert_eq!(data["players"][2]["hp"].as_f64(), Some(300.0));
+        assert!(data["players"][9]["name"].is_nil());
+        assert!(data["missing"]["name"].is_nil());
+        assert_eq!(data[7].as_bool(), Some(true));
+        assert_eq!(data.get(Value::U8(7)), Some(&Value::Bool(true)));
+        assert_eq!(data["players"].as_array().map(Vec::len), Some(3));
+        assert!(data.get("players").unwrap().get("name").is_none());
+
+        data["players"][0]["name"] = Value::from("z");
+        data["players"][0]["level"] = Value::U8(3);
+        assert_eq!(data["players"][0]["name"].as_str(), Some("z"));
+        assert_eq!(data["players"][0]["level"].as_i64(), Some(3));
+
+        let removed = data["players"].remove(1).unwrap();
+        assert_eq!(removed["name"].as_str(), Some("b"));
+        data["players"].push(Value::from("d")).unwrap();
+        assert_eq!(data["players"][2].as_str(), Some("d"));
+        assert_eq!(data["players"].insert("k", 1u8).unwrap_err().kind(), ErrorKind::TypeNotMatchError);
+
+        let mut fresh = Value::Nil;
+        fresh["a"]["b"] = Value::from(1u8);
+        assert_eq!(fresh.insert("c", 2u8).unwrap(), None);
+        *fresh.entry("c").unwrap().or_insert(Value::Nil) = Value::from(5u8);
+        assert_eq!(fresh["c"], Value::U8(5));
+        assert_eq!(fresh["a"].take()["b"], Value::U8(1));
+        assert!(fresh["a"].is_nil());
+
+        // An index past `i64::MAX` finds a `U64` key, not a wrapped negative one.
+        let mut wide = Value::Map([(Value::U64(u64::MAX), Value::Bool(true))].into());
+        wide[usize::MAX - 1] = Value::Nil;
+        assert_eq!(wide.get(usize::MAX), Some(&Value::Bool(true)));
+        assert_eq!(wide.remove(usize::MAX), Some(Value::Bool(true)));
+        assert!(wide.get(Value::Varint(-1)).is_none());
+        assert_eq!(wide[Value::U64(u64::MAX - 1)], Value::Nil);
+
+        assert_eq!(Value::U64(u64::MAX).as_i64(), None);
+        assert_eq!(Value::I8(-1).as_u64(), None);
+        assert_eq!(Value::Str("x".to_string()).as_i64(), None);
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use algorithm::buf::{Bt, BtMut};
+use algorithm::{DefaultHasher, LruCache};
+
+use crate::patch::same;
+use crate::values::type_not_match;
+use crate::{decode_proto, Buffer, Encoder, ErrorKind, HpError, HpResult, Value};
+
+const REPLACE: u8 = 0;
+const ARR: u8 = 1;
+const MAP: u8 = 2;
+const REMOVED: u8 = 3;
+
+/// A message kept to encode against, or to rebuild from.
+struct Baseline {
+    name: String,
+    fields: Value,
+}
+
+struct Connection {
+    next_id: u32,
+    baselines: LruCache<u32, Baseline, DefaultHasher>,
+}
+
+/// The last messages of each connection, bounded both in connections and in
+/// messages per connection; the least recently used go first.
+pub struct BaselineStore<K> {
+    connections: LruCache<K, Connection, DefaultHasher>,
+    per_connection: usize,
+}
+
+impl<K: Hash + Eq> BaselineStore<K> {
+    pub fn new(connections: usize, per_connection: usize) -> BaselineStore<K> {
+        BaselineStore {
+            connections: LruCache::new(connections),
+            per_connection: per_connection.max(1),
+        }
+    }
+
+    /// Forgets a connection, when it closes.
+    pub fn remove(&mut self, conn: &K) {
+        self.connections.remove(conn);
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    fn connection(&mut self, conn: K) -> &mut Connection {
+        let per_connection = self.per_connection;
+        self.connections.get_or_insert_mut(conn, || Connection {
+            next_id: 0,
+            baselines: LruCache::new(per_connection),
+        })
+    }
+}
+
+/// Encodes messages as changes to one the peer acknowledged.
+///
+/// A message is the `name` and `fields` of `encode_proto`. It goes out as a
+/// proto of the same name with three fields: its id, the id of the baseline
+/// or `nil`, and the changes. Each `Arr` or `Map` that changed in place is
+/// sent as a bitmask over its items (over its keys in [`Ord`] order for a
+/// `Map`) with the changed ones only, plus appended items or added keys;
+/// anything else that changed is sent whole. Without a baseline the
+/// message is sent whole.
+pub struct DeltaEncoder<K> {
+    store: BaselineStore<K>,
+    encoder: Encoder,
+}
+
+impl<K: Hash + Eq + Clone> DeltaEncoder<K> {
+    /// Keeps the last `per_connection` messages sent for up to `connections`
+    /// peers.
+    pub fn new(connections: usize, per_connection: usize) -> DeltaEncoder<K> {
+        DeltaEncoder {
+            store: BaselineStore::new(connections, per_connection),
+            encoder: Encoder::new(),
+        }
+    }
+
+    /// Encodes a message for `conn` against `ack`, the id of the last message
+    /// the peer acknowledged, returning the id of this one. A baseline that
+    /// is unknown, evicted, or of another name is not used.
+    pub fn encode<B: Bt + BtMut>(
+        &mut self,
+        buffer: &mut Buffer<B>,
+        conn: &K,
+        ack: Option<u32>,
+        name: &str,
+        fields: Vec<Value>,
+    ) -> HpResult<u32> {
+        let connection = self.store.connection(conn.clone());
+        let id = connection.next_id;
+        connection.next_id = id.wrapping_add(1);
+        let fields = Value::Arr(fields);
+        let baseline = ack.and_then(|ack| {
+            let baseline = connection.baselines.get(&ack)?;
+            (baseline.name == name).then_some((ack, &baseline.fields))
+        });
+        let (base, changes) = match baseline {
+            Some((ack, base)) => (
+                Value::Varint(ack as i64),
+                delta(base, &fields).unwrap_or_else(|| unchanged(&fields)),
+            ),
+            None => (Value::Nil, replace(&fields)),
+        };
+        self.encoder.encode_proto(buffer, name, &[Value::Varint(id as i64), base, changes])?;
+        connection.baselines.insert(id, Baseline { name: name.to_string(), fields });
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, conn: &K) {
+        self.store.remove(conn);
+    }
+}
+
+/// Rebuilds the messages of a [`DeltaEncoder`] from the ones it received.
+pub struct DeltaDecoder<K> {
+    store: BaselineStore<K>,
+}
+
+impl<K: Hash + Eq + Clone> DeltaDecoder<K> {
+    /// Keeps the last `per_connection` messages received for up to
+    /// `connections` peers, which should be at least what the encoder keeps.
+    pub fn new(connections: usize, per_connection: usize) -> DeltaDecoder<K> {
+        DeltaDecoder {
+            store: BaselineStore::new(connections, per_connection),
+        }
+    }
+
+    /// Decodes a message from `conn`, returning its id, to acknowledge, with
+    /// its name and full fields. Fails with `MissingError` when the baseline
+    /// it was encoded against is no longer stored.
+    pub fn decode<B: Bt + BtMut>(&mut self, buffer: &mut Buffer<B>, conn: &K) -> HpResult<(u32, String, Vec<Value>)> {
+        let bad = || HpError::from((ErrorKind::ParseError, "bad delta message"));
+        let (name, fields) = decode_proto(buffer)?;
+        let [id, base, changes]: [Value; 3] = fields.try_into().map_err(|_| bad())?;
+        let id = u32::try_from(id)?;
+        let connection = self.store.connection(conn.clone());
+        let mut fields = match base {
+            Value::Nil => Value::Nil,
+            base => {
+                let base = u32::try_from(base)?;
+                match connection.baselines.get(&base) {
+                    Some(baseline) if baseline.name == name => baseline.fields.clone(),
+                    _ => return Err(HpError::from((ErrorKind::MissingError, "delta baseline not found"))),
+                }
+            }
+        };
+        patch(&mut fields, changes)?;
+        if !matches!(fields, Value::Arr(_)) {
+            return Err(bad());
+        }
+        connection.baselines.insert(id, Baseline { name: name.clone(), fields: fields.clone() });
+        Ok((id, name, Vec::<Value>::try_from(fields)?))
+    }
+
+    pub fn remove(&mut self, conn: &K) {
+        self.store.remove(conn);
+    }
+}
+
+/// The changes to an `Arr` that did not change.
+fn unchanged(value: &Value) -> Value {
+    let len = value.as_array().map_or(0, Vec::len);
+    Value::Arr(vec![Value::U8(ARR), Value::Raw(vec![0; len.div_ceil(8)]), Value::Varint(len as i64)])
+}
+
+fn replace(value: &Value) -> Value {
+    Value::Arr(vec![Value::U8(REPLACE), value.clone()])
+}
+
+fn sorted_keys(map: &HashMap<Value, Value>) -> Vec<&Value> {
+    let mut keys: Vec<_> = map.keys().collect();
+    keys.sort_unstable();
+    keys
+}
+
+/// The changes from `old` to `new`, `None` if there are none.
+fn delta(old: &Value, new: &Value) -> Option<Value> {
+    match (old, new) {
+        (Value::Arr(old), Value::Arr(new)) => {
+            let common = old.len().min(new.len());
+            let mut mask = vec![0u8; common.div_ceil(8)];
+            let mut node = vec![Value::U8(ARR), Value::Nil, Value::Varint(new.len() as i64)];
+            for i in 0..common {
+                if let Some(change) = delta(&old[i], &new[i]) {
+                    mask[i / 8] |= 1 << (i % 8);
+                    node.push(change);
+                }
+            }
+            if node.len() == 3 && old.len() == new.len() {
+                return None;
+            }
+            node[1] = Value::Raw(mask);
+            node.extend(new[common..].iter().cloned());
+            Some(Value::Arr(node))
+        }
+        (Value::Map(old), Value::Map(new)) => {
+            let keys = sorted_keys(old);
+            let mut mask = vec![0u8; keys.len().div_ceil(8)];
+            let mut node = vec![Value::U8(MAP), Value::Nil];
+            for (i, key) in keys.iter().enumerate() {
+                let change = match new.get(*key) {
+                    Some(value) => delta(&old[*key], value),
+                    None => Some(Value::Arr(vec![Value::U8(REMOVED)])),
+                };
+                if let Some(change) = change {
+                    mask[i / 8] |= 1 << (i % 8);
+                    node.push(change);
+                }
+            }
+            let added: HashMap<Value, Value> = new
+                .iter()
+                .filter(|(key, _)| !old.contains_key(*key))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            if node.len() == 2 && added.is_empty() {
+                return None;
+            }
+            node[1] = Value::Raw(mask);
+            node.push(Value::Map(added));
+            Some(Value::Arr(node))
+        }
+        _ if same(old, new) => None,
+        _ => Some(replace(new)),
+    }
+}
+
+/// Applies the changes made by `delta` to a copy of its `old`.
+fn patch(value: &mut Value, changes: Value) -> HpResult<()> {
+    let bad = || HpError::from((ErrorKind::ParseError, "bad delta changes"));
+    let mut changes = Vec::<Value>::try_from(changes)?.into_iter();
+    let tag = u8::try_from(changes.next().ok_or_else(bad)?)?;
+    if tag == REPLACE {
+        *value = changes.next().ok_or_else(bad)?;
+        return Ok(());
+    }
+    let mask = Vec::<u8>::try_from(changes.next().ok_or_else(bad)?)?;
+    let changed = |i: usize| mask.get(i / 8).is_some_and(|b| b & (1 << (i % 8)) != 0);
+    match (tag, &mut *value) {
+        (ARR, Value::Arr(arr)) => {
+            let len = u32::try_from(changes.next().ok_or_else(bad)?)? as usize;
+            let common = arr.len().min(len);
+            if mask.len() != common.div_ceil(8) {
+                return Err(bad());
+            }
+            for (i, item) in arr.iter_mut().enumerate().take(common) {
+                if changed(i) {
+                    patch(item, changes.next().ok_or_else(bad)?)?;
+                }
+            }
+            arr.truncate(len);
+            arr.extend(changes);
+            if arr.len() != len {
+                return Err(bad());
+            }
+        }
+        (MAP, Value::Map(map)) => {
+            let keys: Vec<Value> = sorted_keys(map).into_iter().cloned().collect();
+            if mask.len() != keys.len().div_ceil(8) {
+                return Err(bad());
+            }
+            for (i, key) in keys.iter().enumerate() {
+                if !changed(i) {
+                    continue;
+                }
+                let change = changes.next().ok_or_else(bad)?;
+                if change.as_array().is_some_and(|v| v.len() == 1 && v[0] == Value::U8(REMOVED)) {
+                    map.remove(key);
+                } else if let Some(item) = map.get_mut(key) {
+                    patch(item, change)?;
+                }
+            }
+            let added = HashMap::<Value, Value>::try_from(changes.next().ok_or_else(bad)?)?;
+            map.extend(added);
+            if changes.next().is_some() {
+                return Err(bad());
+            }
+        }
+        (ARR, target) => return Err(type_not_match("arr", target)),
+        (MAP, target) => return Err(type_not_match("map", target)),
+        _ => return Err(bad()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use algorithm::buf::Bt;
+
+    use crate::{Buffer, DeltaDecoder, DeltaEncoder, ErrorKind, Value};
+
+    fn world(tick: u32) -> Vec<Value> {
+        let mut entities = Value::Nil;
+        for i in 0..100u32 {
+            let mut entity = Value::Nil;
+            entity.insert("x", Value::F32(i as f32)).unwrap();
+            entity.insert("y", Value::F32(if i < 2 { tick as f32 } else { 0.0 })).unwrap();
+            entity.insert("name", format!("entity {}", i)).unwrap();
+            entities.insert(i, entity).unwrap();
+        }
+        vec![Value::U32(tick), entities]
+    }
+
+    fn send(encoder: &mut DeltaEncoder<u32>, conn: u32, ack: Option<u32>, fields: Vec<Value>) -> (u32, Buffer) {
+        let mut buffer = Buffer::new();
+        let id = encoder.encode(&mut buffer, &conn, ack, "world", fields).unwrap();
+        (id, buffer)
+    }
+
+    #[test]
+    fn test_delta() {
+        let mut encoder = DeltaEncoder::new(4, 8);
+        let mut decoder = DeltaDecoder::new(4, 4);
+
+        let (id, mut buffer) = send(&mut encoder, 1, None, world(0));
+        let full = buffer.remaining();
+        let (got, name, fields) = decoder.decode(&mut buffer, &1).unwrap();
+        assert_eq!((got, name.as_str()), (id, "world"));
+        assert_eq!(fields, world(0));
+
+        let mut ack = id;
+        for tick in 1..6 {
+            let mut next = world(tick.min(4));
+            if tick == 3 {
+                next[1].remove(7usize).unwrap();
+                next[1]["new"] = Value::Bool(true);
+            }
+            let (id, mut buffer) = send(&mut encoder, 1, Some(ack), next.clone());
+            let len = buffer.remaining();
+            assert!(len * 10 < full, "{} of {}", len, full);
+            // Tick 5 repeats tick 4, nothing changed.
+            assert!(tick < 5 || len < 40, "{}", len);
+            let (got, _, fields) = decoder.decode(&mut buffer, &1).unwrap();
+            assert_eq!(got, id);
+            assert_eq!(fields, next);
+            if tick != 3 {
+                ack = id;
+            }
+        }
+
+        // The decoder keeps fewer messages than the encoder, the first is gone.
+        let (_, mut buffer) = send(&mut encoder, 1, Some(0), world(6));
+        let err = decoder.decode(&mut buffer, &1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingError);
+        // Nor does it know the baselines of another connection.
+        send(&mut encoder, 2, None, world(0));
+        let (_, mut buffer) = send(&mut encoder, 2, Some(0), world(1));
+        let err = decoder.decode(&mut buffer, &2).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingError);
+
+        // An evicted baseline makes the encoder send the whole message.
+        let mut encoder = DeltaEncoder::new(4, 1);
+        let mut decoder = DeltaDecoder::new(4, 1);
+        let (first, mut buffer) = send(&mut encoder, 1, None, world(0));
+        decoder.decode(&mut buffer, &1).unwrap();
+        let (_, mut buffer) = send(&mut encoder, 1, Some(first), world(1));
+        decoder.decode(&mut buffer, &1).unwrap();
+        let (_, mut buffer) = send(&mut encoder, 1, Some(first), world(2));
+        assert!(buffer.remaining() >= full);
+        assert_eq!(decoder.decode(&mut buffer, &1).unwrap().2, world(2));
+
+        // So does an evicted connection.
+        let mut encoder = DeltaEncoder::new(1, 4);
+        let (first, _) = send(&mut encoder, 1, None, world(0));
+        send(&mut encoder, 2, None, world(0));
+        let (_, buffer) = send(&mut encoder, 1, Some(first), world(1));
+        assert!(buffer.remaining() >= full);
+    }
+
+    #[test]
+    fn test_delta_invalid() {
+        let mut encoder = DeltaEncoder::new(1, 2);
+        let mut decoder = DeltaDecoder::new(1, 2);
+        let (id, mut buffer) = send(&mut encoder, 1, None, world(0));
+        decoder.decode(&mut buffer, &1).unwrap();
+
+        let changes = [
+            Value::Arr(vec![]),
+            Value::Arr(vec![Value::U8(9)]),
+            Value::Arr(vec![Value::U8(1), Value::Raw(vec![0xff]), Value::Varint(2)]),
+            Value::Arr(vec![Value::U8(1), Value::Raw(vec![0]), Value::Varint(3)]),
+            Value::Arr(vec![Value::U8(2), Value::Raw(vec![]), Value::Map(Default::default())]),
+            Value::Arr(vec![Value::U8(0), Value::Nil]),
+        ];
+        for changes in changes {
+            let mut buffer = Buffer::new();
+            let fields = vec![Value::Varint(9), Value::Varint(id as i64), changes];
+            crate::encode_proto(&mut buffer, "world", fields).unwrap();
+            assert!(decoder.decode(&mut buffer, &1).is_err());
+        }
+    }
+}
@@ -5,7 +5,7 @@ use std::{
 
 use algorithm::buf::{BinaryMut, Bt, BtMut};
 
-use crate::{encode::{encode_str_raw, encode_varint}, error::HpResult, ErrorKind, HpError, Value};
+use crate::{encode::{encode_string, encode_varint}, error::HpResult, ErrorKind, HpError, Value};
 
 #[derive(Debug)]
 pub struct Buffer<T: Bt + BtMut = BinaryMut> {
@@ -24,6 +24,23 @@ impl Buffer<BinaryMut> {
     }
 }
 
+#[inline(always)]
+pub(crate) fn intern_str(str_arr: &mut Vec<String>, str_map: &mut HashMap<String, u16>, value: &str) -> u16 {
+    if let Some(idx) = str_map.get(value) {
+        return *idx;
+    }
+    str_arr.push(value.to_string());
+    let idx = str_arr.len() as u16 - 1;
+    str_map.insert(value.to_string(), idx);
+    idx
+}
+
+impl Default for Buffer<BinaryMut> {
+    fn default() -> Self {
+        Buffer::new()
+    }
+}
+
 impl<T: Bt + BtMut> Buffer<T> {
     pub fn new_with(buf: T) -> Self {
         Buffer {
@@ -33,14 +50,12 @@ impl<T: Bt + BtMut> Buffer<T> {
         }
     }
     
-    pub fn add_str(&mut self, value: String) -> u16 {
-        if self.str_map.contains_key(&value) {
-            self.str_map[&value]
-        } else {
-            self.str_arr.push(value.clone());
-            self.str_map.insert(value, self.str_arr.len() as u16 - 1);
-            self.str_arr.len() as u16 - 1
-        }
+    /// Interns `value` into the string table and returns its index.
+    ///
+    /// The lookup borrows `value`, so only a string seen for the first time
+    /// is copied into the table.
+    pub fn add_str(&mut self, value: impl AsRef<str>) -> u16 {
+        intern_str(&mut self.str_arr, &mut self.str_map, value.as_ref())
     }
 
     pub fn get_str(&self, idx: u16) -> HpResult<String> {
@@ -53,17 +68,42 @@ impl<T: Bt + BtMut> Buffer<T> {
 
     pub fn export(self) -> HpResult<Buffer> {
         let mut sub_buffer = Buffer::new();
-        encode_varint(&mut sub_buffer, &Value::U16(self.str_arr.len() as u16))?;
+        self.export_to(&mut sub_buffer.buf)?;
+        Ok(sub_buffer)
+    }
+
+    /// Writes the string table followed by the content into `out`.
+    pub fn export_to<W: BtMut>(&self, out: &mut W) -> HpResult<()> {
+        self.export_str_table(out)?;
+        out.put_slice(self.buf.chunk());
+        Ok(())
+    }
+
+    /// Writes only the string table, the content is expected to follow it.
+    pub fn export_str_table<W: BtMut>(&self, out: &mut W) -> HpResult<()> {
+        encode_varint(out, &Value::U16(self.str_arr.len() as u16))?;
         for v in &self.str_arr {
-            encode_str_raw(&mut sub_buffer, &Value::Str(v.to_string()))?;
+            encode_string(out, v)?;
         }
-        sub_buffer.buf.put_slice(self.buf.chunk());
-        Ok(sub_buffer)
+        Ok(())
     }
 
     pub fn len(&self) -> usize {
         self.buf.chunk().len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Buffer<BinaryMut> {
+    /// Drops the content and the string table but keeps their capacity.
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.str_arr.clear();
+        self.str_map.clear();
+    }
 }
 
 impl Read for Buffer {
@@ -117,4 +157,43 @@ unsafe impl<B: Bt+BtMut> BtMut for Buffer<B> {
     fn chunk_mut(&mut self) -> &mut [std::mem::MaybeUninit<u8>] {
         self.buf.chunk_mut()
     }
-}
\ No newline at end of file
+}
+/// A read-only cursor over encoded bytes, used by the borrowing decoder.
+///
+/// Unlike [`Buffer`] its string table holds `&'a str` slices of the input, so
+/// a `StrIdx` lookup hands out the same borrowed slice instead of a clone.
+#[derive(Debug, Clone, Default)]
+pub struct BufferRef<'a> {
+    pub buf: &'a [u8],
+    pub str_arr: Vec<&'a str>,
+}
+
+impl<'a> BufferRef<'a> {
+    pub fn new(buf: &'a [u8]) -> BufferRef<'a> {
+        BufferRef {
+            buf,
+            str_arr: Vec::new(),
+        }
+    }
+
+    pub fn get_str(&self, idx: u16) -> HpResult<&'a str> {
+        match self.str_arr.get(idx as usize) {
+            Some(val) => Ok(*val),
+            None => Err(HpError::from((ErrorKind::BufferOverMaxError, "must left space to read "))),
+        }
+    }
+
+    /// Takes the next `len` bytes, the returned slice borrows the input.
+    pub fn read_slice(&mut self, len: usize) -> HpResult<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(HpError::from((ErrorKind::NoLeftSpaceError, "space error")));
+        }
+        let (val, left) = self.buf.split_at(len);
+        self.buf = left;
+        Ok(val)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+}
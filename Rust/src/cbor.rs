@@ -0,0 +1,462 @@
+use crate::raw_value::take;
+use crate::transcode::{check_depth, escaped_map, Token, TokenReader, TokenWriter, MAP_TAG};
+use crate::{ErrorKind, HpError, HpResult, Lossy, Transcoded, ValueType};
+
+/// A CBOR tagged item, as `{"$tag": [tag, content]}`.
+pub const TAG_TAG: &str = "$tag";
+
+/// The "break" stop code that ends an indefinite length item.
+const BREAK: u8 = 0xff;
+
+/// Transcodes a CBOR item into a message holding a single value.
+///
+/// Integers become `Varint`, or `U64` when they do not fit an `i64`, half and
+/// single precision floats `F32` and double precision ones `F64`. Byte
+/// strings become `Raw` and tagged items a map tagged with [`TAG_TAG`].
+/// Indefinite length items are read whole. A map whose only key is `"$tag"`
+/// or `"$map"` is escaped as `{"$map": [[key, value]]}`, so it is not taken
+/// for a tagged item on the way back. The lossy cases are reported:
+/// text that is not valid UTF-8 kept as `Raw` ([`Lossy::InvalidUtf8`]),
+/// `undefined` and other simple values read as `Nil` ([`Lossy::Unsupported`])
+/// and negative integers below `i64::MIN` read as `F64` ([`Lossy::IntRange`]).
+/// Nesting deeper than 128 containers is an error.
+pub fn cbor_to_proto(input: &[u8]) -> HpResult<Transcoded> {
+    let mut out = Transcoded::default();
+    let mut writer = TokenWriter::new();
+    let mut input = input;
+    read_item(&mut input, &mut writer, &mut out, 0)?;
+    if !input.is_empty() {
+        return Err(HpError::from((ErrorKind::ParseError, "left buffer")));
+    }
+    out.bytes = writer.finish()?;
+    Ok(out)
+}
+
+/// Transcodes a message holding a single value into CBOR, the reverse of
+/// [`cbor_to_proto`].
+///
+/// CBOR integers carry no width, so every fixed width integer type but a
+/// `U64` beyond `i64::MAX` reads back as `Varint` and is reported as
+/// [`Lossy::IntWidth`]. Floats keep their width. A map escaped as
+/// `{"$map": [[key, value], ...]}` is written as the plain map, and one
+/// tagged with [`TAG_TAG`] as the tagged item. Nesting deeper than 128
+/// containers is an error.
+pub fn proto_to_cbor(buf: &[u8]) -> HpResult<Transcoded> {
+    let mut out = Transcoded::default();
+    let mut reader = TokenReader::new(buf)?;
+    let mut bytes = Vec::with_capacity(buf.len());
+    write_item(&mut reader, &mut bytes, &mut out)?;
+    reader.finish()?;
+    out.bytes = bytes;
+    Ok(out)
+}
+
+fn parse_error(desc: &'static str) -> HpError {
+    HpError::from((ErrorKind::ParseError, desc))
+}
+
+/// Reads the argument of an initial byte, `None` for an indefinite length.
+fn read_arg(input: &mut &[u8], info: u8) -> HpResult<Option<u64>> {
+    match info {
+        0..=23 => Ok(Some(info as u64)),
+        24..=27 => Ok(Some(
+            take(input, 1 << (info - 24))?
+                .iter()
+                .fold(0u64, |v, b| v << 8 | *b as u64),
+        )),
+        31 => Ok(None),
+        _ => Err(parse_error("cbor reserved additional info")),
+    }
+}
+
+fn to_len(arg: u64) -> usize {
+    usize::try_from(arg).unwrap_or(usize::MAX)
+}
+
+/// Consumes the break code if it comes next.
+fn at_break(input: &mut &[u8]) -> bool {
+    if input.first() == Some(&BREAK) {
+        *input = &input[1..];
+        return true;
+    }
+    false
+}
+
+/// Reads the chunks of an indefinite length byte or text string of `major` type.
+fn read_chunks(input: &mut &[u8], major: u8) -> HpResult<Vec<u8>> {
+    let mut data = Vec::new();
+    while !at_break(input) {
+        let initial = take(input, 1)?[0];
+        match read_arg(input, initial & 0x1f)? {
+            Some(len) if initial >> 5 == major => data.extend_from_slice(take(input, to_len(len))?),
+            _ => return Err(parse_error("cbor bad string chunk")),
+        }
+    }
+    Ok(data)
+}
+
+fn take_array<const N: usize>(input: &mut &[u8]) -> HpResult<[u8; N]> {
+    let mut data = [0; N];
+    data.copy_from_slice(take(input, N)?);
+    Ok(data)
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exp = (half >> 10) & 0x1f;
+    let mant = (half & 0x3ff) as f32;
+    match exp {
+        0 => sign * mant * 2f32.powi(-24),
+        31 if mant == 0.0 => sign * f32::INFINITY,
+        31 => f32::NAN,
+        _ => sign * (1.0 + mant / 1024.0) * 2f32.powi(exp as i32 - 15),
+    }
+}
+
+fn read_item<'a>(
+    input: &mut &'a [u8],
+    writer: &mut TokenWriter<'a>,
+    out: &mut Transcoded,
+    depth: usize,
+) -> HpResult<()> {
+    check_depth(depth)?;
+    let initial = take(input, 1)?[0];
+    let (major, info) = (initial >> 5, initial & 0x1f);
+    if major == 7 {
+        return read_simple(input, writer, out, info);
+    }
+    let arg = read_arg(input, info)?;
+    match (major, arg) {
+        (0, Some(v)) => writer.write(Token::U64(v)),
+        (1, Some(v)) if v <= i64::MAX as u64 => writer.write(Token::Varint(-1 - v as i64)),
+        (1, Some(v)) => {
+            out.note(Lossy::IntRange);
+            writer.write(Token::F64(-1.0 - v as f64))
+        }
+        (2, Some(len)) => writer.write(Token::Raw(take(input, to_len(len))?)),
+        (2, None) => writer.write_raw(&read_chunks(input, 2)?),
+        (3, Some(len)) => {
+            let val = take(input, to_len(len))?;
+            match std::str::from_utf8(val) {
+                Ok(val) => writer.write(Token::Str(val)),
+                Err(_) => {
+                    out.note(Lossy::InvalidUtf8);
+                    writer.write(Token::Raw(val))
+                }
+            }
+        }
+        (3, None) => match String::from_utf8(read_chunks(input, 3)?) {
+            Ok(val) => writer.write_str_inline(&val),
+            Err(err) => {
+                out.note(Lossy::InvalidUtf8);
+                writer.write_raw(err.as_bytes())
+            }
+        },
+        (4, Some(len)) => {
+            let len = to_len(len);
+            writer.write(Token::Arr(len))?;
+            for _ in 0..len {
+                read_item(input, writer, out, depth + 1)?;
+            }
+            Ok(())
+        }
+        (5, Some(len)) => {
+            let len = to_len(len);
+            let escaped = len == 1 && is_tag_key(input);
+            if escaped {
+                writer.write(Token::Map(1))?;
+                writer.write(Token::Str(MAP_TAG))?;
+                writer.write(Token::Arr(1))?;
+            } else {
+                writer.write(Token::Map(len))?;
+            }
+            for _ in 0..len {
+                if escaped {
+                    writer.write(Token::Arr(2))?;
+                }
+                read_item(input, writer, out, depth + 1)?;
+                read_item(input, writer, out, depth + 1)?;
+            }
+            Ok(())
+        }
+        (4, None) | (5, None) => {
+            // The length of a map is not known ahead, so one keyed first by
+            // a tag name is escaped whatever its length.
+            let escaped = major == 5 && is_tag_key(input);
+            let offset = if escaped {
+                writer.write(Token::Map(1))?;
+                writer.write(Token::Str(MAP_TAG))?;
+                writer.begin(ValueType::Arr)
+            } else {
+                writer.begin(if major == 4 { ValueType::Arr } else { ValueType::Map })
+            };
+            let mut len = 0;
+            while !at_break(input) {
+                if escaped {
+                    writer.write(Token::Arr(2))?;
+                }
+                read_item(input, writer, out, depth + 1)?;
+                if major == 5 {
+                    read_item(input, writer, out, depth + 1)?;
+                }
+                len += 1;
+            }
+            writer.end(offset, len)
+        }
+        (6, Some(tag)) => {
+            writer.write(Token::Map(1))?;
+            writer.write(Token::Str(TAG_TAG))?;
+            writer.write(Token::Arr(2))?;
+            writer.write(Token::U64(tag))?;
+            read_item(input, writer, out, depth + 1)
+        }
+        _ => Err(parse_error("cbor bad indefinite length")),
+    }
+}
+
+/// Whether the next item is a text string named after [`TAG_TAG`] or
+/// [`MAP_TAG`].
+fn is_tag_key(input: &[u8]) -> bool {
+    let mut input = input;
+    let Ok(&[initial]) = take(&mut input, 1) else {
+        return false;
+    };
+    if initial >> 5 != 3 {
+        return false;
+    }
+    match read_arg(&mut input, initial & 0x1f) {
+        Ok(Some(len)) => take(&mut input, to_len(len))
+            .is_ok_and(|key| key == TAG_TAG.as_bytes() || key == MAP_TAG.as_bytes()),
+        _ => false,
+    }
+}
+
+fn read_simple(
+    input: &mut &[u8],
+    writer: &mut TokenWriter,
+    out: &mut Transcoded,
+    info: u8,
+) -> HpResult<()> {
+    let token = match info {
+        20 => Token::Bool(false),
+        21 => Token::Bool(true),
+        22 => Token::Nil,
+        25 => Token::F32(f16_to_f32(u16::from_be_bytes(take_array(input)?))),
+        26 => Token::F32(f32::from_be_bytes(take_array(input)?)),
+        27 => Token::F64(f64::from_be_bytes(take_array(input)?)),
+        0..=19 | 23 | 24 => {
+            if info == 24 {
+                take(input, 1)?;
+            }
+            out.note(Lossy::Unsupported);
+            Token::Nil
+        }
+        31 => return Err(parse_error("cbor unexpected break")),
+        _ => return Err(parse_error("cbor reserved additional info")),
+    };
+    writer.write(token)
+}
+
+/// Writes an initial byte of `major` type with its argument in the shortest form.
+fn write_head(bytes: &mut Vec<u8>, major: u8, arg: u64) {
+    let major = major << 5;
+    if arg < 24 {
+        bytes.push(major | arg as u8);
+    } else if arg <= u8::MAX as u64 {
+        bytes.extend_from_slice(&[major | 24, arg as u8]);
+    } else if arg <= u16::MAX as u64 {
+        bytes.push(major | 25);
+        bytes.extend_from_slice(&(arg as u16).to_be_bytes());
+    } else if arg <= u32::MAX as u64 {
+        bytes.push(major | 26);
+        bytes.extend_from_slice(&(arg as u32).to_be_bytes());
+    } else {
+        bytes.push(major | 27);
+        bytes.extend_from_slice(&arg.to_be_bytes());
+    }
+}
+
+fn write_int(bytes: &mut Vec<u8>, v: i64) {
+    if v >= 0 {
+        write_head(bytes, 0, v as u64);
+    } else {
+        // -1 - v, which can not overflow.
+        write_head(bytes, 1, !v as u64);
+    }
+}
+
+/// Reads the content of a one pair map tagged with [`TAG_TAG`] up to the
+/// tagged item, returning the tag, `None` if it is not one.
+fn tagged(reader: &mut TokenReader) -> HpResult<Option<u64>> {
+    if reader.next()? != Token::Str(TAG_TAG) || reader.next()? != Token::Arr(2) {
+        return Ok(None);
+    }
+    Ok(reader.next()?.as_u64())
+}
+
+fn write_item(reader: &mut TokenReader, bytes: &mut Vec<u8>, out: &mut Transcoded) -> HpResult<()> {
+    match reader.next()? {
+        Token::Nil => bytes.push(0xf6),
+        Token::Bool(v) => bytes.push(if v { 0xf5 } else { 0xf4 }),
+        Token::U64(v) if v > i64::MAX as u64 => write_head(bytes, 0, v),
+        Token::Varint(v) => write_int(bytes, v),
+        Token::F32(v) => {
+            bytes.push(0xfa);
+            bytes.extend_from_slice(&v.to_be_bytes());
+        }
+        Token::F64(v) => {
+            bytes.push(0xfb);
+            bytes.extend_from_slice(&v.to_be_bytes());
+        }
+        Token::Str(v) => {
+            write_head(bytes, 3, v.len() as u64);
+            bytes.extend_from_slice(v.as_bytes());
+        }
+        Token::Raw(v) => {
+            write_head(bytes, 2, v.len() as u64);
+            bytes.extend_from_slice(v);
+        }
+        Token::Arr(len) => {
+            write_head(bytes, 4, len as u64);
+            for _ in 0..len {
+                write_item(reader, bytes, out)?;
+            }
+        }
+        Token::Map(len) => {
+            if len == 1 {
+                let mark = reader.mark();
+                if let Some(tag) = tagged(reader)? {
+                    write_head(bytes, 6, tag);
+                    return write_item(reader, bytes, out);
+                }
+                reader.reset(mark.clone());
+                if let Some(len) = escaped_map(reader)? {
+                    write_head(bytes, 5, len as u64);
+                    for _ in 0..len {
+                        reader.next()?;
+                        write_item(reader, bytes, out)?;
+                        write_item(reader, bytes, out)?;
+                    }
+                    return Ok(());
+                }
+                reader.reset(mark);
+            }
+            write_head(bytes, 5, len as u64);
+            for _ in 0..len * 2 {
+                write_item(reader, bytes, out)?;
+            }
+        }
+        token => {
+            out.note(Lossy::IntWidth);
+            write_int(bytes, token.as_i64().unwrap_or_default());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{cbor_to_proto, f16_to_f32, proto_to_cbor};
+    use crate::{from_slice, to_vec, Lossy, Value};
+
+    #[test]
+    fn test_cbor_round_trip() {
+        let mut map = HashMap::new();
+        map.insert(Value::from("name".to_string()), Value::from("hc".to_string()));
+        map.insert(Value::Varint(7), Value::Arr(vec![Value::Varint(-3), Value::Varint(i64::MIN)]));
+        map.insert(Value::from("big".to_string()), Value::U64(u64::MAX));
+        map.insert(Value::from("float".to_string()), Value::Arr(vec![Value::F32(1.5), Value::F64(0.1)]));
+        map.insert(Value::Nil, Value::Raw(vec![1, 2, 3]));
+        let value = Value::Map(map);
+
+        let cbor = proto_to_cbor(&to_vec(&value).unwrap()).unwrap();
+        assert!(cbor.is_lossless());
+        let back = cbor_to_proto(&cbor.bytes).unwrap();
+        assert!(back.is_lossless());
+        assert_eq!(from_slice::<Value>(&back.bytes).unwrap(), value);
+
+        let cbor = proto_to_cbor(&to_vec(&vec![Value::U8(1), Value::I8(-1)]).unwrap()).unwrap();
+        assert_eq!(cbor.bytes, [0x82, 0x01, 0x20]);
+        assert_eq!(cbor.lossy, vec![(Lossy::IntWidth, 2)]);
+    }
+
+    #[test]
+    fn test_cbor_bytes() {
+        // [_ "ab" "c", 1(-500), [_ 1.0f16, undefined], -2^64, {_ "k": true}]
+        let input = [
+            0x85, 0x7f, 0x62, b'a', b'b', 0x61, b'c', 0xff, 0xc1, 0x39, 0x01, 0xf3, 0x9f, 0xf9,
+            0x3c, 0x00, 0xf7, 0xff, 0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xbf,
+            0x61, b'k', 0xf5, 0xff,
+        ];
+        let proto = cbor_to_proto(&input).unwrap();
+        let mut lossy = proto.lossy.clone();
+        lossy.sort();
+        assert_eq!(lossy, vec![(Lossy::Unsupported, 1), (Lossy::IntRange, 1)]);
+
+        let mut tag = HashMap::new();
+        tag.insert(
+            Value::from("$tag".to_string()),
+            Value::Arr(vec![Value::Varint(1), Value::Varint(-500)]),
+        );
+        let mut map = HashMap::new();
+        map.insert(Value::from("k".to_string()), Value::Bool(true));
+        let value = Value::Arr(vec![
+            Value::from("abc".to_string()),
+            Value::Map(tag),
+            Value::Arr(vec![Value::F32(1.0), Value::Nil]),
+            Value::F64(-18446744073709551616.0),
+            Value::Map(map),
+        ]);
+        assert_eq!(from_slice::<Value>(&proto.bytes).unwrap(), value);
+
+        let cbor = proto_to_cbor(&proto.bytes).unwrap();
+        assert_eq!(&cbor.bytes[..11], [0x85, 0x63, b'a', b'b', b'c', 0xc1, 0x39, 0x01, 0xf3, 0x82, 0xfa]);
+
+        assert_eq!(f16_to_f32(0x7bff), 65504.0);
+        assert_eq!(f16_to_f32(0x0001), 5.960_464_5e-8);
+        assert!(cbor_to_proto(&[0x82, 0x01]).is_err());
+    }
+
+    #[test]
+    fn test_cbor_escaped_map() {
+        let content = Value::Arr(vec![Value::Varint(1), Value::from("x".to_string())]);
+        // {"$tag": [1, "x"]} as a plain map, not tag 1.
+        let input = [0xa1, 0x64, b'$', b't', b'a', b'g', 0x82, 0x01, 0x61, b'x'];
+        let proto = cbor_to_proto(&input).unwrap();
+        assert!(proto.is_lossless());
+        let entry = Value::Arr(vec![Value::from("$tag".to_string()), content.clone()]);
+        let escaped = Value::Map([(Value::from("$map".to_string()), Value::Arr(vec![entry]))].into());
+        assert_eq!(from_slice::<Value>(&proto.bytes).unwrap(), escaped);
+        assert_eq!(proto_to_cbor(&proto.bytes).unwrap().bytes, input);
+
+        // An indefinite length one is escaped too, and written back definite.
+        let input = [0xbf, 0x64, b'$', b'm', b'a', b'p', 0x01, 0xff];
+        let proto = cbor_to_proto(&input).unwrap();
+        assert_eq!(proto_to_cbor(&proto.bytes).unwrap().bytes, [0xa1, 0x64, b'$', b'm', b'a', b'p', 0x01]);
+
+        // The map left unescaped is the tagged item.
+        let tagged = Value::Map([(Value::from("$tag".to_string()), content)].into());
+        assert_eq!(proto_to_cbor(&to_vec(&tagged).unwrap()).unwrap().bytes, [0xc1, 0x61, b'x']);
+    }
+
+    #[test]
+    fn test_cbor_depth() {
+        let nested = |depth: usize, initial: u8| {
+            let mut input = vec![initial; depth];
+            input.push(0xf6);
+            input
+        };
+        assert!(cbor_to_proto(&nested(128, 0x81)).is_ok());
+        assert!(cbor_to_proto(&nested(129, 0x81)).is_err());
+        assert!(cbor_to_proto(&nested(100_000, 0x9f)).is_err());
+        assert!(cbor_to_proto(&nested(100_000, 0xc1)).is_err());
+
+        let mut value = Value::Nil;
+        for _ in 0..129 {
+            value = Value::Arr(vec![value]);
+        }
+        assert!(proto_to_cbor(&to_vec(&value).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,430 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::values::{decoded, out_of_range};
+use crate::{ErrorKind, HpError, HpResult, RawValue, Value};
+
+/// Builds a [`Value`] from a JSON-like literal, keeping the types of the
+/// literals: `100u16` is a `U16`, `100` an `I32` and `1.5` an `F64`.
+///
+/// ```
+/// use hcproto::{value, Value};
+///
+/// let player = value! {
+///     "name": "hc",
+///     "hp": 100u16,
+///     "tags": ["a", "b"],
+///     "guild": nil,
+///     1u8: {"x": 1.5f32, "y": -2i8},
+/// };
+/// assert_eq!(player["hp"], Value::U16(100));
+/// assert_eq!(player["tags"][1], Value::from("b"));
+/// assert_eq!(player[1usize]["y"], Value::I8(-2));
+/// ```
+///
+/// `[...]` is an `Arr`, `{...}` a `Map`, and `nil` is `Nil`; an empty
+/// `value!{}` is an empty `Map`. Any other expression goes through
+/// [`ToValue`]. A key is a single token: a literal, a variable, or an
+/// expression in parentheses.
+#[macro_export]
+macro_rules! value {
+    (@arr [$($done:expr,)*]) => {
+        ::std::vec![$($done,)*]
+    };
+    (@arr [$($done:expr,)*] nil $(, $($rest:tt)*)?) => {
+        $crate::value!(@arr [$($done,)* $crate::Value::Nil,] $($($rest)*)?)
+    };
+    (@arr [$($done:expr,)*] [$($item:tt)*] $(, $($rest:tt)*)?) => {
+        $crate::value!(@arr [$($done,)* $crate::value!([$($item)*]),] $($($rest)*)?)
+    };
+    (@arr [$($done:expr,)*] {$($entry:tt)*} $(, $($rest:tt)*)?) => {
+        $crate::value!(@arr [$($done,)* $crate::value!({$($entry)*}),] $($($rest)*)?)
+    };
+    (@arr [$($done:expr,)*] $item:expr $(, $($rest:tt)*)?) => {
+        $crate::value!(@arr [$($done,)* $crate::ToValue::to_value(&$item),] $($($rest)*)?)
+    };
+
+    (@map $map:ident) => {};
+    (@map $map:ident $key:tt : nil $(, $($rest:tt)*)?) => {
+        $map.insert($crate::value!($key), $crate::Value::Nil);
+        $($crate::value!(@map $map $($rest)*);)?
+    };
+    (@map $map:ident $key:tt : [$($item:tt)*] $(, $($rest:tt)*)?) => {
+        $map.insert($crate::value!($key), $crate::value!([$($item)*]));
+        $($crate::value!(@map $map $($rest)*);)?
+    };
+    (@map $map:ident $key:tt : {$($entry:tt)*} $(, $($rest:tt)*)?) => {
+        $map.insert($crate::value!($key), $crate::value!({$($entry)*}));
+        $($crate::value!(@map $map $($rest)*);)?
+    };
+    (@map $map:ident $key:tt : $value:expr $(, $($rest:tt)*)?) => {
+        $map.insert($crate::value!($key), $crate::ToValue::to_value(&$value));
+        $($crate::value!(@map $map $($rest)*);)?
+    };
+
+    () => {
+        $crate::Value::Map(::std::collections::HashMap::new())
+    };
+    (nil) => {
+        $crate::Value::Nil
+    };
+    ([$($item:tt)*]) => {
+        $crate::Value::Arr($crate::value!(@arr [] $($item)*))
+    };
+    ({$($entry:tt)*}) => {{
+        #[allow(unused_mut)]
+        let mut map = ::std::collections::HashMap::new();
+        $crate::value!(@map map $($entry)*);
+        $crate::Value::Map(map)
+    }};
+    ($key:tt : $($rest:tt)*) => {
+        $crate::value!({$key : $($rest)*})
+    };
+    ($other:expr) => {
+        $crate::ToValue::to_value(&$other)
+    };
+}
+
+/// Converts to a [`Value`], keeping the type: a `u16` is a `U16`.
+///
+/// Sequences, arrays and tuples are an `Arr`, so a `Vec<u8>` is an `Arr` of
+/// `U8`; wrap bytes in `Value::Raw` to send them as such. `None` is `Nil`.
+pub trait ToValue {
+    fn to_value(&self) -> Value;
+}
+
+/// Converts from a [`Value`], taking an integer of any width that fits, as
+/// `TryFrom<Value>` does, and checking the length of arrays and tuples.
+///
+/// `Nil` is `None`, and a `Vec` also takes the bytes of a `Raw` as `U8`.
+pub trait FromValue: Sized {
+    fn from_value(value: Value) -> HpResult<Self>;
+}
+
+impl ToValue for Value {
+    fn to_value(&self) -> Value {
+        self.clone()
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> HpResult<Value> {
+        Ok(value)
+    }
+}
+
+impl ToValue for RawValue {
+    fn to_value(&self) -> Value {
+        Value::Encoded(self.clone())
+    }
+}
+
+macro_rules! convert_via_from {
+    ($($t:ty),*) => {
+        $(
+            impl ToValue for $t {
+                fn to_value(&self) -> Value {
+                    Value::from(self.clone())
+                }
+            }
+
+            impl FromValue for $t {
+                fn from_value(value: Value) -> HpResult<$t> {
+                    <$t>::try_from(value)
+                }
+            }
+        )*
+    };
+}
+
+convert_via_from!(bool, u8, i8, u16, i16, u32, i32, u64, i64, f32, f64, String);
+
+impl ToValue for usize {
+    fn to_value(&self) -> Value {
+        Value::U64(*self as u64)
+    }
+}
+
+impl FromValue for usize {
+    fn from_value(value: Value) -> HpResult<usize> {
+        let value = decoded(value)?;
+        let v = u64::try_from(value.clone())?;
+        usize::try_from(v).map_err(|_| out_of_range("usize", &value))
+    }
+}
+
+impl ToValue for isize {
+    fn to_value(&self) -> Value {
+        Value::I64(*self as i64)
+    }
+}
+
+impl FromValue for isize {
+    fn from_value(value: Value) -> HpResult<isize> {
+        let value = decoded(value)?;
+        let v = i64::try_from(value.clone())?;
+        isize::try_from(v).map_err(|_| out_of_range("isize", &value))
+    }
+}
+
+impl ToValue for str {
+    fn to_value(&self) -> Value {
+        Value::Str(self.to_string())
+    }
+}
+
+impl<T: ToValue + ?Sized> ToValue for &T {
+    fn to_value(&self) -> Value {
+        (**self).to_value()
+    }
+}
+
+impl<T: ToValue + ?Sized> ToValue for &mut T {
+    fn to_value(&self) -> Value {
+        (**self).to_value()
+    }
+}
+
+impl<T: ToValue> ToValue for Option<T> {
+    fn to_value(&self) -> Value {
+        match self {
+            Some(v) => v.to_value(),
+            None => Value::Nil,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value) -> HpResult<Option<T>> {
+        match decoded(value)? {
+            Value::Nil => Ok(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+impl<T: ToValue> ToValue for [T] {
+    fn to_value(&self) -> Value {
+        Value::Arr(self.iter().map(ToValue::to_value).collect())
+    }
+}
+
+impl<T: ToValue> ToValue for Vec<T> {
+    fn to_value(&self) -> Value {
+        self.as_slice().to_value()
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: Value) -> HpResult<Vec<T>> {
+        match decoded(value)? {
+            Value::Raw(bytes) => bytes.into_iter().map(|v| T::from_value(Value::U8(v))).collect(),
+            value => Vec::<Value>::try_from(value)?.into_iter().map(T::from_value).collect(),
+        }
+    }
+}
+
+fn wrong_len(expected: usize, found: usize) -> HpError {
+    HpError::from((
+        ErrorKind::TypeNotMatchError,
+        "type not match",
+        format!("expected arr of {} items, found {} items", expected, found),
+    ))
+}
+
+impl<T: ToValue, const N: usize> ToValue for [T; N] {
+    fn to_value(&self) -> Value {
+        self.as_slice().to_value()
+    }
+}
+
+impl<T: FromValue, const N: usize> FromValue for [T; N] {
+    fn from_value(value: Value) -> HpResult<[T; N]> {
+        let items = Vec::<T>::from_value(value)?;
+        let len = items.len();
+        items.try_into().map_err(|_| wrong_len(N, len))
+    }
+}
+
+macro_rules! convert_tuple {
+    ($(($len:expr; $($t:ident),+))*) => {
+        $(
+            impl<$($t: ToValue),+> ToValue for ($($t,)+) {
+                #[allow(non_snake_case)]
+                fn to_value(&self) -> Value {
+                    let ($($t,)+) = self;
+                    Value::Arr(vec![$($t.to_value()),+])
+                }
+            }
+
+            impl<$($t: FromValue),+> FromValue for ($($t,)+) {
+                fn from_value(value: Value) -> HpResult<($($t,)+)> {
+                    let items = Vec::<Value>::try_from(value)?;
+                    if items.len() != $len {
+                        return Err(wrong_len($len, items.len()));
+                    }
+                    let mut items = items.into_iter();
+                    Ok(($($t::from_value(items.next().unwrap_or(Value::Nil))?,)+))
+                }
+            }
+        )*
+    };
+}
+
+convert_tuple! {
+    (1; A)
+    (2; A, B)
+    (3; A, B, C)
+    (4; A, B, C, D)
+    (5; A, B, C, D, E)
+    (6; A, B, C, D, E, F)
+    (7; A, B, C, D, E, F, G)
+    (8; A, B, C, D, E, F, G, H)
+}
+
+impl<K: ToValue, V: ToValue, S> ToValue for HashMap<K, V, S> {
+    fn to_value(&self) -> Value {
+        Value::Map(self.iter().map(|(k, v)| (k.to_value(), v.to_value())).collect())
+    }
+}
+
+impl<K: FromValue + Eq + Hash, V: FromValue, S: BuildHasher + Default> FromValue for HashMap<K, V, S> {
+    fn from_value(value: Value) -> HpResult<HashMap<K, V, S>> {
+        HashMap::<Value, Value>::try_from(value)?
+            .into_iter()
+            .map(|(k, v)| Ok((K::from_value(k)?, V::from_value(v)?)))
+            .collect()
+    }
+}
+
+impl<K: ToValue, V: ToValue> ToValue for BTreeMap<K, V> {
+    fn to_value(&self) -> Value {
+        Value::Map(self.iter().map(|(k, v)| (k.to_value(), v.to_value())).collect())
+    }
+}
+
+impl<K: FromValue + Ord, V: FromValue> FromValue for BTreeMap<K, V> {
+    fn from_value(value: Value) -> HpResult<BTreeMap<K, V>> {
+        HashMap::<Value, Value>::try_from(value)?
+            .into_iter()
+            .map(|(k, v)| Ok((K::from_value(k)?, V::from_value(v)?)))
+            .collect()
+    }
+}
+
+macro_rules! convert_pointer {
+    ($($p:ident),*) => {
+        $(
+            impl<T: ToValue + ?Sized> ToValue for $p<T> {
+                fn to_value(&self) -> Value {
+                    (**self).to_value()
+                }
+            }
+
+            impl<T: FromValue> FromValue for $p<T> {
+                fn from_value(value: Value) -> HpResult<$p<T>> {
+                    T::from_value(value).map($p::new)
+                }
+            }
+        )*
+    };
+}
+
+convert_pointer!(Box, Rc, Arc);
+
+impl<T: ToValue + ToOwned + ?Sized> ToValue for Cow<'_, T> {
+    fn to_value(&self) -> Value {
+        (**self).to_value()
+    }
+}
+
+impl<T: ToOwned + ?Sized> FromValue for Cow<'_, T>
+where
+    T::Owned: FromValue,
+{
+    fn from_value(value: Value) -> HpResult<Self> {
+        T::Owned::from_value(value).map(Cow::Owned)
+    }
+}
+
+impl Value {
+    /// Converts into `T`, see [`FromValue`].
+    pub fn to<T: FromValue>(self) -> HpResult<T> {
+        T::from_value(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::collections::{BTreeMap, HashMap};
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use crate::{ErrorKind, FromValue, ToValue, Value};
+
+    #[test]
+    fn test_value_macro() {
+        let name = "hc";
+        let level = 3u8;
+        let value = value! {
+            "name": name,
+            "hp": 100u16,
+            "mp": -5,
+            "speed": 1.5,
+            "tags": ["a", nil, [], {}, level + 1],
+            "pos": {"x": 1.5f32, "y": -2i64},
+            (level): Some(7u32),
+            "guild": nil,
+        };
+        assert_eq!(value["name"], Value::Str("hc".to_string()));
+        assert!(matches!(value["hp"], Value::U16(100)));
+        assert!(matches!(value["mp"], Value::I32(-5)));
+        assert!(matches!(value["speed"], Value::F64(_)));
+        assert!(matches!(value["pos"]["y"], Value::I64(-2)));
+        assert!(matches!(value[3usize], Value::U32(7)));
+        assert_eq!(value["guild"], Value::Nil);
+        assert_eq!(
+            value["tags"],
+            Value::Arr(vec![
+                Value::from("a"),
+                Value::Nil,
+                Value::Arr(vec![]),
+                Value::Map(HashMap::new()),
+                Value::U8(4),
+            ])
+        );
+        assert_eq!(value!(nil), Value::Nil);
+        assert_eq!(value!([1u8, 2u8].len()), Value::U64(2));
+        assert_eq!(value!({}), value!());
+    }
+
+    #[test]
+    fn test_to_value() {
+        let mut map = HashMap::new();
+        map.insert("a".to_string(), vec![(1u8, Some(true)), (2, None)]);
+        let value = map.to_value();
+        assert_eq!(value["a"][1], Value::Arr(vec![Value::U8(2), Value::Nil]));
+        assert_eq!(HashMap::<String, Vec<(u8, Option<bool>)>>::from_value(value.clone()).unwrap(), map);
+        let sorted = BTreeMap::<Cow<str>, Vec<(u16, Option<bool>)>>::from_value(value).unwrap();
+        assert_eq!(sorted["a"][0], (1, Some(true)));
+
+        assert_eq!([1i8, 2].to_value(), Value::Arr(vec![Value::I8(1), Value::I8(2)]));
+        assert_eq!(<[u8; 2]>::from_value(Value::Raw(vec![1, 2])).unwrap(), [1, 2]);
+        let err = <[u8; 3]>::from_value(Value::Raw(vec![1, 2])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TypeNotMatchError);
+        let err = <(u8, u8)>::from_value(value!([1, 2, 3])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TypeNotMatchError);
+        let err = u8::from_value(value!(300)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TypeNotMatchError);
+
+        assert_eq!(Box::new(3usize).to_value(), Value::U64(3));
+        assert_eq!(Rc::new("x").to_value(), Value::from("x"));
+        assert_eq!(*Arc::<String>::from_value(Value::from("x")).unwrap(), "x");
+        assert_eq!(Cow::Borrowed("x").to_value(), Value::from("x"));
+        assert_eq!(value!([1u8, nil]).to::<Vec<Option<isize>>>().unwrap(), [Some(1), None]);
+    }
+}
@@ -1,21 +1,26 @@
-use std::collections::HashMap;
-use std::marker::PhantomData;
+use std::{fmt, io};
 
-use crate::decode::{decode_field, decode_str_raw, decode_type, decode_varint, peek_type};
+use crate::decode::{
+    decode_raw_ref, decode_raw_value_ref, decode_str_ref, decode_str_table_ref, decode_varint,
+};
+use crate::raw_value::TOKEN;
 use crate::error::HpError;
-use crate::{Buffer, HpResult, Value, ValueType};
+use crate::{Buffer, BufferRef, ErrorKind, HpResult, StructMode, ValueType};
 
 use algorithm::buf::{Bt, BtMut};
 use serde::de::{
-    self, Deserialize, DeserializeSeed, EnumAccess, Error, MapAccess, SeqAccess, VariantAccess,
-    Visitor,
+    self, Deserialize, DeserializeOwned, DeserializeSeed, EnumAccess, Error, IntoDeserializer,
+    MapAccess, SeqAccess, VariantAccess, Visitor,
 };
 use serde::forward_to_deserialize_any;
 
-pub fn from_buffer<'de, 'a, T, B>(buf: Buffer<B>) -> HpResult<T>
+/// Deserializes `T` from an encoded message, borrowing from `buf` where `T` allows.
+///
+/// Strings and raw bytes are handed to the visitor as borrowed slices of `buf`,
+/// so fields like `&'de str`, `&'de [u8]` or `Cow<'de, str>` need no copy.
+pub fn from_slice<'de, T>(buf: &'de [u8]) -> HpResult<T>
 where
     T: Deserialize<'de>,
-    B: Bt + BtMut,
 {
     let mut deserializer = Deserializer::new(buf)?;
     let t = T::deserialize(&mut deserializer)?;
@@ -26,86 +31,130 @@ where
     }
 }
 
+/// Deserializes a message written by [`crate::encode_proto`] or [`crate::to_proto`]
+/// into its proto name and arguments.
+///
+/// `T` is a tuple, a sequence, or a struct whose fields are the positional
+/// arguments, as [`crate::to_proto`] writes them.
+pub fn from_proto<'de, T>(buf: &'de [u8]) -> HpResult<(String, T)>
+where
+    T: Deserialize<'de>,
+{
+    let mut head = BufferRef::new(buf);
+    let name = decode_str_ref(&mut head)?.to_string();
+    let mut deserializer = Deserializer::new(head.buf)?;
+    if deserializer.peek_type()? != ValueType::Arr {
+        return Err(HpError::custom("proto is not array"));
+    }
+    deserializer.proto_args = Some(deserializer.buf.remaining());
+    let t = T::deserialize(&mut deserializer)?;
+    if deserializer.buf.remaining() == 0 {
+        Ok((name, t))
+    } else {
+        Err(HpError::custom("left buffer"))
+    }
+}
+
+pub fn from_reader<R, T>(mut reader: R) -> HpResult<T>
+where
+    R: io::Read,
+    T: DeserializeOwned,
+{
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf)?;
+    from_slice(&buf)
+}
+
+pub fn from_buffer<T, B>(buf: Buffer<B>) -> HpResult<T>
+where
+    T: DeserializeOwned,
+    B: Bt + BtMut,
+{
+    from_slice(buf.chunk())
+}
+
 #[derive(Debug)]
-pub struct Deserializer<'de, B: Bt + BtMut> {
-    buf: Buffer<B>,
-    value: Option<Value>,
-    data: PhantomData<&'de ()>,
+pub struct Deserializer<'de> {
+    buf: BufferRef<'de>,
+    struct_mode: StructMode,
+    /// Where the arguments of a proto start, a struct found there is read
+    /// positionally whatever its length.
+    proto_args: Option<usize>,
 }
 
-impl<'de, B: Bt + BtMut> Deserializer<'de, B> {
-    pub fn new(mut buf: Buffer<B>) -> HpResult<Self> {
-        let str_len: u16 = decode_varint(&mut buf)?.into();
-        for _ in 0..str_len {
-            let value = decode_str_raw(&mut buf, ValueType::Str)?.into();
-            buf.add_str(value);
-        }
+impl<'de> Deserializer<'de> {
+    /// Reads the string table at the front of `buf`, the fields follow it.
+    pub fn new(buf: &'de [u8]) -> HpResult<Self> {
+        let mut buf = BufferRef::new(buf);
+        decode_str_table_ref(&mut buf)?;
         Ok(Deserializer {
             buf,
-            value: None,
-            data: PhantomData,
+            struct_mode: StructMode::default(),
+            proto_args: None,
         })
     }
 
-    fn visit_val<V>(&mut self, value: Value, visitor: V) -> Result<V::Value, HpError>
-    where
-        V: Visitor<'de>,
-    {
-        match value {
-            crate::Value::Nil => visitor.visit_none(),
-            crate::Value::Bool(v) => visitor.visit_bool(v),
-            crate::Value::U8(v) => visitor.visit_u8(v),
-            crate::Value::I8(v) => visitor.visit_i8(v),
-            crate::Value::U16(v) => visitor.visit_u16(v),
-            crate::Value::I16(v) => visitor.visit_i16(v),
-            crate::Value::U32(v) => visitor.visit_u32(v),
-            crate::Value::I32(v) => visitor.visit_i32(v),
-            crate::Value::U64(v) => visitor.visit_u64(v),
-            crate::Value::I64(v) => visitor.visit_i64(v),
-            crate::Value::Varint(v) => visitor.visit_i64(v),
-            crate::Value::F32(v) => visitor.visit_f32(v),
-            crate::Value::F64(v) => visitor.visit_f64(v),
-            crate::Value::Str(v) => visitor.visit_string(v),
-            crate::Value::Raw(vec) => visitor.visit_byte_buf(vec),
-            crate::Value::Arr(vec) => self.visit_seq(vec, visitor),
-            crate::Value::Map(hash_map) => self.visit_map(hash_map, visitor),
-            // crate::Value::KeyValue(name, vec) => todo!(),
+    /// Reads structs written as an `Arr` with the layout the serializer was
+    /// given, see [`StructMode`]. A `Map` is read as a named struct either way.
+    pub fn with_struct_mode(mut self, struct_mode: StructMode) -> Self {
+        self.struct_mode = struct_mode;
+        self
+    }
+
+    fn peek_type(&self) -> HpResult<ValueType> {
+        match self.buf.buf.first() {
+            Some(t) => Ok(ValueType::from(*t)),
+            None => Err(HpError::from((ErrorKind::NoLeftSpaceError, "space error"))),
         }
     }
 
-    fn visit_seq<V>(&mut self, mut val: Vec<Value>, visitor: V) -> Result<V::Value, HpError>
-    where
-        V: Visitor<'de>,
-    {
-        let array = val.drain(..).rev().collect();
-        let value = visitor.visit_seq(CommaSeparated {
-            de: self,
-            array,
-            len: 0,
-        })?;
-        Ok(value)
+    fn decode_type(&mut self) -> HpResult<ValueType> {
+        Ok(ValueType::from(self.buf.buf.try_get_u8()?))
+    }
+
+    fn decode_len(&mut self) -> HpResult<usize> {
+        let len: u32 = decode_varint(&mut self.buf.buf)?.try_into()?;
+        Ok(len as usize)
     }
 
-    fn visit_map<V>(&mut self, val: HashMap<Value, Value>, visitor: V) -> Result<V::Value, HpError>
+    fn visit_type<V>(&mut self, pattern: ValueType, visitor: V) -> HpResult<V::Value>
     where
         V: Visitor<'de>,
     {
-        let mut array = vec![];
-        for (k, v) in val.into_iter() {
-            array.push(k);
-            array.push(v);
+        let buf = &mut self.buf.buf;
+        match pattern {
+            ValueType::Nil => visitor.visit_unit(),
+            ValueType::Bool => visitor.visit_bool(buf.try_get_u8()? == 1),
+            ValueType::U8 => visitor.visit_u8(buf.try_get_u8()?),
+            ValueType::I8 => visitor.visit_i8(buf.try_get_i8()?),
+            ValueType::U16 => visitor.visit_u16(buf.try_get_u16()?),
+            ValueType::I16 => visitor.visit_i16(buf.try_get_i16()?),
+            ValueType::U32 => visitor.visit_u32(buf.try_get_u32()?),
+            ValueType::I32 => visitor.visit_i32(buf.try_get_i32()?),
+            ValueType::U64 => visitor.visit_u64(buf.try_get_u64()?),
+            ValueType::I64 => visitor.visit_i64(buf.try_get_i64()?),
+            ValueType::Varint => visitor.visit_i64(decode_varint(buf)?.try_into()?),
+            ValueType::F32 => visitor.visit_f32(buf.try_get_f32()?),
+            ValueType::F64 => visitor.visit_f64(buf.try_get_f64()?),
+            ValueType::Str => visitor.visit_borrowed_str(decode_str_ref(&mut self.buf)?),
+            ValueType::StrIdx => {
+                let idx: u16 = decode_varint(buf)?.try_into()?;
+                visitor.visit_borrowed_str(self.buf.get_str(idx)?)
+            }
+            ValueType::Raw => visitor.visit_borrowed_bytes(decode_raw_ref(&mut self.buf)?),
+            ValueType::Arr => {
+                let len = self.decode_len()?;
+                visitor.visit_seq(CommaSeparated::new(self, len))
+            }
+            ValueType::Map => {
+                let len = self.decode_len()?;
+                visitor.visit_map(CommaSeparated::new(self, len / 2))
+            }
         }
-        let array = array.drain(..).rev().collect();
-        let value = visitor.visit_map(CommaSeparated {
-            de: self,
-            array,
-            len: 0,
-        })?;
-        Ok(value)
     }
 }
 
-impl<'de, B: Bt + BtMut> de::Deserializer<'de> for &mut Deserializer<'de, B> {
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
     type Error = HpError;
 
     forward_to_deserialize_any! {
@@ -117,24 +166,27 @@ impl<'de, B: Bt + BtMut> de::Deserializer<'de> for &mut Deserializer<'de, B> {
     where
         V: Visitor<'de>,
     {
-        if self.value.is_some() {
-            let value = self.value.take().unwrap();
-            self.visit_val(value, visitor)
-        } else {
-            let value = decode_field(&mut self.buf)?;
-            self.visit_val(value, visitor)
+        let pattern = self.decode_type()?;
+        if pattern == ValueType::Arr && self.struct_mode == StructMode::Legacy && is_tagged_enum(&visitor) {
+            // serde finds the tag of an internally tagged enum by name, so a
+            // legacy struct is handed over as the map its pairs stand for.
+            let len = self.decode_len()?;
+            return match len % 2 {
+                0 => visitor.visit_map(CommaSeparated::new(self, len / 2)),
+                _ => visitor.visit_seq(CommaSeparated::new(self, len)),
+            };
         }
+        self.visit_type(pattern, visitor)
     }
 
     fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
     where
         V: Visitor<'de>,
     {
-        let value = decode_field(&mut self.buf)?;
-        if value.is_nil() {
+        if self.peek_type()? == ValueType::Nil {
+            self.decode_type()?;
             visitor.visit_none()
         } else {
-            self.value = Some(value);
             visitor.visit_some(self)
         }
     }
@@ -147,9 +199,8 @@ impl<'de, B: Bt + BtMut> de::Deserializer<'de> for &mut Deserializer<'de, B> {
     where
         V: Visitor<'de>,
     {
-        let value = decode_field(&mut self.buf)?;
-        if value.is_nil() {
-            visitor.visit_none()
+        if self.decode_type()? == ValueType::Nil {
+            visitor.visit_unit()
         } else {
             Err(de::Error::custom("unit struct must be none"))
         }
@@ -157,28 +208,26 @@ impl<'de, B: Bt + BtMut> de::Deserializer<'de> for &mut Deserializer<'de, B> {
 
     fn deserialize_newtype_struct<V>(
         self,
-        _name: &'static str,
+        name: &'static str,
         visitor: V,
     ) -> Result<V::Value, Self::Error>
     where
         V: Visitor<'de>,
     {
-        let t = peek_type(&mut self.buf)?;
-        if t == ValueType::Arr {
-            visitor.visit_newtype_struct(self)
-        } else {
-            Err(de::Error::custom("struct must be kv type"))
+        if name == TOKEN {
+            let raw = decode_raw_value_ref(&mut self.buf)?;
+            return visitor.visit_byte_buf(raw.into_vec());
         }
+        visitor.visit_newtype_struct(self)
     }
 
     fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error>
     where
         V: Visitor<'de>,
     {
-        let value = decode_field(&mut self.buf)?;
-        match value {
-            Value::Arr(v) => self.visit_seq(v, visitor),
-            _ => Err(de::Error::custom("struct must be kv type")),
+        match self.decode_type()? {
+            ValueType::Arr => self.visit_type(ValueType::Arr, visitor),
+            _ => Err(de::Error::custom("tuple must be arr type")),
         }
     }
 
@@ -203,17 +252,24 @@ impl<'de, B: Bt + BtMut> de::Deserializer<'de> for &mut Deserializer<'de, B> {
     where
         V: Visitor<'de>,
     {
-        let t = decode_type(&mut self.buf)?;
-        if t == ValueType::Arr || t == ValueType::Map {
-            let len: u32 = decode_varint(&mut self.buf)?.into();
-            println!("deserialize_struct len = {:?} t = {:?}", len, t);
-            visitor.visit_map(CommaSeparated {
-                de: self,
-                array: vec![],
-                len: len as usize,
-            })
-        } else {
-            Err(de::Error::custom("struct must be kv type"))
+        let args = self.proto_args == Some(self.buf.remaining());
+        match self.decode_type()? {
+            ValueType::Map => {
+                let len = self.decode_len()?;
+                visitor.visit_map(CommaSeparated::new(self, len / 2))
+            }
+            ValueType::Arr if args => {
+                let len = self.decode_len()?;
+                visitor.visit_seq(CommaSeparated::new(self, len))
+            }
+            ValueType::Arr => {
+                let len = self.decode_len()?;
+                match self.struct_mode {
+                    StructMode::Positional => visitor.visit_seq(CommaSeparated::new(self, len)),
+                    _ => visitor.visit_map(CommaSeparated::new(self, len / 2)),
+                }
+            }
+            _ => Err(de::Error::custom("struct must be kv type")),
         }
     }
 
@@ -221,86 +277,116 @@ impl<'de, B: Bt + BtMut> de::Deserializer<'de> for &mut Deserializer<'de, B> {
         self,
         _name: &'static str,
         _variants: &'static [&'static str],
-        _visitor: V,
+        visitor: V,
     ) -> Result<V::Value, Self::Error>
     where
         V: Visitor<'de>,
     {
-        todo!()
+        match self.peek_type()? {
+            ValueType::Map => {
+                self.decode_type()?;
+                if self.decode_len()? != 2 {
+                    return Err(de::Error::custom("enum must be a map with one entry"));
+                }
+                visitor.visit_enum(Enum::new(self, true))
+            }
+            _ => visitor.visit_enum(Enum::new(self, false)),
+        }
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
     }
 }
 
-struct CommaSeparated<'a, 'de: 'a, B: Bt + BtMut> {
-    de: &'a mut Deserializer<'de, B>,
-    array: Vec<Value>,
+/// Whether `visitor` is the one serde reads an internally tagged enum with,
+/// which only tells itself apart by what it expects.
+fn is_tagged_enum<'de, V: Visitor<'de>>(visitor: &V) -> bool {
+    struct Expecting<'a, V>(&'a V);
+
+    impl<'de, V: Visitor<'de>> fmt::Display for Expecting<'_, V> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.0.expecting(f)
+        }
+    }
+
+    Expecting(visitor).to_string().starts_with("internally tagged enum")
+}
+
+/// Hands out the next `len` elements (or key/value pairs) of a container.
+struct CommaSeparated<'a, 'de: 'a> {
+    de: &'a mut Deserializer<'de>,
     len: usize,
 }
 
+impl<'a, 'de> CommaSeparated<'a, 'de> {
+    fn new(de: &'a mut Deserializer<'de>, len: usize) -> Self {
+        CommaSeparated { de, len }
+    }
+}
+
 // `SeqAccess` is provided to the `Visitor` to give it the ability to iterate
 // through elements of the sequence.
-impl<'de, B: Bt + BtMut> SeqAccess<'de> for CommaSeparated<'_, 'de, B> {
+impl<'de> SeqAccess<'de> for CommaSeparated<'_, 'de> {
     type Error = HpError;
 
     fn next_element_seed<T>(&mut self, seed: T) -> HpResult<Option<T::Value>>
     where
         T: DeserializeSeed<'de>,
     {
-        if !self.array.is_empty() {
-            self.de.value = self.array.pop();
-            seed.deserialize(&mut *self.de).map(Some)
-        } else {
-            if self.len == 0 {
-                return Ok(None);
-            }
-            seed.deserialize(&mut *self.de).map(Some)
+        if self.len == 0 {
+            return Ok(None);
         }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        // The length comes from the input, each element takes a byte at least.
+        Some(self.len.min(self.de.buf.remaining()))
     }
 }
 
-impl<'de, B: Bt + BtMut> MapAccess<'de> for CommaSeparated<'_, 'de, B> {
+impl<'de> MapAccess<'de> for CommaSeparated<'_, 'de> {
     type Error = HpError;
 
     fn next_key_seed<K>(&mut self, seed: K) -> HpResult<Option<K::Value>>
     where
         K: DeserializeSeed<'de>,
     {
-        if !self.array.is_empty() {
-            self.de.value = self.array.pop();
-            seed.deserialize(&mut *self.de).map(Some)
-        } else {
-            if self.len <= 1 {
-                return Ok(None);
-            }
-            self.len -= 2;
-            seed.deserialize(&mut *self.de).map(Some)
+        if self.len == 0 {
+            return Ok(None);
         }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
     }
 
     fn next_value_seed<V>(&mut self, seed: V) -> HpResult<V::Value>
     where
         V: DeserializeSeed<'de>,
     {
-        if !self.array.is_empty() {
-            self.de.value = self.array.pop();
-            seed.deserialize(&mut *self.de)
-        } else {
-            seed.deserialize(&mut *self.de)
-        }
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len.min(self.de.buf.remaining() / 2))
     }
 }
 
-struct Enum<'a, 'de: 'a, B: Bt + BtMut> {
-    de: &'a mut Deserializer<'de, B>,
+/// Reads one variant, either a bare identifier or a `{ variant: content }` map.
+struct Enum<'a, 'de: 'a> {
+    de: &'a mut Deserializer<'de>,
+    /// Whether the variant carries content after its identifier.
+    wrapped: bool,
 }
 
-#[allow(unused)]
-impl<'a, 'de, B: Bt + BtMut> Enum<'a, 'de, B> {
-    fn new(de: &'a mut Deserializer<'de, B>) -> Self {
-        Enum { de }
+impl<'a, 'de> Enum<'a, 'de> {
+    fn new(de: &'a mut Deserializer<'de>, wrapped: bool) -> Self {
+        Enum { de, wrapped }
     }
 }
 
-impl<'de, B: Bt + BtMut> EnumAccess<'de> for Enum<'_, 'de, B> {
+impl<'de> EnumAccess<'de> for Enum<'_, 'de> {
     type Error = HpError;
     type Variant = Self;
 
@@ -308,46 +394,69 @@ impl<'de, B: Bt + BtMut> EnumAccess<'de> for Enum<'_, 'de, B> {
     where
         V: DeserializeSeed<'de>,
     {
-        let val = seed.deserialize(&mut *self.de)?;
+        // A variant index must reach the identifier visitor as an unsigned integer.
+        let val = if self.de.peek_type()? == ValueType::Varint {
+            self.de.decode_type()?;
+            let idx: i64 = decode_varint(&mut self.de.buf.buf)?.try_into()?;
+            match u32::try_from(idx) {
+                Ok(idx) => seed.deserialize(IntoDeserializer::<HpError>::into_deserializer(idx))?,
+                Err(_) => return Err(Error::custom("variant index out of range")),
+            }
+        } else {
+            seed.deserialize(&mut *self.de)?
+        };
         Ok((val, self))
     }
 }
 
 // `VariantAccess` is provided to the `Visitor` to give it the ability to see
 // the content of the single variant that it decided to deserialize.
-impl<'de, B: Bt + BtMut> VariantAccess<'de> for Enum<'_, 'de, B> {
+impl<'de> VariantAccess<'de> for Enum<'_, 'de> {
     type Error = HpError;
 
-    // If the `Visitor` expected this variant to be a unit variant, the input
-    // should have been the plain string case handled in `deserialize_enum`.
+    // A unit variant is normally the bare identifier, a wrapped one must
+    // hold nil.
     fn unit_variant(self) -> HpResult<()> {
-        Err(Error::custom("unint variant"))
+        if self.wrapped {
+            de::Deserialize::deserialize(self.de)
+        } else {
+            Ok(())
+        }
     }
 
-    // Newtype variants are represented in JSON as `{ NAME: VALUE }` so
+    // Newtype variants are represented as `{ NAME: VALUE }` so
     // deserialize the value here.
     fn newtype_variant_seed<T>(self, seed: T) -> HpResult<T::Value>
     where
         T: DeserializeSeed<'de>,
     {
+        if !self.wrapped {
+            return Err(Error::custom("newtype variant must be a map"));
+        }
         seed.deserialize(self.de)
     }
 
-    // Tuple variants are represented in JSON as `{ NAME: [DATA...] }` so
+    // Tuple variants are represented as `{ NAME: [DATA...] }` so
     // deserialize the sequence of data here.
-    fn tuple_variant<V>(self, _len: usize, visitor: V) -> HpResult<V::Value>
+    fn tuple_variant<V>(self, len: usize, visitor: V) -> HpResult<V::Value>
     where
         V: Visitor<'de>,
     {
-        de::Deserializer::deserialize_seq(self.de, visitor)
+        if !self.wrapped {
+            return Err(Error::custom("tuple variant must be a map"));
+        }
+        de::Deserializer::deserialize_tuple(self.de, len, visitor)
     }
 
-    // Struct variants are represented in JSON as `{ NAME: { K: V, ... } }` so
-    // deserialize the inner map here.
-    fn struct_variant<V>(self, _fields: &'static [&'static str], visitor: V) -> HpResult<V::Value>
+    // Struct variants are represented as `{ NAME: { K: V, ... } }` so
+    // deserialize the inner struct here.
+    fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> HpResult<V::Value>
     where
         V: Visitor<'de>,
     {
-        de::Deserializer::deserialize_map(self.de, visitor)
+        if !self.wrapped {
+            return Err(Error::custom("struct variant must be a map"));
+        }
+        de::Deserializer::deserialize_struct(self.de, "", fields, visitor)
     }
 }
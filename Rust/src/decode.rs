@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use algorithm::buf::{Bt, BtMut};
 
-use crate::HpResult;
+use crate::{BufferRef, HpResult, RawValue, ValueRef};
 use crate::ValueType;
 
 use super::make_extension_error;
@@ -99,7 +99,7 @@ pub fn decode_varint<B: Bt>(buffer: &mut B) -> HpResult<Value> {
 }
 
 pub fn decode_string<B: Bt>(buffer: &mut B) -> HpResult<String> {
-    let len: u16 = decode_varint(buffer)?.into();
+    let len: u16 = decode_varint(buffer)?.try_into()?;
     if len == 0 {
         return Ok(String::new());
     }
@@ -117,7 +117,7 @@ pub fn decode_string<B: Bt>(buffer: &mut B) -> HpResult<String> {
 pub fn decode_str_raw<B: Bt+BtMut>(buffer: &mut Buffer<B>, pattern: ValueType) -> HpResult<Value> {
     match pattern {
         ValueType::Str => {
-            let len: u16 = decode_varint(buffer)?.into();
+            let len: u16 = decode_varint(buffer)?.try_into()?;
             if len == 0 {
                 return Ok(Value::from(String::new()));
             }
@@ -132,7 +132,7 @@ pub fn decode_str_raw<B: Bt+BtMut>(buffer: &mut Buffer<B>, pattern: ValueType) -
             Ok(Value::from(val.ok().unwrap()))
         }
         ValueType::Raw => {
-            let len: u16 = decode_varint(buffer)?.into();
+            let len: u16 = decode_varint(buffer)?.try_into()?;
             if len == 0 {
                 return Ok(Value::from(Vec::<u8>::new()));
             }
@@ -150,7 +150,7 @@ pub fn decode_str_raw<B: Bt+BtMut>(buffer: &mut Buffer<B>, pattern: ValueType) -
 
 pub fn decode_map<B: Bt+BtMut>(buffer: &mut Buffer<B>) -> HpResult<Value> {
     let mut map = HashMap::<Value, Value>::new();
-    let arr_len: u32 = decode_varint(buffer)?.into();
+    let arr_len: u32 = decode_varint(buffer)?.try_into()?;
     for _ in 0..arr_len / 2 {
         let key = decode_field(buffer)?;
         let sub_value = decode_field(buffer)?;
@@ -161,7 +161,7 @@ pub fn decode_map<B: Bt+BtMut>(buffer: &mut Buffer<B>) -> HpResult<Value> {
 
 pub fn decode_arr<B: Bt+BtMut>(buffer: &mut Buffer<B>) -> HpResult<Value> {
     let mut arr = Vec::<Value>::new();
-    let arr_len: u16 = decode_varint(buffer)?.into();
+    let arr_len: u16 = decode_varint(buffer)?.try_into()?;
     for _ in 0..arr_len {
         let sub_value = decode_field(buffer)?;
         arr.push(sub_value);
@@ -177,7 +177,9 @@ pub fn decode_by_pattern<B: Bt+BtMut>(buffer: &mut Buffer<B>, pattern: &ValueTyp
         | ValueType::U16
         | ValueType::I16
         | ValueType::U32
-        | ValueType::I32 => decode_number(buffer, *pattern),
+        | ValueType::I32
+        | ValueType::U64
+        | ValueType::I64 => decode_number(buffer, *pattern),
         ValueType::F32 => Ok(Value::F32(buffer.try_get_f32()?)),
         ValueType::F64 => Ok(Value::F64(buffer.try_get_f64()?)),
         ValueType::Varint => decode_varint(buffer),
@@ -185,7 +187,7 @@ pub fn decode_by_pattern<B: Bt+BtMut>(buffer: &mut Buffer<B>, pattern: &ValueTyp
         ValueType::Map => decode_map(buffer),
         ValueType::Arr => decode_arr(buffer),
         ValueType::StrIdx => {
-            let idx: u16 = decode_varint(buffer)?.into();
+            let idx: u16 = decode_varint(buffer)?.try_into()?;
             Ok(Value::from(buffer.get_str(idx)?))
         }
         // ValueType::Kv => {
@@ -199,22 +201,31 @@ pub fn decode_by_pattern<B: Bt+BtMut>(buffer: &mut Buffer<B>, pattern: &ValueTyp
         // }
         // TYPE_AMAP => decode_array!(decode_field(buffer, config), Value::AMap, Value::Map),
         ValueType::Nil => Ok(Value::Nil),
-        _ => fail!((ErrorKind::TypeNotMatchError, "must match type")),
     }
 }
 
 pub fn decode_field<B: Bt+BtMut>(buffer: &mut Buffer<B>) -> HpResult<Value> {
-    let pattern = decode_type(buffer)?.into();
+    let pattern = decode_type(buffer)?;
     decode_by_pattern(buffer, &pattern)
 }
 
+/// Captures the next field as a [`RawValue`] instead of decoding it.
+pub fn decode_raw_value<B: Bt+BtMut>(buffer: &mut Buffer<B>) -> HpResult<RawValue> {
+    let mut input = buffer.buf.chunk();
+    let len = input.len();
+    let raw = RawValue::capture(&mut input, &buffer.str_arr)?;
+    let used = len - input.len();
+    buffer.advance(used);
+    Ok(raw)
+}
+
 pub fn decode_proto<B: Bt+BtMut>(buffer: &mut Buffer<B>) -> HpResult<(String, Vec<Value>)> {
-    let name = decode_str_raw(buffer, ValueType::Str)?.into();
+    let name = decode_str_raw(buffer, ValueType::Str)?.try_into()?;
 
-    let str_len: u16 = decode_varint(buffer)?.into();
+    let str_len: u16 = decode_varint(buffer)?.try_into()?;
     for _ in 0..str_len {
-        let value = decode_str_raw(buffer, ValueType::Str)?.into();
-        buffer.add_str(value);
+        let value: String = decode_str_raw(buffer, ValueType::Str)?.try_into()?;
+        buffer.add_str(&value);
     }
 
     let sub_value = decode_field(buffer)?;
@@ -225,10 +236,10 @@ pub fn decode_proto<B: Bt+BtMut>(buffer: &mut Buffer<B>) -> HpResult<(String, Ve
 }
 
 pub fn decode_msg<B: Bt+BtMut>(buffer: &mut Buffer<B>) -> HpResult<Vec<Value>> {
-    let str_len: u16 = decode_varint(buffer)?.into();
+    let str_len: u16 = decode_varint(buffer)?.try_into()?;
     for _ in 0..str_len {
-        let value = decode_str_raw(buffer, ValueType::Str)?.into();
-        buffer.add_str(value);
+        let value: String = decode_str_raw(buffer, ValueType::Str)?.try_into()?;
+        buffer.add_str(&value);
     }
 
     let sub_value = decode_field(buffer)?;
@@ -240,15 +251,127 @@ pub fn decode_msg<B: Bt+BtMut>(buffer: &mut Buffer<B>) -> HpResult<Vec<Value>> {
 
 
 pub fn decode_msg_map<B: Bt+BtMut>(buffer: &mut Buffer<B>) -> HpResult<Value> {
-    let str_len: u16 = decode_varint(buffer)?.into();
+    let str_len: u16 = decode_varint(buffer)?.try_into()?;
     for _ in 0..str_len {
-        let value = decode_str_raw(buffer, ValueType::Str)?.into();
-        buffer.add_str(value);
+        let value: String = decode_str_raw(buffer, ValueType::Str)?.try_into()?;
+        buffer.add_str(&value);
     }
 
-    let sub_value = decode_map(buffer)?;
+    let sub_value = decode_field(buffer)?;
     match sub_value {
         Value::Map(_) => Ok(sub_value),
+        _ => Err(make_extension_error("proto is not map", None)),
+    }
+}
+
+fn decode_len_ref(buffer: &mut BufferRef) -> HpResult<usize> {
+    let len: u16 = decode_varint(&mut buffer.buf)?.try_into()?;
+    Ok(len as usize)
+}
+
+pub fn decode_str_ref<'a>(buffer: &mut BufferRef<'a>) -> HpResult<&'a str> {
+    let len = decode_len_ref(buffer)?;
+    match std::str::from_utf8(buffer.read_slice(len)?) {
+        Ok(val) => Ok(val),
+        Err(_) => fail!((ErrorKind::StringFormatError, "string format error")),
+    }
+}
+
+pub fn decode_raw_ref<'a>(buffer: &mut BufferRef<'a>) -> HpResult<&'a [u8]> {
+    let len = decode_len_ref(buffer)?;
+    buffer.read_slice(len)
+}
+
+pub fn decode_map_ref<'a>(buffer: &mut BufferRef<'a>) -> HpResult<ValueRef<'a>> {
+    let mut map = HashMap::<ValueRef<'a>, ValueRef<'a>>::new();
+    let arr_len: u32 = decode_varint(&mut buffer.buf)?.try_into()?;
+    for _ in 0..arr_len / 2 {
+        let key = decode_field_ref(buffer)?;
+        let sub_value = decode_field_ref(buffer)?;
+        map.insert(key, sub_value);
+    }
+    Ok(ValueRef::Map(map))
+}
+
+pub fn decode_arr_ref<'a>(buffer: &mut BufferRef<'a>) -> HpResult<ValueRef<'a>> {
+    let arr_len = decode_len_ref(buffer)?;
+    let mut arr = Vec::<ValueRef<'a>>::with_capacity(arr_len.min(buffer.remaining()));
+    for _ in 0..arr_len {
+        arr.push(decode_field_ref(buffer)?);
+    }
+    Ok(ValueRef::Arr(arr))
+}
+
+pub fn decode_by_pattern_ref<'a>(buffer: &mut BufferRef<'a>, pattern: &ValueType) -> HpResult<ValueRef<'a>> {
+    let buf = &mut buffer.buf;
+    match *pattern {
+        ValueType::Bool => Ok(ValueRef::Bool(buf.try_get_u8()? == 1)),
+        ValueType::U8 => Ok(ValueRef::U8(buf.try_get_u8()?)),
+        ValueType::I8 => Ok(ValueRef::I8(buf.try_get_i8()?)),
+        ValueType::U16 => Ok(ValueRef::U16(buf.try_get_u16()?)),
+        ValueType::I16 => Ok(ValueRef::I16(buf.try_get_i16()?)),
+        ValueType::U32 => Ok(ValueRef::U32(buf.try_get_u32()?)),
+        ValueType::I32 => Ok(ValueRef::I32(buf.try_get_i32()?)),
+        ValueType::U64 => Ok(ValueRef::U64(buf.try_get_u64()?)),
+        ValueType::I64 => Ok(ValueRef::I64(buf.try_get_i64()?)),
+        ValueType::F32 => Ok(ValueRef::F32(buf.try_get_f32()?)),
+        ValueType::F64 => Ok(ValueRef::F64(buf.try_get_f64()?)),
+        ValueType::Varint => Ok(ValueRef::Varint(decode_varint(buf)?.try_into()?)),
+        ValueType::Str => Ok(ValueRef::Str(decode_str_ref(buffer)?)),
+        ValueType::Raw => Ok(ValueRef::Raw(decode_raw_ref(buffer)?)),
+        ValueType::Map => decode_map_ref(buffer),
+        ValueType::Arr => decode_arr_ref(buffer),
+        ValueType::StrIdx => {
+            let idx: u16 = decode_varint(buf)?.try_into()?;
+            Ok(ValueRef::Str(buffer.get_str(idx)?))
+        }
+        ValueType::Nil => Ok(ValueRef::Nil),
+    }
+}
+
+pub fn decode_field_ref<'a>(buffer: &mut BufferRef<'a>) -> HpResult<ValueRef<'a>> {
+    let pattern = ValueType::from(buffer.buf.try_get_u8()?);
+    decode_by_pattern_ref(buffer, &pattern)
+}
+
+/// Reads the string table of a message, the entries borrow from the input.
+pub fn decode_str_table_ref(buffer: &mut BufferRef) -> HpResult<()> {
+    let str_len = decode_len_ref(buffer)?;
+    buffer.str_arr.clear();
+    buffer.str_arr.reserve(str_len.min(buffer.remaining()));
+    for _ in 0..str_len {
+        let value = decode_str_ref(buffer)?;
+        buffer.str_arr.push(value);
+    }
+    Ok(())
+}
+
+pub fn decode_proto_ref<'a>(buffer: &mut BufferRef<'a>) -> HpResult<(&'a str, Vec<ValueRef<'a>>)> {
+    let name = decode_str_ref(buffer)?;
+    decode_str_table_ref(buffer)?;
+    match decode_field_ref(buffer)? {
+        ValueRef::Arr(val) => Ok((name, val)),
+        _ => Err(make_extension_error("proto is not array", None)),
+    }
+}
+
+pub fn decode_msg_ref<'a>(buffer: &mut BufferRef<'a>) -> HpResult<Vec<ValueRef<'a>>> {
+    decode_str_table_ref(buffer)?;
+    match decode_field_ref(buffer)? {
+        ValueRef::Arr(val) => Ok(val),
         _ => Err(make_extension_error("proto is not array", None)),
     }
-}
\ No newline at end of file
+}
+
+pub fn decode_msg_map_ref<'a>(buffer: &mut BufferRef<'a>) -> HpResult<ValueRef<'a>> {
+    decode_str_table_ref(buffer)?;
+    match decode_field_ref(buffer)? {
+        sub_value @ ValueRef::Map(_) => Ok(sub_value),
+        _ => Err(make_extension_error("proto is not map", None)),
+    }
+}
+
+/// Captures the next field as a [`RawValue`] instead of decoding it.
+pub fn decode_raw_value_ref(buffer: &mut BufferRef) -> HpResult<RawValue> {
+    RawValue::capture(&mut buffer.buf, &buffer.str_arr)
+}
@@ -0,0 +1,309 @@
+use std::fmt;
+
+use sha2::{Digest as _, Sha256};
+
+use crate::decode::decode_str_ref;
+use crate::transcode::{Token, TokenReader};
+use crate::{BufferRef, HpResult, RawValue, Value};
+
+/// Prefixes every digest, to change along with the canonical form.
+const VERSION: &[u8] = b"hcproto digest v1";
+
+/// A SHA-256 of the content of a value, stable across machines, runs and
+/// versions of this crate.
+///
+/// It is computed over a canonical form, not over the encoded bytes, so it
+/// does not depend on the order of a `Map`, on the string table, nor on how
+/// a value was encoded:
+///
+/// - integers are hashed by value whatever their type, as the encoder picks
+///   the width (`U32(5)`, `Varint(5)` and `U8(5)` are the same content).
+///   Floats keep their width, with `-0.0` hashed as `0.0` and one NaN
+/// - `Str` and `Raw` are distinct, and so are `Arr` and `Map`
+/// - a `Map` is hashed as the sorted digests of its entries
+/// - a `Value::Encoded` is hashed as the value it holds
+///
+/// So a value, its [`RawValue`], and the message [`crate::to_vec`] writes for
+/// it all have the same digest.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Digest(pub [u8; 32]);
+
+impl Digest {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// The first 128 bits, when a shorter key is enough.
+    pub fn short(&self) -> u128 {
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&self.0[..16]);
+        u128::from_be_bytes(bytes)
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Digest({})", self)
+    }
+}
+
+impl Value {
+    /// The digest of the content of the value, see [`Digest`].
+    pub fn digest(&self) -> Digest {
+        let mut hasher = Sha256::new();
+        hasher.update(VERSION);
+        write_value(&mut hasher, self);
+        Digest(hasher.finalize().into())
+    }
+}
+
+impl RawValue {
+    /// The digest of the value, without decoding it.
+    pub fn digest(&self) -> Digest {
+        digest_slice(self.as_bytes()).expect("RawValue is checked when built")
+    }
+}
+
+/// The digest of the value in a message of a single field, as written by
+/// [`crate::to_vec`], streamed from the bytes without building a [`Value`].
+pub fn digest_slice(bytes: &[u8]) -> HpResult<Digest> {
+    let mut reader = TokenReader::new(bytes)?;
+    let mut hasher = Sha256::new();
+    hasher.update(VERSION);
+    write_tokens(&mut hasher, &mut reader)?;
+    reader.finish()?;
+    Ok(Digest(hasher.finalize().into()))
+}
+
+/// The name of a message written by [`crate::encode_proto`] and the digest of
+/// its fields, the same as the one of `Value::Arr(fields)`.
+pub fn digest_proto(bytes: &[u8]) -> HpResult<(&str, Digest)> {
+    let mut buffer = BufferRef::new(bytes);
+    let name = decode_str_ref(&mut buffer)?;
+    Ok((name, digest_slice(buffer.buf)?))
+}
+
+// Tags of the canonical form, one per kind of content.
+const NIL: u8 = 0;
+const BOOL: u8 = 1;
+const INT: u8 = 2;
+const F32: u8 = 3;
+const F64: u8 = 4;
+const STR: u8 = 5;
+const RAW: u8 = 6;
+const ARR: u8 = 7;
+const MAP: u8 = 8;
+
+fn write_len(hasher: &mut Sha256, tag: u8, len: usize) {
+    hasher.update([tag]);
+    hasher.update((len as u64).to_le_bytes());
+}
+
+fn write_scalar(hasher: &mut Sha256, token: Token) {
+    let int = |hasher: &mut Sha256, v: i128| {
+        hasher.update([INT]);
+        hasher.update(v.to_le_bytes());
+    };
+    match token {
+        Token::Nil => hasher.update([NIL]),
+        Token::Bool(v) => hasher.update([BOOL, v as u8]),
+        Token::U8(v) => int(hasher, v as i128),
+        Token::I8(v) => int(hasher, v as i128),
+        Token::U16(v) => int(hasher, v as i128),
+        Token::I16(v) => int(hasher, v as i128),
+        Token::U32(v) => int(hasher, v as i128),
+        Token::I32(v) => int(hasher, v as i128),
+        Token::U64(v) => int(hasher, v as i128),
+        Token::I64(v) | Token::Varint(v) => int(hasher, v as i128),
+        Token::F32(v) => {
+            let v = if v.is_nan() { f32::NAN } else { v + 0.0 };
+            hasher.update([F32]);
+            hasher.update(v.to_bits().to_le_bytes());
+        }
+        Token::F64(v) => {
+            let v = if v.is_nan() { f64::NAN } else { v + 0.0 };
+            hasher.update([F64]);
+            hasher.update(v.to_bits().to_le_bytes());
+        }
+        Token::Str(v) => {
+            write_len(hasher, STR, v.len());
+            hasher.update(v);
+        }
+        Token::Raw(v) => {
+            write_len(hasher, RAW, v.len());
+            hasher.update(v);
+        }
+        Token::Arr(_) | Token::Map(_) => unreachable!("containers are not scalars"),
+    }
+}
+
+/// Sorts the digests of the entries, each hashed on its own, and writes them.
+fn write_entries(hasher: &mut Sha256, mut entries: Vec<[u8; 32]>) {
+    entries.sort_unstable();
+    write_len(hasher, MAP, entries.len());
+    for entry in entries {
+        hasher.update(entry);
+    }
+}
+
+fn write_value(hasher: &mut Sha256, value: &Value) {
+    let token = match value {
+        Value::Nil => Token::Nil,
+        Value::Bool(v) => Token::Bool(*v),
+        Value::U8(v) => Token::U8(*v),
+        Value::I8(v) => Token::I8(*v),
+        Value::U16(v) => Token::U16(*v),
+        Value::I16(v) => Token::I16(*v),
+        Value::U32(v) => Token::U32(*v),
+        Value::I32(v) => Token::I32(*v),
+        Value::U64(v) => Token::U64(*v),
+        Value::I64(v) => Token::I64(*v),
+        Value::Varint(v) => Token::Varint(*v),
+        Value::F32(v) => Token::F32(*v),
+        Value::F64(v) => Token::F64(*v),
+        Value::Str(v) => Token::Str(v),
+        Value::Raw(v) => Token::Raw(v),
+        Value::Arr(arr) => {
+            write_len(hasher, ARR, arr.len());
+            for item in arr {
+                write_value(hasher, item);
+            }
+            return;
+        }
+        Value::Map(map) => {
+            let entries = map
+                .iter()
+                .map(|(key, value)| {
+                    let mut entry = Sha256::new();
+                    write_value(&mut entry, key);
+                    write_value(&mut entry, value);
+                    entry.finalize().into()
+                })
+                .collect();
+            write_entries(hasher, entries);
+            return;
+        }
+        Value::Encoded(raw) => {
+            let mut reader = TokenReader::new(raw.as_bytes()).expect("RawValue is checked when built");
+            write_tokens(hasher, &mut reader).expect("RawValue is checked when built");
+            return;
+        }
+    };
+    write_scalar(hasher, token);
+}
+
+fn write_tokens(hasher: &mut Sha256, reader: &mut TokenReader) -> HpResult<()> {
+    match reader.next()? {
+        Token::Arr(len) => {
+            write_len(hasher, ARR, len);
+            for _ in 0..len {
+                write_tokens(hasher, reader)?;
+            }
+        }
+        Token::Map(len) => {
+            // Grown as the entries are read, as `len` comes from the input.
+            let mut entries = Vec::new();
+            for _ in 0..len {
+                let mut entry = Sha256::new();
+                write_tokens(&mut entry, reader)?;
+                write_tokens(&mut entry, reader)?;
+                entries.push(entry.finalize().into());
+            }
+            write_entries(hasher, entries);
+        }
+        token => write_scalar(hasher, token),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use algorithm::buf::{Bt, BtMut};
+
+    use crate::{digest_proto, digest_slice, encode_proto, encode_varint, to_vec, Buffer, RawValue, Value};
+
+    #[test]
+    fn test_digest() {
+        let mut a = HashMap::new();
+        let mut b = HashMap::new();
+        for i in 0..50u32 {
+            a.insert(Value::Str(format!("key {}", i)), Value::U32(i));
+        }
+        for i in (0..50).rev() {
+            b.insert(Value::Str(format!("key {}", i)), Value::Varint(i));
+        }
+        let a = Value::Arr(vec![Value::Map(a), Value::F64(-0.0), Value::Raw(vec![1, 2])]);
+        let b = Value::Arr(vec![Value::Map(b), Value::F64(0.0), Value::Raw(vec![1, 2])]);
+        assert_eq!(a.digest(), b.digest());
+        assert_eq!(a.digest().to_string().len(), 64);
+
+        // The encoded forms agree, whatever their string table.
+        let bytes = to_vec(&a).unwrap();
+        assert_eq!(digest_slice(&bytes).unwrap(), a.digest());
+        let raw = RawValue::from_value(&b).unwrap();
+        assert_eq!(raw.digest(), a.digest());
+        let fields = Value::Arr(vec![b.clone(), Value::Nil]);
+        assert_eq!(Value::Arr(vec![Value::Encoded(raw), Value::Nil]).digest(), fields.digest());
+
+        let mut buffer = Buffer::new();
+        encode_proto(&mut buffer, "cached", vec![a.clone(), Value::Nil]).unwrap();
+        let (name, digest) = digest_proto(buffer.chunk()).unwrap();
+        assert_eq!(name, "cached");
+        assert_eq!(digest, fields.digest());
+
+        // Different content, different digests.
+        let digests = [
+            Value::Nil,
+            Value::Bool(false),
+            Value::U8(0),
+            Value::F32(0.0),
+            Value::F64(0.0),
+            Value::Str(String::new()),
+            Value::Raw(vec![]),
+            Value::Arr(vec![]),
+            Value::Map(HashMap::new()),
+            Value::Arr(vec![Value::Str("ab".to_string()), Value::Str("c".to_string())]),
+            Value::Arr(vec![Value::Str("a".to_string()), Value::Str("bc".to_string())]),
+            Value::U64(u64::MAX),
+            Value::I64(-1),
+        ]
+        .map(|v| v.digest());
+        for (i, digest) in digests.iter().enumerate() {
+            assert!(!digests[i + 1..].contains(digest), "{}", i);
+        }
+
+        // Pinned, so a change to the canonical form is noticed.
+        let pinned = Value::Arr(vec![Value::Str("a".to_string()), Value::Varint(-1), Value::Nil]);
+        assert_eq!(pinned.digest().to_string(), "f753b34fe377f753381fe430996cb0eb94dc97f443ee1e4dbd23722efbda1d6b");
+        // A length the input cannot hold is an error.
+        for len in [4, u32::MAX as i64] {
+            for kind in [16, 17] {
+                let mut buffer = Buffer::new();
+                buffer.put_slice(&[0, kind]);
+                encode_varint(&mut buffer, &Value::Varint(len)).unwrap();
+                buffer.put_slice(&[0, 0]);
+                assert!(digest_slice(buffer.chunk()).is_err());
+            }
+        }
+        // So is one nested too deep, before the stack runs out.
+        let mut buffer = Buffer::new();
+        buffer.put_u8(0);
+        for _ in 0..200_000 {
+            buffer.put_u8(16);
+            encode_varint(&mut buffer, &Value::Varint(1)).unwrap();
+        }
+        buffer.put_u8(0);
+        assert!(digest_slice(buffer.chunk()).is_err());
+    }
+}
@@ -2,7 +2,12 @@ use std::io::Result;
 
 use algorithm::buf::{Bt, BtMut};
 
-use crate::{get_type_by_value, Buffer, Value, ValueType};
+use algorithm::buf::BinaryMut;
+use algorithm::HashMap;
+
+use crate::buffer::intern_str;
+use crate::raw_value::splice;
+use crate::{get_type_by_value, Buffer, HpResult, Value, ValueType};
 
 #[inline(always)]
 pub fn append_and_align<B: BtMut>(buffer: &mut B, val: &[u8]) -> Result<()> {
@@ -76,7 +81,7 @@ pub fn encode_number<B: Bt + BtMut>(buffer: &mut Buffer<B>, value: &Value) -> Re
 }
 
 #[inline(always)]
-pub fn encode_varint<B: BtMut>(buffer: &mut B, value: &Value) -> Result<()> {
+fn zigzag(value: &Value) -> u64 {
     let val = match *value {
         Value::U8(val) => val as i64,
         Value::I8(val) => val as i64,
@@ -85,31 +90,72 @@ pub fn encode_varint<B: BtMut>(buffer: &mut B, value: &Value) -> Result<()> {
         Value::U32(val) => val as i64,
         Value::I32(val) => val as i64,
         Value::U64(val) => val as i64,
-        Value::I64(val) => val as i64,
-        Value::Varint(val) => val as i64,
+        Value::I64(val) => val,
+        Value::Varint(val) => val,
         _ => unreachable!("encode_number only"),
     };
-    let mut real = if val < 0 {
+    if val < 0 {
         (-(val + 1)) as u64 * 2 + 1
     } else {
         (val as u64) * 2
-    };
+    }
+}
+
+/// Writes the varint of `value` into `data`, returning how many bytes it took.
+#[inline(always)]
+pub(crate) fn varint_bytes(value: &Value, data: &mut [u8; 10]) -> usize {
+    let mut real = zigzag(value);
+    let mut len = 0;
     loop {
-        let data = (real & 0x7F) as u8;
-        real = real >> 7;
+        data[len] = (real & 0x7F) as u8;
+        real >>= 7;
+        len += 1;
         if real == 0 {
-            buffer.put_u8(data);
             break;
-        } else {
-            buffer.put_u8(data | 0x80);
         }
+        data[len - 1] |= 0x80;
+    }
+    len
+}
+
+#[inline(always)]
+pub fn encode_varint<B: BtMut>(buffer: &mut B, value: &Value) -> Result<()> {
+    let mut data = [0u8; 10];
+    let len = varint_bytes(value, &mut data);
+    buffer.put_slice(&data[..len]);
+    Ok(())
+}
+
+/// Bytes reserved for a length that is only known once its items are written.
+pub const PADDED_VARINT_LEN: usize = 5;
+
+/// Writes `value` as a varint that fills exactly `out.len()` bytes.
+///
+/// The unused high groups are written as zero with the continuation bit set,
+/// which `decode_varint` reads back as the same value. This lets a length be
+/// reserved up front and patched in place later.
+pub fn encode_varint_padded(out: &mut [u8], value: &Value) -> Result<()> {
+    let mut real = zigzag(value);
+    let len = out.len();
+    for (i, data) in out.iter_mut().enumerate() {
+        *data = (real & 0x7F) as u8;
+        real >>= 7;
+        if i + 1 < len {
+            *data |= 0x80;
+        }
+    }
+    if real != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "varint too big for the reserved space",
+        ));
     }
     Ok(())
 }
 
 #[inline(always)]
 pub fn encode_str_idx<B: Bt + BtMut>(buffer: &mut Buffer<B>, pattern: &str) -> Result<()> {
-    let idx = buffer.add_str(pattern.to_string());
+    let idx = buffer.add_str(pattern);
     encode_sure_type(buffer, ValueType::StrIdx)?;
     encode_varint(buffer, &Value::U16(idx))?;
     Ok(())
@@ -123,8 +169,8 @@ pub fn encode_str_idx<B: Bt + BtMut>(buffer: &mut Buffer<B>, pattern: &str) -> R
 // }
 
 pub fn encode_string<B: BtMut>(buffer: &mut B, val: &str) -> Result<()> {
-    encode_varint(buffer, &Value::U16(val.as_bytes().len() as u16))?;
-    append_and_align(buffer, &val.as_bytes()[..])?;
+    encode_varint(buffer, &Value::U16(val.len() as u16))?;
+    append_and_align(buffer, val.as_bytes())?;
     Ok(())
 }
 
@@ -132,41 +178,99 @@ pub fn encode_string<B: BtMut>(buffer: &mut B, val: &str) -> Result<()> {
 pub fn encode_str_raw<B: Bt + BtMut>(buffer: &mut Buffer<B>, value: &Value) -> Result<()> {
     match *value {
         Value::Str(ref val) => {
-            encode_varint(buffer, &Value::U16(val.as_bytes().len() as u16))?;
-            append_and_align(buffer, &val.as_bytes()[..])?;
+            encode_string(buffer, val)?;
         }
         Value::Raw(ref val) => {
             encode_varint(buffer, &Value::U16(val.len() as u16))?;
-            append_and_align(buffer, &val[..])?;
+            append_and_align(buffer, val)?;
         }
         _ => unreachable!("encode_str_raw only"),
     }
     Ok(())
 }
 
-pub fn encode_map<B: Bt + BtMut>(buffer: &mut Buffer<B>, value: &Value) -> Result<()> {
-    match *value {
-        Value::Map(ref val) => {
-            encode_varint(buffer, &Value::from((val.len() * 2) as u32))?;
-            for (name, sub_value) in val {
-                encode_field(buffer, name)?;
-                encode_field(buffer, sub_value)?;
-            }
+/// A string table the field writer interns `Value::Str` into.
+pub(crate) trait StrTable<'a> {
+    fn intern(&mut self, val: &'a str) -> u16;
+}
+
+/// The owned table of a [`Buffer`], used when the strings may not outlive the call.
+struct BufferTable<'b> {
+    str_arr: &'b mut Vec<String>,
+    str_map: &'b mut std::collections::HashMap<String, u16>,
+}
+
+impl<'a> StrTable<'a> for BufferTable<'_> {
+    #[inline(always)]
+    fn intern(&mut self, val: &'a str) -> u16 {
+        intern_str(self.str_arr, self.str_map, val)
+    }
+}
+
+/// A table of strings borrowed from the value being encoded, it never copies them.
+#[derive(Default)]
+pub(crate) struct BorrowedTable<'a> {
+    pub(crate) str_arr: Vec<&'a str>,
+    str_map: HashMap<&'a str, u16>,
+}
+
+impl<'a> BorrowedTable<'a> {
+    fn with_capacity(cap: usize) -> Self {
+        BorrowedTable {
+            str_arr: Vec::with_capacity(cap),
+            str_map: HashMap::with_capacity(cap),
         }
-        _ => unreachable!("encode_map only"),
+    }
+}
+
+impl<'a> StrTable<'a> for BorrowedTable<'a> {
+    #[inline(always)]
+    fn intern(&mut self, val: &'a str) -> u16 {
+        let str_arr = &mut self.str_arr;
+        *self.str_map.entry(val).or_insert_with(|| {
+            str_arr.push(val);
+            str_arr.len() as u16 - 1
+        })
+    }
+}
+
+fn write_arr<'a, W: BtMut, T: StrTable<'a>>(buf: &mut W, table: &mut T, val: &'a [Value]) -> Result<()> {
+    buf.put_u8(ValueType::Arr as u8);
+    encode_varint(buf, &Value::from(val.len() as u16))?;
+    for v in val {
+        write_field(buf, table, v)?;
     }
     Ok(())
 }
 
-pub fn encode_field<B: Bt + BtMut>(buffer: &mut Buffer<B>, value: &Value) -> Result<()> {
-    match &*value {
-        Value::Bool(_) => {
-            encode_type(buffer, value)?;
-            encode_bool(buffer, value)?;
+fn write_map<'a, W: BtMut, T: StrTable<'a>>(
+    buf: &mut W,
+    table: &mut T,
+    val: &'a std::collections::HashMap<Value, Value>,
+) -> Result<()> {
+    encode_varint(buf, &Value::from((val.len() * 2) as u32))?;
+    for (name, sub_value) in val {
+        write_field(buf, table, name)?;
+        write_field(buf, table, sub_value)?;
+    }
+    Ok(())
+}
+
+fn write_field<'a, W: BtMut, T: StrTable<'a>>(buf: &mut W, table: &mut T, value: &'a Value) -> Result<()> {
+    match value {
+        Value::Bool(v) => {
+            buf.put_slice(&[ValueType::Bool as u8, *v as u8]);
+        }
+        Value::U8(v) => {
+            buf.put_slice(&[ValueType::U8 as u8, *v]);
         }
-        Value::U8(_) | Value::I8(_) => {
-            encode_type(buffer, value)?;
-            encode_number(buffer, value)?;
+        Value::I8(v) => {
+            buf.put_slice(&[ValueType::I8 as u8, *v as u8]);
+        }
+        // A varint holds an i64, larger values keep their fixed width.
+        Value::U64(v) if *v > i64::MAX as u64 => {
+            buf.put_u8(ValueType::U64 as u8);
+            buf.put_u64(*v);
         }
         Value::U16(_)
         | Value::I16(_)
@@ -175,87 +279,137 @@ pub fn encode_field<B: Bt + BtMut>(buffer: &mut Buffer<B>, value: &Value) -> Res
         | Value::U64(_)
         | Value::I64(_)
         | Value::Varint(_) => {
-            encode_sure_type(buffer, ValueType::Varint)?;
-            encode_varint(buffer, value)?;
+            buf.put_u8(ValueType::Varint as u8);
+            encode_varint(buf, value)?;
         }
         Value::F32(v) => {
-            encode_sure_type(buffer, ValueType::F32)?;
-            buffer.buf.put_f32(*v);
+            buf.put_u8(ValueType::F32 as u8);
+            buf.put_f32(*v);
         }
         Value::F64(v) => {
-            encode_sure_type(buffer, ValueType::F64)?;
-            buffer.buf.put_f64(*v);
+            buf.put_u8(ValueType::F64 as u8);
+            buf.put_f64(*v);
         }
         Value::Str(ref pattern) => {
-            encode_str_idx(buffer, pattern)?;
+            let idx = table.intern(pattern);
+            buf.put_u8(ValueType::StrIdx as u8);
+            encode_varint(buf, &Value::U16(idx))?;
         }
-        Value::Raw(_) => {
-            encode_type(buffer, value)?;
-            encode_str_raw(buffer, value)?;
+        Value::Raw(ref val) => {
+            buf.put_u8(ValueType::Raw as u8);
+            encode_varint(buf, &Value::U16(val.len() as u16))?;
+            append_and_align(buf, val)?;
         }
         Value::Arr(ref val) => {
-            encode_type(buffer, value)?;
-            encode_varint(buffer, &Value::from(val.len() as u16))?;
-            for v in val {
-                encode_field(buffer, v)?;
-            }
+            write_arr(buf, table, val)?;
         }
-        Value::Map(_) => {
-            encode_type(buffer, value)?;
-            encode_map(buffer, value)?;
+        Value::Map(ref val) => {
+            buf.put_u8(ValueType::Map as u8);
+            write_map(buf, table, val)?;
+        }
+        Value::Encoded(ref val) => {
+            splice(val.as_bytes(), buf, table).map_err(|err| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string())
+            })?;
         }
-        // Value::Kv(ref key, ref val) => {
-        //     encode_type(buffer, value)?;
-        //     encode_str_idx_not_type(buffer, key)?;
-        //     encode_varint(buffer, &Value::from(val.len() as u16))?;
-        //     for v in val {
-        //         encode_field(buffer, v)?;
-        //     }
-        // }
         Value::Nil => {
-            encode_type(buffer, value)?;
+            buf.put_u8(ValueType::Nil as u8);
         }
     }
     Ok(())
 }
 
-pub fn encode_proto<B: Bt + BtMut>(
-    buffer: &mut Buffer<B>,
-    name: &String,
-    infos: Vec<Value>,
-) -> Result<()> {
-    let mut sub_buffer = Buffer::new();
-    encode_field(&mut sub_buffer, &Value::from(infos))?;
-
-    encode_str_raw(buffer, &Value::Str(name.clone()))?;
-    encode_varint(buffer, &Value::U16(sub_buffer.str_arr.len() as u16))?;
-    for v in &sub_buffer.str_arr {
-        encode_str_raw(buffer, &Value::Str(v.to_string()))?;
+pub fn encode_map<B: Bt + BtMut>(buffer: &mut Buffer<B>, value: &Value) -> Result<()> {
+    match *value {
+        Value::Map(ref val) => {
+            let Buffer { buf, str_arr, str_map } = buffer;
+            write_map(buf, &mut BufferTable { str_arr, str_map }, val)
+        }
+        _ => unreachable!("encode_map only"),
     }
+}
 
-    buffer.buf.put_slice(sub_buffer.chunk());
-    Ok(())
+pub fn encode_arr<B: Bt + BtMut>(buffer: &mut Buffer<B>, val: &[Value]) -> Result<()> {
+    let Buffer { buf, str_arr, str_map } = buffer;
+    write_arr(buf, &mut BufferTable { str_arr, str_map }, val)
 }
 
-pub fn encode_msg<B: Bt + BtMut>(buffer: &mut Buffer<B>, infos: Vec<Value>) -> Result<()> {
-    let mut sub_buffer = Buffer::new();
-    encode_field(&mut sub_buffer, &Value::from(infos))?;
+pub fn encode_field<B: Bt + BtMut>(buffer: &mut Buffer<B>, value: &Value) -> Result<()> {
+    let Buffer { buf, str_arr, str_map } = buffer;
+    write_field(buf, &mut BufferTable { str_arr, str_map }, value)
+}
+
+/// Splices the field of an encoded message, as a [`crate::RawValue`] holds it,
+/// into `buffer`.
+pub(crate) fn splice_encoded<B: Bt + BtMut>(buffer: &mut Buffer<B>, bytes: &[u8]) -> HpResult<()> {
+    let Buffer { buf, str_arr, str_map } = buffer;
+    splice(bytes, buf, &mut BufferTable { str_arr, str_map })
+}
+
+/// Reusable state for encoding whole messages.
+///
+/// A message is written as its string table followed by the body, so the body
+/// has to be encoded into a scratch buffer first. `Encoder` keeps that scratch
+/// buffer between calls, and interns the strings of the message by borrowing
+/// them from the `Value` being encoded, so no field is ever copied into the
+/// table. The table is sized from the previous message.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    body: BinaryMut,
+    str_cap: usize,
+}
 
-    encode_varint(buffer, &Value::U16(sub_buffer.str_arr.len() as u16))?;
-    for v in &sub_buffer.str_arr {
-        encode_str_raw(buffer, &Value::Str(v.to_string()))?;
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder::default()
     }
-    buffer.put_slice(sub_buffer.chunk());
-    Ok(())
+
+    pub fn encode_proto<B: Bt + BtMut>(
+        &mut self,
+        buffer: &mut Buffer<B>,
+        name: &str,
+        infos: &[Value],
+    ) -> Result<()> {
+        self.body.clear();
+        let mut table = BorrowedTable::with_capacity(self.str_cap);
+        write_arr(&mut self.body, &mut table, infos)?;
+        encode_string(buffer, name)?;
+        self.write_to(buffer, &table)
+    }
+
+    pub fn encode_msg<B: Bt + BtMut>(&mut self, buffer: &mut Buffer<B>, infos: &[Value]) -> Result<()> {
+        self.body.clear();
+        let mut table = BorrowedTable::with_capacity(self.str_cap);
+        write_arr(&mut self.body, &mut table, infos)?;
+        self.write_to(buffer, &table)
+    }
+
+    pub fn encode_msg_map<B: Bt + BtMut>(&mut self, buffer: &mut Buffer<B>, map: &Value) -> Result<()> {
+        self.body.clear();
+        let mut table = BorrowedTable::with_capacity(self.str_cap);
+        write_field(&mut self.body, &mut table, map)?;
+        self.write_to(buffer, &table)
+    }
+
+    fn write_to<B: Bt + BtMut>(&mut self, buffer: &mut Buffer<B>, table: &BorrowedTable) -> Result<()> {
+        self.str_cap = table.str_arr.len();
+        encode_varint(buffer, &Value::U16(table.str_arr.len() as u16))?;
+        for v in &table.str_arr {
+            encode_string(buffer, v)?;
+        }
+        buffer.put_slice(self.body.chunk());
+        Ok(())
+    }
+}
+
+pub fn encode_proto<B: Bt + BtMut>(buffer: &mut Buffer<B>, name: &str, infos: Vec<Value>) -> Result<()> {
+    Encoder::new().encode_proto(buffer, name, &infos)
+}
+
+pub fn encode_msg<B: Bt + BtMut>(buffer: &mut Buffer<B>, infos: Vec<Value>) -> Result<()> {
+    Encoder::new().encode_msg(buffer, &infos)
 }
 
 pub fn encode_msg_map<B: Bt + BtMut>(buffer: &mut Buffer<B>, map: Value) -> Result<()> {
-    let mut sub_buffer = Buffer::new();
-    encode_field(&mut sub_buffer, &map)?;
-    encode_varint(buffer, &Value::U16(sub_buffer.str_arr.len() as u16))?;
-    for v in &sub_buffer.str_arr {
-        encode_str_raw(buffer, &Value::Str(v.to_string()))?;
-    }
-    buffer.put_slice(sub_buffer.chunk());
-    Ok(())
+    Encoder::new().encode_msg_map(buffer, &map)
 }
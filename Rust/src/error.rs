@@ -47,15 +47,15 @@ pub type HpResult<T> = Result<T, HpError>;
 impl PartialEq for HpError {
     fn eq(&self, other: &HpError) -> bool {
         match (&self.repr, &other.repr) {
-            (&ErrorRepr::WithDescription(kind_a, _), &ErrorRepr::WithDescription(kind_b, _)) => {
+            (ErrorRepr::WithDescription(kind_a, _), ErrorRepr::WithDescription(kind_b, _)) => {
                 kind_a == kind_b
             }
             (
                 &ErrorRepr::WithDescriptionAndDetail(kind_a, _, _),
                 &ErrorRepr::WithDescriptionAndDetail(kind_b, _, _),
             ) => kind_a == kind_b,
-            (&ErrorRepr::ExtensionError(ref a, _), &ErrorRepr::ExtensionError(ref b, _)) => {
-                *a == *b
+            (ErrorRepr::ExtensionError(a, _), ErrorRepr::ExtensionError(b, _)) => {
+                a == b
             }
             _ => false,
         }
@@ -148,16 +148,13 @@ impl HpError {
 
     /// Indicates that this failure is an IO failure.
     pub fn is_io_error(&self) -> bool {
-        match self.kind() {
-            ErrorKind::IoError => true,
-            _ => false,
-        }
+        matches!(self.kind(), ErrorKind::IoError)
     }
 
     /// Returns the extension error code
     pub fn extension_error_code(&self) -> Option<&str> {
         match self.repr {
-            ErrorRepr::ExtensionError(ref code, _) => Some(&code),
+            ErrorRepr::ExtensionError(ref code, _) => Some(code),
             _ => None,
         }
     }
@@ -165,8 +162,8 @@ impl HpError {
     /// Returns the extension error detail
     pub fn extension_error_detail(&self) -> Option<&str> {
         match self.repr {
-            ErrorRepr::ExtensionError(_, ref detail) => Some(&detail),
-            ErrorRepr::WithDescriptionAndDetail(_, _, ref detail) => Some(&detail),
+            ErrorRepr::ExtensionError(_, ref detail) => Some(detail),
+            ErrorRepr::WithDescriptionAndDetail(_, _, ref detail) => Some(detail),
             _ => None,
         }
     }
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+
+use algorithm::buf::Bt;
+use serde_json::{Map, Number, Value as Json};
+
+use crate::decode::{decode_field_ref, decode_str_table_ref};
+use crate::{Buffer, BufferRef, Encoder, ErrorKind, HpError, HpResult, Value};
+
+pub use crate::transcode::MAP_TAG;
+
+/// A `Raw` value, as `{"$raw": "<base64>"}`.
+pub const RAW_TAG: &str = "$raw";
+/// A NaN or infinite float, as `{"$float": "NaN" | "inf" | "-inf"}`.
+pub const FLOAT_TAG: &str = "$float";
+
+/// Converts JSON into a [`Value`].
+///
+/// Integers become `Varint`, or `U64` when they do not fit an `i64`, other
+/// numbers `F64`. An object with a single key named after one of the tags
+/// ([`RAW_TAG`], [`FLOAT_TAG`], [`MAP_TAG`]) and a well formed content is read
+/// as the value the tag stands for, every other object as a `Map` of `Str`
+/// keys.
+impl From<Json> for Value {
+    fn from(json: Json) -> Value {
+        match json {
+            Json::Null => Value::Nil,
+            Json::Bool(v) => Value::Bool(v),
+            Json::Number(v) => {
+                if let Some(v) = v.as_i64() {
+                    Value::Varint(v)
+                } else if let Some(v) = v.as_u64() {
+                    Value::U64(v)
+                } else {
+                    Value::F64(v.as_f64().unwrap_or(f64::NAN))
+                }
+            }
+            Json::String(v) => Value::Str(v),
+            Json::Array(v) => Value::Arr(v.into_iter().map(Value::from).collect()),
+            Json::Object(v) => match from_tagged(&v) {
+                Some(value) => value,
+                None => Value::Map(
+                    v.into_iter()
+                        .map(|(k, v)| (Value::Str(k), Value::from(v)))
+                        .collect(),
+                ),
+            },
+        }
+    }
+}
+
+/// Converts a [`Value`] into JSON, the reverse of `From<Json> for Value`.
+///
+/// Every integer becomes a JSON number, so `U8(1)` and `Varint(1)` both read
+/// back as `Varint(1)`, and `F32` reads back as `F64`. `Raw`, non finite
+/// floats and maps with non `Str` keys, or with a single key named after a
+/// tag, are written as tagged objects, so none reads back as something else.
+/// It fails only when a `Value::Encoded` can not be decoded.
+impl TryFrom<Value> for Json {
+    type Error = HpError;
+
+    fn try_from(value: Value) -> HpResult<Json> {
+        Ok(match value {
+            Value::Nil => Json::Null,
+            Value::Bool(v) => Json::Bool(v),
+            Value::U8(v) => Json::from(v),
+            Value::I8(v) => Json::from(v),
+            Value::U16(v) => Json::from(v),
+            Value::I16(v) => Json::from(v),
+            Value::U32(v) => Json::from(v),
+            Value::I32(v) => Json::from(v),
+            Value::U64(v) => Json::from(v),
+            Value::I64(v) | Value::Varint(v) => Json::from(v),
+            Value::F32(v) => float_to_json(v as f64),
+            Value::F64(v) => float_to_json(v),
+            Value::Str(v) => Json::String(v),
+            Value::Raw(v) => tagged(RAW_TAG, Json::String(base64_encode(&v))),
+            Value::Arr(v) => Json::Array(
+                v.into_iter()
+                    .map(Json::try_from)
+                    .collect::<HpResult<_>>()?,
+            ),
+            Value::Map(v) => {
+                if v.keys().all(|k| matches!(k, Value::Str(k) if v.len() > 1 || !is_tag(k))) {
+                    let mut map = Map::with_capacity(v.len());
+                    for (k, v) in v {
+                        if let Value::Str(k) = k {
+                            map.insert(k, Json::try_from(v)?);
+                        }
+                    }
+                    Json::Object(map)
+                } else {
+                    let mut entries = Vec::with_capacity(v.len());
+                    for (k, v) in v {
+                        entries.push(Json::Array(vec![Json::try_from(k)?, Json::try_from(v)?]));
+                    }
+                    tagged(MAP_TAG, Json::Array(entries))
+                }
+            }
+            Value::Encoded(v) => Json::try_from(v.to_value()?)?,
+        })
+    }
+}
+
+/// Encodes a JSON document as a message holding a single value.
+pub fn json_to_proto(json: &[u8]) -> HpResult<Vec<u8>> {
+    let json: Json = serde_json::from_slice(json).map_err(|err| {
+        HpError::from((ErrorKind::ParseError, "json parse error", err.to_string()))
+    })?;
+    let value = Value::from(json);
+    let mut buffer = Buffer::new();
+    Encoder::new().encode_msg_map(&mut buffer, &value)?;
+    Ok(buffer.buf.chunk().to_vec())
+}
+
+/// Decodes a message holding a single value into a JSON document.
+pub fn proto_to_json(buf: &[u8]) -> HpResult<Vec<u8>> {
+    let mut buffer = BufferRef::new(buf);
+    decode_str_table_ref(&mut buffer)?;
+    let value = decode_field_ref(&mut buffer)?.into_owned();
+    let json = Json::try_from(value)?;
+    serde_json::to_vec(&json).map_err(|err| {
+        HpError::from((ErrorKind::ParseError, "json write error", err.to_string()))
+    })
+}
+
+/// Whether an object key is the name of a tag, so can not be the only key of
+/// an object written as is.
+pub(crate) fn is_tag(key: &str) -> bool {
+    [RAW_TAG, FLOAT_TAG, MAP_TAG].contains(&key)
+}
+
+pub(crate) fn tagged(tag: &str, content: Json) -> Json {
+    let mut map = Map::with_capacity(1);
+    map.insert(tag.to_string(), content);
+    Json::Object(map)
+}
+
+pub(crate) fn float_to_json(v: f64) -> Json {
+    match Number::from_f64(v) {
+        Some(v) => Json::Number(v),
+        None if v.is_nan() => tagged(FLOAT_TAG, Json::from("NaN")),
+        None if v > 0.0 => tagged(FLOAT_TAG, Json::from("inf")),
+        None => tagged(FLOAT_TAG, Json::from("-inf")),
+    }
+}
+
+/// Reads a single key object written by `tagged`, `None` if it is not one.
+fn from_tagged(map: &Map<String, Json>) -> Option<Value> {
+    if map.len() != 1 {
+        return None;
+    }
+    let (tag, content) = map.iter().next()?;
+    match (tag.as_str(), content) {
+        (RAW_TAG, Json::String(v)) => base64_decode(v).map(Value::Raw),
+        (FLOAT_TAG, Json::String(v)) => match v.as_str() {
+            "NaN" => Some(Value::F64(f64::NAN)),
+            "inf" => Some(Value::F64(f64::INFINITY)),
+            "-inf" => Some(Value::F64(f64::NEG_INFINITY)),
+            _ => None,
+        },
+        (MAP_TAG, Json::Array(entries)) => {
+            let mut map = HashMap::with_capacity(entries.len());
+            for entry in entries {
+                match entry {
+                    Json::Array(pair) if pair.len() == 2 => {
+                        map.insert(Value::from(pair[0].clone()), Value::from(pair[1].clone()));
+                    }
+                    _ => return None,
+                }
+            }
+            Some(Value::Map(map))
+        }
+        _ => None,
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(crate) fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    for chunk in text.chunks(4) {
+        let pad = chunk.iter().rev().take_while(|b| **b == b'=').count();
+        if pad > 2 {
+            return None;
+        }
+        let mut n = 0u32;
+        for (i, b) in chunk[..4 - pad].iter().enumerate() {
+            let v = BASE64.iter().position(|c| c == b)? as u32;
+            n |= v << (18 - 6 * i);
+        }
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - pad]);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{base64_decode, base64_encode, json_to_proto, proto_to_json, Json};
+    use crate::{to_vec, Value};
+
+    #[test]
+    fn test_base64() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+            assert_eq!(base64_decode(&base64_encode(data)).unwrap(), data);
+        }
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert!(base64_decode("Zm9").is_none());
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let json = json!({
+            "name": "hc",
+            "big": u64::MAX,
+            "neg": i64::MIN,
+            "list": [1, 2.5, null, true],
+            "raw": {"$raw": "AQID"},
+            "nan": {"$float": "NaN"},
+            "ids": {"$map": [[1, "one"]]},
+        });
+        let bytes = json_to_proto(json.to_string().as_bytes()).unwrap();
+        let back: Json = serde_json::from_slice(&proto_to_json(&bytes).unwrap()).unwrap();
+        assert_eq!(back, json);
+
+        let value = Value::from(json);
+        let Value::Map(ref map) = value else { panic!("object is not a map") };
+        assert_eq!(map[&Value::from("raw".to_string())], Value::Raw(vec![1, 2, 3]));
+        assert_eq!(map[&Value::from("big".to_string())], Value::U64(u64::MAX));
+
+        let value = Value::Arr(vec![Value::U8(3), Value::F32(f32::INFINITY)]);
+        assert_eq!(Json::try_from(value).unwrap(), json!([3, {"$float": "inf"}]));
+
+        // A map keyed by a tag name is escaped, not read back as the tag.
+        let key = Value::from("$raw".to_string());
+        let value = Value::Map([(key, Value::from("AAAA".to_string()))].into());
+        let json = Json::try_from(value.clone()).unwrap();
+        assert_eq!(json, json!({"$map": [["$raw", "AAAA"]]}));
+        assert_eq!(Value::from(json), value);
+        let bytes = to_vec(&value).unwrap();
+        let back: Json = serde_json::from_slice(&proto_to_json(&bytes).unwrap()).unwrap();
+        assert_eq!(Value::from(back), value);
+    }
+}
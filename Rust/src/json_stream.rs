@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use algorithm::buf::BtMut;
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::Serialize;
+use serde_json::{Map, Value as Json};
+
+use crate::encode::{encode_string, encode_varint, PADDED_VARINT_LEN};
+use crate::json::{base64_encode, float_to_json, is_tag, tagged, FLOAT_TAG, MAP_TAG, RAW_TAG};
+use crate::transcode::{check_depth, check_len, container_len, open_container, Token, TokenReader};
+use crate::{ErrorKind, HpError, HpResult, Value, ValueType};
+
+/// Output kept in memory until it grows past this, then written out.
+const FLUSH_LEN: usize = 64 * 1024;
+
+/// Transcodes JSON read from `reader` into a message holding a single value,
+/// written to `writer` as it goes.
+///
+/// No [`Value`] tree is built: only the open containers and a bounded output
+/// buffer are kept, so the memory used does not grow with the input. The
+/// lengths of arrays and maps are reserved when they open and patched when
+/// they close, seeking back in `writer` for those that were already flushed.
+///
+/// A message starts with its string table, so it is fixed before reading:
+/// `strings` such as the known keys go into the table and are written as
+/// `StrIdx`, every other string is written inline. The values follow the
+/// rules of `From<serde_json::Value> for Value`, a tagged object is the only
+/// part read whole. Nesting deeper than 128 containers and arrays of more
+/// than 65535 items are errors.
+pub fn json_to_proto_stream<R, W>(reader: R, writer: W, strings: &[&str]) -> HpResult<()>
+where
+    R: Read,
+    W: Write + Seek,
+{
+    let mut sink = Sink::new(writer, strings)?;
+    let mut de = serde_json::Deserializer::from_reader(BufReader::new(reader));
+    let res = Item { sink: &mut sink, depth: 0 }
+        .deserialize(&mut de)
+        .and_then(|_| de.end());
+    if let Some(err) = sink.error.take() {
+        return Err(err);
+    }
+    res.map_err(|err| {
+        HpError::from((ErrorKind::ParseError, "json parse error", err.to_string()))
+    })?;
+    sink.flush()?;
+    sink.out.flush()?;
+    Ok(())
+}
+
+/// Writes a message holding a single value to `writer` as JSON text, the
+/// reverse of [`json_to_proto_stream`], without building a [`Value`] tree.
+///
+/// The output is the same document [`crate::proto_to_json`] produces, except
+/// that object keys keep the order of the message. Each map is scanned once
+/// ahead to know whether all its keys are strings. Nesting deeper than 128
+/// containers is an error.
+pub fn proto_to_json_stream<W: Write>(buf: &[u8], writer: W) -> HpResult<()> {
+    let mut reader = TokenReader::new(buf)?;
+    let mut out = BufWriter::new(writer);
+    write_json(&mut reader, &mut out)?;
+    reader.finish()?;
+    out.flush()?;
+    Ok(())
+}
+
+fn write_value<W: Write, T: Serialize + ?Sized>(out: &mut W, value: &T) -> HpResult<()> {
+    serde_json::to_writer(out, value)
+        .map_err(|err| HpError::from((ErrorKind::ParseError, "json write error", err.to_string())))
+}
+
+fn write_json<W: Write>(reader: &mut TokenReader, out: &mut W) -> HpResult<()> {
+    match reader.next()? {
+        Token::Nil => out.write_all(b"null")?,
+        Token::Bool(v) => write!(out, "{}", v)?,
+        Token::U64(v) => write!(out, "{}", v)?,
+        Token::F32(v) => write_value(out, &float_to_json(v as f64))?,
+        Token::F64(v) => write_value(out, &float_to_json(v))?,
+        Token::Str(v) => write_value(out, v)?,
+        Token::Raw(v) => write_value(out, &tagged(RAW_TAG, Json::String(base64_encode(v))))?,
+        Token::Arr(len) => {
+            out.write_all(b"[")?;
+            for i in 0..len {
+                if i > 0 {
+                    out.write_all(b",")?;
+                }
+                write_json(reader, out)?;
+            }
+            out.write_all(b"]")?;
+        }
+        Token::Map(len) => {
+            let mark = reader.mark();
+            let mut str_keys = true;
+            for _ in 0..len {
+                if !matches!(reader.next()?, Token::Str(k) if len > 1 || !is_tag(k)) {
+                    str_keys = false;
+                    break;
+                }
+                reader.skip()?;
+            }
+            reader.reset(mark);
+            if str_keys {
+                out.write_all(b"{")?;
+            } else {
+                write!(out, "{{\"{}\":[", MAP_TAG)?;
+            }
+            for i in 0..len {
+                if i > 0 {
+                    out.write_all(b",")?;
+                }
+                if str_keys {
+                    write_json(reader, out)?;
+                    out.write_all(b":")?;
+                    write_json(reader, out)?;
+                } else {
+                    out.write_all(b"[")?;
+                    write_json(reader, out)?;
+                    out.write_all(b",")?;
+                    write_json(reader, out)?;
+                    out.write_all(b"]")?;
+                }
+            }
+            out.write_all(if str_keys { b"}" } else { b"]}" })?;
+        }
+        token => write!(out, "{}", token.as_i64().unwrap_or_default())?,
+    }
+    Ok(())
+}
+
+/// The message being written by [`json_to_proto_stream`].
+struct Sink<'s, W> {
+    out: W,
+    /// Where the message starts in `out`.
+    base: u64,
+    /// Bytes already written to `out`.
+    flushed: u64,
+    pending: Vec<u8>,
+    table: HashMap<&'s str, u16>,
+    /// The error behind a failed write, the deserializer only carries a message.
+    error: Option<HpError>,
+}
+
+impl<'s, W: Write + Seek> Sink<'s, W> {
+    fn new(mut out: W, strings: &[&'s str]) -> HpResult<Self> {
+        if strings.len() > u16::MAX as usize {
+            return Err(HpError::from((ErrorKind::BufferOverMaxError, "too many strings")));
+        }
+        let base = out.stream_position()?;
+        let mut pending = Vec::with_capacity(FLUSH_LEN + 1024);
+        let mut table = HashMap::with_capacity(strings.len());
+        encode_varint(&mut pending, &Value::U16(strings.len() as u16))?;
+        for (idx, val) in strings.iter().enumerate() {
+            check_len(val.len(), "string too long")?;
+            encode_string(&mut pending, val)?;
+            table.entry(*val).or_insert(idx as u16);
+        }
+        Ok(Sink {
+            out,
+            base,
+            flushed: 0,
+            pending,
+            table,
+            error: None,
+        })
+    }
+
+    /// Runs a write for the deserializer, keeping its error aside.
+    fn with<E: de::Error>(&mut self, f: impl FnOnce(&mut Self) -> HpResult<()>) -> Result<(), E> {
+        let res = f(self).and_then(|_| {
+            if self.pending.len() >= FLUSH_LEN {
+                self.flush()?;
+            }
+            Ok(())
+        });
+        res.map_err(|err| {
+            let msg = err.to_string();
+            self.error = Some(err);
+            E::custom(msg)
+        })
+    }
+
+    fn flush(&mut self) -> HpResult<()> {
+        self.out.write_all(&self.pending)?;
+        self.flushed += self.pending.len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn str(&mut self, val: &str) -> HpResult<()> {
+        match self.table.get(val) {
+            Some(idx) => {
+                self.pending.push(ValueType::StrIdx as u8);
+                encode_varint(&mut self.pending, &Value::U16(*idx))?;
+            }
+            None => {
+                check_len(val.len(), "string too long")?;
+                self.pending.push(ValueType::Str as u8);
+                encode_string(&mut self.pending, val)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, value: &Value) -> HpResult<()> {
+        let pending = &mut self.pending;
+        match value {
+            Value::Nil => pending.push(ValueType::Nil as u8),
+            Value::Bool(v) => pending.extend_from_slice(&[ValueType::Bool as u8, *v as u8]),
+            Value::U8(v) => pending.extend_from_slice(&[ValueType::U8 as u8, *v]),
+            Value::I8(v) => pending.extend_from_slice(&[ValueType::I8 as u8, *v as u8]),
+            Value::U64(v) if *v > i64::MAX as u64 => {
+                pending.push(ValueType::U64 as u8);
+                pending.put_u64(*v);
+            }
+            Value::U16(_)
+            | Value::I16(_)
+            | Value::U32(_)
+            | Value::I32(_)
+            | Value::U64(_)
+            | Value::I64(_)
+            | Value::Varint(_) => {
+                pending.push(ValueType::Varint as u8);
+                encode_varint(pending, value)?;
+            }
+            Value::F32(v) => {
+                pending.push(ValueType::F32 as u8);
+                pending.put_f32(*v);
+            }
+            Value::F64(v) => {
+                pending.push(ValueType::F64 as u8);
+                pending.put_f64(*v);
+            }
+            Value::Str(v) => self.str(v)?,
+            Value::Raw(v) => {
+                let len = check_len(v.len(), "raw too long")?;
+                pending.push(ValueType::Raw as u8);
+                encode_varint(pending, &Value::U16(len))?;
+                pending.extend_from_slice(v);
+            }
+            Value::Arr(v) => {
+                let len = check_len(v.len(), "array too long")?;
+                pending.push(ValueType::Arr as u8);
+                encode_varint(pending, &Value::U16(len))?;
+                for v in v {
+                    self.value(v)?;
+                }
+            }
+            Value::Map(v) => {
+                pending.push(ValueType::Map as u8);
+                encode_varint(pending, &Value::U32(v.len() as u32 * 2))?;
+                for (k, v) in v {
+                    self.value(k)?;
+                    self.value(v)?;
+                }
+            }
+            Value::Encoded(v) => self.value(&v.to_value()?)?,
+        }
+        Ok(())
+    }
+
+    /// Opens an `Arr` or `Map` of unknown length, returning its position.
+    fn begin(&mut self, pattern: ValueType) -> u64 {
+        let pos = self.flushed + self.pending.len() as u64;
+        open_container(&mut self.pending, pattern);
+        pos
+    }
+
+    /// Patches the length of the container opened at `pos`, `len` counts
+    /// items for an `Arr` and pairs for a `Map`.
+    fn end(&mut self, pos: u64, pattern: ValueType, len: usize) -> HpResult<()> {
+        let data = container_len(pattern, len)?;
+        let at = pos + 1;
+        // The head may straddle what was already written out.
+        let written = self.flushed.saturating_sub(at).min(PADDED_VARINT_LEN as u64) as usize;
+        if written > 0 {
+            self.out.seek(SeekFrom::Start(self.base + at))?;
+            self.out.write_all(&data[..written])?;
+            self.out.seek(SeekFrom::Start(self.base + self.flushed))?;
+        }
+        if written < PADDED_VARINT_LEN {
+            let start = (at + written as u64 - self.flushed) as usize;
+            self.pending[start..start + PADDED_VARINT_LEN - written].copy_from_slice(&data[written..]);
+        }
+        Ok(())
+    }
+}
+
+/// Transcodes the next JSON value into the sink.
+struct Item<'a, 's, W> {
+    sink: &'a mut Sink<'s, W>,
+    /// How many containers the value is nested in.
+    depth: usize,
+}
+
+impl<'de, W: Write + Seek> DeserializeSeed<'de> for Item<'_, '_, W> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        let depth = self.depth;
+        self.sink.with(|_| check_depth(depth))?;
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, W: Write + Seek> Visitor<'de> for Item<'_, '_, W> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any JSON value")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        self.sink.with(|s| s.value(&Value::Nil))
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<(), E> {
+        self.sink.with(|s| s.value(&Value::Bool(v)))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<(), E> {
+        self.sink.with(|s| s.value(&Value::Varint(v)))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<(), E> {
+        self.sink.with(|s| s.value(&Value::U64(v)))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<(), E> {
+        self.sink.with(|s| s.value(&Value::F64(v)))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<(), E> {
+        self.sink.with(|s| s.str(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let (sink, depth) = (self.sink, self.depth + 1);
+        let pos = sink.begin(ValueType::Arr);
+        let mut len = 0;
+        while seq.next_element_seed(Item { sink: &mut *sink, depth })?.is_some() {
+            len += 1;
+            // Fail as soon as it overflows rather than after reading the rest.
+            sink.with(|_| check_len(len, "array too long").map(drop))?;
+        }
+        sink.with(|s| s.end(pos, ValueType::Arr, len))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let (sink, depth) = (self.sink, self.depth + 1);
+        let Some(first) = map.next_key::<String>()? else {
+            return sink.with(|s| s.value(&Value::Map(HashMap::new())));
+        };
+        let mut tagged = None;
+        if [RAW_TAG, FLOAT_TAG, MAP_TAG].contains(&first.as_str()) {
+            // A possible tagged object, read whole until it proves to have another key.
+            let content: Json = map.next_value()?;
+            let Some(second) = map.next_key::<String>()? else {
+                let mut object = Map::with_capacity(1);
+                object.insert(first, content);
+                return sink.with(|s| s.value(&Value::from(Json::Object(object))));
+            };
+            tagged = Some((content, second));
+        }
+        let pos = sink.begin(ValueType::Map);
+        let mut len = 1;
+        sink.with(|s| s.str(&first))?;
+        if let Some((content, second)) = tagged {
+            sink.with(|s| {
+                s.value(&Value::from(content))?;
+                s.str(&second)
+            })?;
+            len += 1;
+        }
+        map.next_value_seed(Item { sink: &mut *sink, depth })?;
+        while map.next_key_seed(Item { sink: &mut *sink, depth })?.is_some() {
+            map.next_value_seed(Item { sink: &mut *sink, depth })?;
+            len += 1;
+        }
+        sink.with(|s| s.end(pos, ValueType::Map, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use serde_json::json;
+
+    use super::{json_to_proto_stream, proto_to_json_stream, Json};
+    use crate::{from_slice, json_to_proto, to_vec, Value};
+
+    #[test]
+    fn test_json_stream() {
+        let json = json!({
+            "name": "hc",
+            "big": u64::MAX,
+            "list": [1, 2.5, null, true, [], {}],
+            "raw": {"$raw": "AQID"},
+            "not_raw": {"$raw": 1, "more": "AQID"},
+            "ids": {"$map": [[1, "one"]]},
+            "escaped": {"$map": [["$raw", "AAAA"]]},
+            "long": (0..6000).map(|i| json!({"id": i, "name": "item"})).collect::<Vec<_>>(),
+        });
+        let text = json.to_string();
+        let mut out = Cursor::new(vec![0xAA]);
+        out.set_position(1);
+        json_to_proto_stream(text.as_bytes(), &mut out, &["id", "name"]).unwrap();
+        let bytes = &out.get_ref()[1..];
+        let value: Value = from_slice(bytes).unwrap();
+        let tree: Value = from_slice(&json_to_proto(text.as_bytes()).unwrap()).unwrap();
+        assert_eq!(value, tree);
+
+        let mut back = Vec::new();
+        proto_to_json_stream(bytes, &mut back).unwrap();
+        assert_eq!(serde_json::from_slice::<Json>(&back).unwrap(), json);
+
+        let mut out = Cursor::new(Vec::new());
+        assert!(json_to_proto_stream(&b"[1, 2"[..], &mut out, &[]).is_err());
+    }
+
+    #[test]
+    fn test_json_stream_limits() {
+        let nested = |depth: usize| format!("{}null{}", "[".repeat(depth), "]".repeat(depth));
+        let mut out = Cursor::new(Vec::new());
+        json_to_proto_stream(nested(100).as_bytes(), &mut out, &[]).unwrap();
+        let mut back = Vec::new();
+        proto_to_json_stream(out.get_ref(), &mut back).unwrap();
+        assert_eq!(back, nested(100).into_bytes());
+        let mut out = Cursor::new(Vec::new());
+        assert!(json_to_proto_stream(nested(200).as_bytes(), &mut out, &[]).is_err());
+
+        let mut value = Value::Nil;
+        for _ in 0..129 {
+            value = Value::Arr(vec![value]);
+        }
+        assert!(proto_to_json_stream(&to_vec(&value).unwrap(), &mut Vec::new()).is_err());
+
+        let long = |len: usize| serde_json::to_string(&vec![0; len]).unwrap();
+        let mut out = Cursor::new(Vec::new());
+        json_to_proto_stream(long(65535).as_bytes(), &mut out, &[]).unwrap();
+        let value: Value = from_slice(out.get_ref()).unwrap();
+        assert_eq!(value, Value::Arr(vec![Value::Varint(0); 65535]));
+        let mut out = Cursor::new(Vec::new());
+        let err = json_to_proto_stream(long(65536).as_bytes(), &mut out, &[]).unwrap_err();
+        assert!(err.to_string().contains("array too long"));
+    }
+}
@@ -2,25 +2,96 @@
 mod error;
 mod ser;
 mod values;
+mod value_ref;
+mod raw_value;
+mod value_ser;
+mod value_de;
+mod text;
+mod pretty;
+mod ord;
+mod access;
+mod query;
+mod patch;
+mod baseline;
+mod digest;
+mod visit;
+mod convert;
 mod buffer;
 pub mod encode;
 pub mod decode;
 mod de;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "json")]
+mod json_stream;
+mod transcode;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+#[cfg(feature = "cbor")]
+pub mod cbor;
 
 
-pub use buffer::Buffer;
+pub use buffer::{Buffer, BufferRef};
 pub use values::*;
+pub use value_ref::ValueRef;
+pub use raw_value::RawValue;
+pub use value_ser::{to_value, ValueSerializer};
+pub use value_de::{from_value, from_value_with_struct_mode};
+pub use pretty::{Pretty, PrettyConfig};
+pub use access::ValueIndex;
+pub use query::Query;
+pub use patch::{apply, diff, Patch, PatchOp};
+pub use baseline::{BaselineStore, DeltaDecoder, DeltaEncoder};
+pub use digest::{digest_proto, digest_slice, Digest};
+pub use convert::{FromValue, ToValue};
+pub use visit::{
+    transform_encoded, visit_encoded, walk_arr, walk_arr_mut, walk_fold_arr, walk_fold_map, walk_fold_value, walk_map,
+    walk_map_mut, walk_value, walk_value_mut, Edit, Flow, Fold, Path, Segment, TransformEncoded, Visit, VisitEncoded,
+    VisitMut,
+};
+#[cfg(feature = "json")]
+pub use json::{json_to_proto, proto_to_json};
+#[cfg(feature = "json")]
+pub use json_stream::{json_to_proto_stream, proto_to_json_stream};
+#[cfg(any(feature = "msgpack", feature = "cbor", feature = "json"))]
+pub use transcode::{Lossy, Transcoded};
+#[cfg(feature = "msgpack")]
+pub use msgpack::{msgpack_to_proto, proto_to_msgpack};
+#[cfg(feature = "cbor")]
+pub use cbor::{cbor_to_proto, proto_to_cbor};
 pub use error::{HpError, ErrorKind, HpResult, make_extension_error};
-pub use ser::to_buffer;
-pub use de::from_buffer;
+pub use ser::{to_buffer, to_proto, to_vec, to_writer, Serializer, StructMode, VariantMode};
+pub use de::{from_buffer, from_proto, from_reader, from_slice, Deserializer};
 
 pub use encode::*;
 pub use decode::*;
 
 #[cfg(test)]
 mod tests {
-    use crate::{decode_varint, encode_varint, Buffer, Value};
+    use std::borrow::Cow;
+    use std::collections::HashMap;
 
+    use algorithm::buf::{Bt, BtMut};
+    use serde::{Deserialize, Serialize};
+
+    use crate::{
+        decode_field, decode_field_ref, decode_msg, decode_proto, decode_proto_ref,
+        decode_raw_value, decode_str_table_ref, decode_varint, encode_field, encode_proto,
+        encode_varint, from_proto, from_reader, from_slice, from_value,
+        from_value_with_struct_mode, to_buffer, to_proto, to_value, to_vec, to_writer, Buffer,
+        BufferRef, Deserializer, Encoder, ErrorKind, RawValue, Serializer, StructMode, Value,
+        ValueRef, VariantMode,
+    };
+
+
+    /// Writes structs as maps, for the shapes only a named struct can carry.
+    fn to_vec_named<T: Serialize>(value: &T) -> Vec<u8> {
+        let mut serializer = Serializer::new(Buffer::new()).with_struct_mode(StructMode::Named);
+        value.serialize(&mut serializer).unwrap();
+        let mut out = Vec::new();
+        serializer.export_to(&mut out).unwrap();
+        out
+    }
 
     #[test]
     fn test_varint() {
@@ -29,5 +100,553 @@ mod tests {
         let ret = decode_varint(&mut buffer).unwrap();
         assert_eq!(ret, Value::U16(3));
     }
-    
-}
\ No newline at end of file
+
+    #[test]
+    fn test_encoder_reuse() {
+        let mut map = HashMap::new();
+        map.insert(Value::from("name".to_string()), Value::from("hcproto".to_string()));
+        map.insert(Value::from("hcproto".to_string()), Value::U16(1));
+        let first = vec![Value::Map(map), Value::from("name".to_string())];
+        let second = vec![Value::from("other".to_string()), Value::from(vec![1u8, 2])];
+
+        let mut encoder = Encoder::new();
+        for infos in [&first, &second, &first] {
+            let mut buffer = Buffer::new();
+            encoder.encode_proto(&mut buffer, "cmd_test", infos).unwrap();
+            let (name, val) = decode_proto(&mut buffer).unwrap();
+            assert_eq!(name, "cmd_test");
+            assert_eq!(&val, infos);
+        }
+    }
+
+    #[test]
+    fn test_decode_ref() {
+        let mut map = HashMap::new();
+        map.insert(Value::from("name".to_string()), Value::from("hcproto".to_string()));
+        map.insert(Value::from("raw".to_string()), Value::from(vec![1u8, 2, 3]));
+        let infos = vec![Value::Map(map), Value::from("name".to_string()), Value::Varint(-7)];
+        let mut buffer = Buffer::new();
+        Encoder::new().encode_proto(&mut buffer, "cmd_test", &infos).unwrap();
+
+        let data = buffer.chunk();
+        let mut reader = BufferRef::new(data);
+        let (name, val) = decode_proto_ref(&mut reader).unwrap();
+        assert_eq!(reader.remaining(), 0);
+        assert_eq!(name, "cmd_test");
+        match val[1] {
+            ValueRef::Str(v) => assert!(data.as_ptr_range().contains(&v.as_ptr())),
+            _ => unreachable!(),
+        }
+        let owned: Vec<Value> = val.into_iter().map(ValueRef::into_owned).collect();
+        assert_eq!(owned, infos);
+        assert_eq!(decode_proto(&mut buffer).unwrap().1, infos);
+    }
+
+    #[test]
+    fn test_from_slice_borrowed() {
+        #[derive(Serialize)]
+        struct Owned {
+            name: String,
+            alias: String,
+            tags: Vec<String>,
+            hp: Option<u16>,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Borrowed<'a> {
+            name: &'a str,
+            #[serde(borrow)]
+            alias: Cow<'a, str>,
+            tags: Vec<&'a str>,
+            hp: Option<u16>,
+        }
+
+        let owned = Owned {
+            name: "hcproto".to_string(),
+            alias: "hc".to_string(),
+            tags: vec!["a".to_string(), "hcproto".to_string()],
+            hp: None,
+        };
+        let buffer = to_buffer(&owned).unwrap();
+        let data = buffer.chunk();
+        let val: Borrowed = from_slice(data).unwrap();
+        assert_eq!(
+            val,
+            Borrowed {
+                name: "hcproto",
+                alias: Cow::Borrowed("hc"),
+                tags: vec!["a", "hcproto"],
+                hp: None,
+            }
+        );
+        assert!(matches!(val.alias, Cow::Borrowed(_)));
+        assert!(data.as_ptr_range().contains(&val.name.as_ptr()));
+    }
+
+    #[test]
+    fn test_io_entry_points() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            val: u32,
+            seq: Vec<String>,
+        }
+
+        let test = Test {
+            val: 1,
+            seq: vec!["a".to_string(), "b".to_string()],
+        };
+        let bytes = to_vec(&test).unwrap();
+        assert_eq!(bytes, to_buffer(&test).unwrap().chunk());
+        let mut written = Vec::new();
+        to_writer(&mut written, &test).unwrap();
+        assert_eq!(bytes, written);
+        assert_eq!(from_reader::<_, Test>(&bytes[..]).unwrap(), test);
+
+        let mut serializer = Serializer::new(Buffer::new());
+        for i in 0..3 {
+            let test = Test { val: i, seq: vec![i.to_string()] };
+            test.serialize(&mut serializer).unwrap();
+            let mut out = Vec::new();
+            serializer.export_to(&mut out).unwrap();
+            assert_eq!(from_slice::<Test>(&out).unwrap(), test);
+        }
+    }
+
+    #[test]
+    fn test_add_str() {
+        let names: Vec<String> = ["name", "hp"].iter().map(|v| v.to_string()).collect();
+        let mut buffer = Buffer::new();
+        assert_eq!(buffer.add_str(names[0].clone()), 0);
+        assert_eq!(buffer.add_str("hp"), 1);
+        assert_eq!(buffer.add_str(&names[0]), 0);
+        let str_map: &HashMap<String, u16> = &buffer.str_map;
+        assert_eq!(str_map.len(), 2);
+    }
+
+    #[test]
+    fn test_hash() {
+        use std::hash::{BuildHasher, RandomState};
+
+        let state = RandomState::new();
+        let hash = |v: &Value| state.hash_one(v);
+        assert_eq!(hash(&Value::Varint(5)), hash(&Value::U8(5)));
+        assert_eq!(hash(&Value::Varint(-5)), hash(&Value::I32(-5)));
+        assert_ne!(hash(&Value::Str("a".to_string())), hash(&Value::Str("b".to_string())));
+        assert_eq!(hash(&Value::F64(0.0)), hash(&Value::F64(-0.0)));
+        assert_eq!(hash(&Value::F32(f32::NAN)), hash(&Value::F32(-f32::NAN)));
+
+        let map: HashMap<Value, Value> = (0..100)
+            .map(|i| (Value::Str(i.to_string()), Value::Varint(i)))
+            .collect();
+        let reversed: HashMap<Value, Value> = (0..100)
+            .rev()
+            .map(|i| (Value::Str(i.to_string()), Value::U8(i as u8)))
+            .collect();
+        assert_eq!(hash(&Value::Map(map.clone())), hash(&Value::Map(reversed)));
+        assert_eq!(map[&Value::Str("42".to_string())], Value::U8(42));
+
+        let mut buffer = Buffer::new();
+        encode_proto(&mut buffer, "cmd", vec![Value::Map(map.clone())]).unwrap();
+        let mut reader = BufferRef::new(buffer.chunk());
+        let (_, fields) = decode_proto_ref(&mut reader).unwrap();
+        let ValueRef::Map(decoded) = &fields[0] else { panic!() };
+        assert_eq!(decoded[&ValueRef::Str("42")], ValueRef::U8(42));
+        assert_eq!(hash(&Value::Map(map)), hash(&fields[0].clone().into_owned()));
+        assert_eq!(state.hash_one(ValueRef::Varint(7)), state.hash_one(ValueRef::U16(7)));
+
+        // Integers of any width are equal by value, as for `Value`.
+        assert_eq!(ValueRef::U8(5), ValueRef::U16(5));
+        assert_eq!(ValueRef::I64(-1), ValueRef::Varint(-1));
+        assert_eq!(ValueRef::U64(300), ValueRef::I32(300));
+        assert_ne!(ValueRef::U64(u64::MAX), ValueRef::Varint(-1));
+        assert_ne!(ValueRef::U8(5), ValueRef::F32(5.0));
+    }
+
+    #[test]
+    fn test_try_from() {
+        assert_eq!(u8::try_from(Value::Varint(200)).unwrap(), 200);
+        assert_eq!(u16::try_from(Value::U64(300)).unwrap(), 300);
+        assert_eq!(i64::try_from(Value::U8(7)).unwrap(), 7);
+        assert!(bool::try_from(Value::U8(1)).unwrap());
+        assert_eq!(f64::try_from(Value::Varint(3)).unwrap(), 3.0);
+        assert_eq!(f32::try_from(Value::F64(0.5)).unwrap(), 0.5);
+        assert_eq!(String::try_from(Value::Str("hc".to_string())).unwrap(), "hc");
+
+        let err = u8::try_from(Value::Varint(300)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TypeNotMatchError);
+        assert_eq!(err.to_string(), "type not match: expected u8, found varint out of range: 300v");
+        let err = u32::try_from(Value::I8(-1)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TypeNotMatchError);
+        let err = String::try_from(Value::Raw(vec![1])).unwrap_err();
+        assert_eq!(err.to_string(), "type not match: expected str, found raw");
+        assert!(bool::try_from(Value::Varint(2)).is_err());
+        assert!(f32::try_from(Value::Varint(i64::MAX)).is_err());
+        assert!(f32::try_from(Value::F64(1e300)).is_err());
+
+        let raw = RawValue::from_value(&Value::U16(9)).unwrap();
+        assert_eq!(u8::try_from(Value::Encoded(raw)).unwrap(), 9);
+        assert_eq!(<&str>::try_from(ValueRef::Str("hc")).unwrap(), "hc");
+        assert!(<&[u8]>::try_from(ValueRef::Str("hc")).is_err());
+        assert_eq!(i16::try_from(ValueRef::Varint(-5)).unwrap(), -5);
+
+        // An array length over u16 is a decode error, not a truncated length.
+        let mut buffer = Buffer::new();
+        buffer.put_u8(16);
+        encode_varint(&mut buffer, &Value::Varint(70000)).unwrap();
+        assert_eq!(decode_field(&mut buffer).unwrap_err().kind(), ErrorKind::TypeNotMatchError);
+    }
+
+    #[test]
+    fn test_size_hint() {
+        struct Hint(Option<usize>);
+
+        impl<'de> Deserialize<'de> for Hint {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct HintVisitor;
+
+                impl<'de> serde::de::Visitor<'de> for HintVisitor {
+                    type Value = Hint;
+
+                    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                        f.write_str("a container")
+                    }
+
+                    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, seq: A) -> Result<Hint, A::Error> {
+                        Ok(Hint(seq.size_hint()))
+                    }
+
+                    fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Hint, A::Error> {
+                        Ok(Hint(map.size_hint()))
+                    }
+                }
+
+                deserializer.deserialize_any(HintVisitor)
+            }
+        }
+
+        // The hint never claims more elements than the rest of the input holds.
+        for (kind, len, hint) in [(16, 65535, 2), (17, u32::MAX as i64, 1), (16, 2, 2)] {
+            let mut buffer = Buffer::new();
+            buffer.put_slice(&[0, kind]);
+            encode_varint(&mut buffer, &Value::Varint(len)).unwrap();
+            buffer.put_slice(&[0, 0]);
+            let mut de = Deserializer::new(buffer.chunk()).unwrap();
+            assert_eq!(Hint::deserialize(&mut de).unwrap().0, Some(hint));
+        }
+    }
+
+    #[test]
+    fn test_unknown_length() {
+        struct Odd(Vec<u32>);
+
+        impl Serialize for Odd {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_seq(self.0.iter().filter(|v| *v % 2 == 1))
+            }
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Inner {
+            hp: u16,
+            name: String,
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Outer {
+            id: u32,
+            #[serde(flatten)]
+            inner: Inner,
+        }
+
+        let bytes = to_vec(&Odd((0..300).collect())).unwrap();
+        let odd: Vec<u32> = from_slice(&bytes).unwrap();
+        assert_eq!(odd, (0..300).filter(|v| v % 2 == 1).collect::<Vec<_>>());
+
+        let mut buffer = Buffer::new();
+        buffer.put_slice(&bytes);
+        let val = decode_msg(&mut buffer).unwrap();
+        assert_eq!(val.len(), 150);
+
+        let outer = Outer {
+            id: 7,
+            inner: Inner { hp: 100, name: "hc".to_string() },
+        };
+        let bytes = to_vec(&outer).unwrap();
+        assert_eq!(from_slice::<Outer>(&bytes).unwrap(), outer);
+    }
+
+    #[test]
+    fn test_enum() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        enum External {
+            Unit,
+            Newtype(u32),
+            Tuple(u8, String),
+            Struct { hp: u16, name: String },
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        #[serde(tag = "type")]
+        enum Internal {
+            Unit,
+            Struct { hp: u16, name: String },
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        #[serde(tag = "t", content = "c")]
+        enum Adjacent {
+            Unit,
+            Newtype(String),
+            Tuple(u8, u8),
+        }
+
+        let external = vec![
+            External::Unit,
+            External::Newtype(3),
+            External::Tuple(1, "a".to_string()),
+            External::Struct { hp: 100, name: "hc".to_string() },
+        ];
+        for mode in [VariantMode::Name, VariantMode::Index] {
+            let mut serializer = Serializer::new(Buffer::new()).with_variant_mode(mode);
+            external.serialize(&mut serializer).unwrap();
+            let mut out = Vec::new();
+            serializer.export_to(&mut out).unwrap();
+            assert_eq!(from_slice::<Vec<External>>(&out).unwrap(), external);
+        }
+
+        // An internally tagged enum is read through `deserialize_any`, which
+        // hands a legacy struct over as a map so the tag is found by name.
+        let internal = vec![Internal::Unit, Internal::Struct { hp: 1, name: "hc".to_string() }];
+        let bytes = to_vec(&internal).unwrap();
+        assert_eq!(from_slice::<Vec<Internal>>(&bytes).unwrap(), internal);
+        assert_eq!(from_slice::<Vec<Internal>>(&to_vec_named(&internal)).unwrap(), internal);
+
+        let adjacent = vec![Adjacent::Unit, Adjacent::Newtype("hc".to_string()), Adjacent::Tuple(1, 2)];
+        let bytes = to_vec(&adjacent).unwrap();
+        assert_eq!(from_slice::<Vec<Adjacent>>(&bytes).unwrap(), adjacent);
+    }
+
+    #[test]
+    fn test_struct_mode() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Player {
+            name: String,
+            hp: u16,
+            tags: Vec<String>,
+            alive: bool,
+        }
+
+        let player = Player {
+            name: "hp".to_string(),
+            hp: 100,
+            tags: vec!["a".to_string()],
+            alive: true,
+        };
+        let mut sizes = vec![];
+        for mode in [StructMode::Named, StructMode::Positional, StructMode::Legacy] {
+            let mut serializer = Serializer::new(Buffer::new()).with_struct_mode(mode);
+            player.serialize(&mut serializer).unwrap();
+            let mut out = Vec::new();
+            serializer.export_to(&mut out).unwrap();
+            if mode == StructMode::Legacy {
+                // The default, so `to_vec` writes what it always has.
+                assert_eq!(out, to_vec(&player).unwrap());
+            }
+            let mut de = Deserializer::new(&out).unwrap().with_struct_mode(mode);
+            assert_eq!(Player::deserialize(&mut de).unwrap(), player);
+            sizes.push(out.len());
+
+            let mut reader = BufferRef::new(&out);
+            decode_str_table_ref(&mut reader).unwrap();
+            match (mode, decode_field_ref(&mut reader).unwrap()) {
+                (StructMode::Named, ValueRef::Map(map)) => assert_eq!(map.len(), 4),
+                (StructMode::Positional, ValueRef::Arr(arr)) => assert_eq!(arr.len(), 4),
+                (StructMode::Legacy, ValueRef::Arr(arr)) => assert_eq!(arr.len(), 8),
+                (mode, val) => panic!("{:?} wrote {:?}", mode, val),
+            }
+        }
+        assert!(sizes[1] < sizes[0]);
+
+        #[derive(Serialize)]
+        struct Skip {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            hp: Option<u16>,
+        }
+        let mut serializer = Serializer::new(Buffer::new()).with_struct_mode(StructMode::Positional);
+        assert!(Skip { hp: None }.serialize(&mut serializer).is_err());
+    }
+
+    #[test]
+    fn test_typed_proto() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Pos {
+            x: f32,
+            y: f32,
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Move {
+            id: u32,
+            to: Pos,
+            speed: f64,
+        }
+
+        let args = Move { id: 7, to: Pos { x: 1.5, y: -2.0 }, speed: 0.25 };
+        let mut buffer = to_proto("cmd_move", &args).unwrap();
+        let (name, (id, to, speed)): (String, (u32, Pos, f64)) =
+            from_proto(buffer.chunk()).unwrap();
+        assert_eq!((name.as_str(), id, &to, speed), ("cmd_move", 7, &args.to, 0.25));
+
+        // A dynamic client sees the arguments as an array, with the nested
+        // struct in the default layout, its names and values alternating.
+        let (name, infos) = decode_proto(&mut buffer).unwrap();
+        assert_eq!(name, "cmd_move");
+        assert_eq!(infos[0], Value::Varint(7));
+        let Value::Arr(ref to) = infos[1] else { panic!("nested struct is not legacy") };
+        assert_eq!(to[..2], [Value::Str("x".to_string()), Value::F32(1.5)]);
+        assert_eq!(infos[2], Value::F64(0.25));
+
+        let mut buffer = Buffer::new();
+        encode_proto(&mut buffer, "cmd_move", infos).unwrap();
+        assert_eq!(from_proto::<Move>(buffer.chunk()).unwrap(), ("cmd_move".to_string(), args));
+
+        assert!(to_proto("cmd_move", &7u32).is_err());
+        assert!(from_proto::<Move>(to_buffer(&Pos { x: 0.0, y: 0.0 }).unwrap().chunk()).is_err());
+    }
+
+    #[test]
+    fn test_raw_value() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Envelope {
+            route: String,
+            payload: RawValue,
+        }
+
+        let mut payload = HashMap::new();
+        payload.insert(Value::from("route".to_string()), Value::from("inner".to_string()));
+        payload.insert(Value::from("hp".to_string()), Value::U16(300));
+        let payload = Value::Map(payload);
+
+        let raw = RawValue::from_value(&payload).unwrap();
+        assert_eq!(raw.get_type(), crate::ValueType::Map);
+        assert_eq!(raw.to_value().unwrap(), payload);
+        assert_eq!(RawValue::from_vec(raw.as_bytes().to_vec()).unwrap(), raw);
+        assert!(RawValue::from_vec(vec![0, 14, 0]).is_err());
+        let mut deep = vec![0];
+        for _ in 0..200_000 {
+            deep.extend_from_slice(&[16, 2]);
+        }
+        deep.push(0);
+        assert!(RawValue::from_vec(deep).is_err());
+
+        // The gateway reads the route and forwards the payload untouched, its
+        // strings are remapped into the string table of the new message.
+        let out = to_vec(&Envelope { route: "game".to_string(), payload: raw.clone() }).unwrap();
+        let envelope: Envelope = from_slice(&out).unwrap();
+        assert_eq!(envelope.payload.to_value().unwrap(), payload);
+        let forwarded = to_vec(&envelope).unwrap();
+        assert_eq!(from_slice::<Envelope>(&forwarded).unwrap(), envelope);
+        let Value::Arr(decoded) = from_slice::<Value>(&forwarded).unwrap() else { panic!() };
+        assert_eq!(decoded[2..], [Value::from("payload".to_string()), payload.clone()]);
+
+        // Other formats carry it as the bytes of the message.
+        let json = serde_json::to_string(&raw).unwrap();
+        assert_eq!(serde_json::from_str::<RawValue>(&json).unwrap(), raw);
+        assert_eq!(from_value::<RawValue>(to_value(&raw).unwrap()).unwrap().to_value().unwrap(), payload);
+
+        // As a `Value` variant it is spliced by every encoder.
+        let infos = vec![Value::from("game".to_string()), Value::Encoded(raw)];
+        let mut buffer = Buffer::new();
+        Encoder::new().encode_msg(&mut buffer, &infos).unwrap();
+        assert_eq!(decode_msg(&mut buffer).unwrap()[1], payload);
+
+        let mut buffer = Buffer::new();
+        encode_field(&mut buffer, &Value::from("route".to_string())).unwrap();
+        encode_field(&mut buffer, &payload).unwrap();
+        encode_field(&mut buffer, &Value::U8(1)).unwrap();
+        assert_eq!(decode_field(&mut buffer).unwrap(), Value::from("route".to_string()));
+        let captured = decode_raw_value(&mut buffer).unwrap();
+        assert_eq!(captured.to_value().unwrap(), payload);
+        assert_eq!(decode_field(&mut buffer).unwrap(), Value::U8(1));
+    }
+
+    #[test]
+    fn test_value_serde() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        enum Action {
+            Idle,
+            Attack { target: u32 },
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Player {
+            name: String,
+            hp: Option<u16>,
+            actions: Vec<Action>,
+            extra: Value,
+        }
+
+        let player = Player {
+            name: "hc".to_string(),
+            hp: Some(100),
+            actions: vec![Action::Idle, Action::Attack { target: 7 }],
+            extra: Value::Arr(vec![Value::Nil, Value::F64(0.5), Value::Raw(vec![1, 2])]),
+        };
+
+        let value = to_value(&player).unwrap();
+        let Value::Map(ref map) = value else { panic!("struct is not a map") };
+        assert_eq!(map[&Value::from("hp".to_string())], Value::U16(100));
+        assert_eq!(from_value::<Player>(value.clone()).unwrap(), player);
+
+        // A `Value` embedded in a struct, or decoded on its own, matches the tree.
+        let bytes = to_vec_named(&player);
+        assert_eq!(from_slice::<Player>(&bytes).unwrap(), player);
+        assert_eq!(from_slice::<Value>(&bytes).unwrap(), value);
+        assert_eq!(from_slice::<Value>(&to_vec(&value).unwrap()).unwrap(), value);
+
+        // Any other serde format works as well.
+        let arr = Value::Arr(vec![Value::Varint(-3), Value::from("a".to_string())]);
+        let json = serde_json::to_string(&arr).unwrap();
+        assert_eq!(json, r#"[-3,"a"]"#);
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), arr);
+
+        // Variants written by index and legacy struct arrays read back too.
+        let mut serializer = Serializer::new(Buffer::new()).with_variant_mode(VariantMode::Index);
+        player.actions.serialize(&mut serializer).unwrap();
+        let mut out = Vec::new();
+        serializer.export_to(&mut out).unwrap();
+        let actions: Value = from_slice(&out).unwrap();
+        assert_eq!(from_value::<Vec<Action>>(actions).unwrap(), player.actions);
+        let legacy = Value::Arr(vec![Value::from("target".to_string()), Value::Varint(7)]);
+        let legacy = Value::Map([(Value::from("Attack".to_string()), legacy)].into());
+        assert_eq!(from_value::<Action>(legacy).unwrap(), Action::Attack { target: 7 });
+
+        // Two items are the pairs of a legacy struct with a field skipped,
+        // not a positional one, unless the caller asks for that layout.
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Sparse {
+            id: u32,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            note: Option<String>,
+        }
+        let sparse = Sparse { id: 3, note: None };
+        let value: Value = from_slice(&to_vec(&sparse).unwrap()).unwrap();
+        let Value::Arr(ref items) = value else { panic!("legacy struct is not an arr") };
+        assert_eq!(items.len(), 2);
+        assert_eq!(from_value::<Sparse>(value).unwrap(), sparse);
+        let positional = Value::Arr(vec![Value::U32(3), Value::from("x".to_string())]);
+        let sparse = Sparse { id: 3, note: Some("x".to_string()) };
+        let read = from_value_with_struct_mode::<Sparse>(positional.clone(), StructMode::Positional);
+        assert_eq!(read.unwrap(), sparse);
+        assert!(from_value::<Sparse>(positional).is_err());
+
+        // A length read from the input reserves no more than it holds.
+        for kind in [16, 17] {
+            let mut buffer = Buffer::new();
+            buffer.put_slice(&[0, kind]);
+            encode_varint(&mut buffer, &Value::Varint(u32::MAX as i64)).unwrap();
+            assert_eq!(buffer.chunk().len(), 7);
+            assert!(from_slice::<Value>(buffer.chunk()).is_err());
+        }
+    }
+}
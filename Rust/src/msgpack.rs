@@ -0,0 +1,436 @@
+use crate::raw_value::take;
+use crate::transcode::{check_depth, escaped_map, Token, TokenReader, TokenWriter, MAP_TAG};
+use crate::{ErrorKind, HpError, HpResult, Lossy, Transcoded};
+
+/// A MessagePack extension, as `{"$ext": [type, Raw]}`.
+pub const EXT_TAG: &str = "$ext";
+
+/// Transcodes a MessagePack document into a message holding a single value.
+///
+/// `uint8` and `int8` become `U8` and `I8`, other integers `Varint`, or `U64`
+/// when they do not fit an `i64`. The 16, 32 and 64 bit integer types lose
+/// their width in a `Varint` and are reported as [`Lossy::IntWidth`]. `float
+/// 32` and `float 64` keep their width, `bin` becomes `Raw` and an extension
+/// a map tagged with [`EXT_TAG`]. A map whose only key is `"$ext"` or
+/// `"$map"` is escaped as `{"$map": [[key, value]]}`, so it is not taken for
+/// an extension on the way back. A `str` that is not valid UTF-8 is kept as
+/// `Raw` and reported as [`Lossy::InvalidUtf8`]. Nesting deeper than 128
+/// containers is an error.
+pub fn msgpack_to_proto(input: &[u8]) -> HpResult<Transcoded> {
+    let mut out = Transcoded::default();
+    let mut writer = TokenWriter::new();
+    let mut input = input;
+    read_item(&mut input, &mut writer, &mut out, 0)?;
+    if !input.is_empty() {
+        return Err(HpError::from((ErrorKind::ParseError, "left buffer")));
+    }
+    out.bytes = writer.finish()?;
+    Ok(out)
+}
+
+/// Transcodes a message holding a single value into MessagePack, the reverse
+/// of [`msgpack_to_proto`].
+///
+/// Integers take the smallest MessagePack form, except `U8` and `I8` which
+/// are always written as `uint8` and `int8` so they read back with their
+/// type. The other fixed width types read back as `Varint` and are reported
+/// as [`Lossy::IntWidth`]. A map escaped as `{"$map": [[key, value], ...]}`
+/// is written as the plain map, and one tagged with [`EXT_TAG`] as the
+/// extension. Nesting deeper than 128 containers is an error.
+pub fn proto_to_msgpack(buf: &[u8]) -> HpResult<Transcoded> {
+    let mut out = Transcoded::default();
+    let mut reader = TokenReader::new(buf)?;
+    let mut bytes = Vec::with_capacity(buf.len());
+    write_item(&mut reader, &mut bytes, &mut out)?;
+    reader.finish()?;
+    out.bytes = bytes;
+    Ok(out)
+}
+
+fn read_uint(input: &mut &[u8], size: usize) -> HpResult<u64> {
+    Ok(take(input, size)?
+        .iter()
+        .fold(0u64, |v, b| v << 8 | *b as u64))
+}
+
+fn read_item<'a>(
+    input: &mut &'a [u8],
+    writer: &mut TokenWriter<'a>,
+    out: &mut Transcoded,
+    depth: usize,
+) -> HpResult<()> {
+    check_depth(depth)?;
+    let marker = take(input, 1)?[0];
+    let token = match marker {
+        0x00..=0x7f => Token::Varint(marker as i64),
+        0x80..=0x8f => return read_map(input, writer, out, (marker & 0x0f) as usize, depth),
+        0x90..=0x9f => return read_arr(input, writer, out, (marker & 0x0f) as usize, depth),
+        0xa0..=0xbf => return read_str(input, writer, out, (marker & 0x1f) as usize),
+        0xc0 => Token::Nil,
+        0xc1 => return Err(HpError::from((ErrorKind::ParseError, "msgpack never used marker"))),
+        0xc2 => Token::Bool(false),
+        0xc3 => Token::Bool(true),
+        0xc4..=0xc6 => {
+            let len = read_uint(input, 1 << (marker - 0xc4))? as usize;
+            Token::Raw(take(input, len)?)
+        }
+        0xc7..=0xc9 => {
+            let len = read_uint(input, 1 << (marker - 0xc7))? as usize;
+            return read_ext(input, writer, len);
+        }
+        0xca => Token::F32(f32::from_bits(read_uint(input, 4)? as u32)),
+        0xcb => Token::F64(f64::from_bits(read_uint(input, 8)?)),
+        0xcc => Token::U8(read_uint(input, 1)? as u8),
+        0xcd | 0xce | 0xd1..=0xd3 => {
+            out.note(Lossy::IntWidth);
+            Token::Varint(match marker {
+                0xcd => read_uint(input, 2)? as i64,
+                0xce => read_uint(input, 4)? as i64,
+                0xd1 => read_uint(input, 2)? as i16 as i64,
+                0xd2 => read_uint(input, 4)? as i32 as i64,
+                _ => read_uint(input, 8)? as i64,
+            })
+        }
+        0xcf => match read_uint(input, 8)? {
+            v if v > i64::MAX as u64 => Token::U64(v),
+            v => {
+                out.note(Lossy::IntWidth);
+                Token::Varint(v as i64)
+            }
+        },
+        0xd0 => Token::I8(read_uint(input, 1)? as i8),
+        0xd4..=0xd8 => return read_ext(input, writer, 1 << (marker - 0xd4)),
+        0xd9..=0xdb => {
+            let len = read_uint(input, 1 << (marker - 0xd9))? as usize;
+            return read_str(input, writer, out, len);
+        }
+        0xdc | 0xdd => {
+            let len = read_uint(input, 2 << (marker - 0xdc))? as usize;
+            return read_arr(input, writer, out, len, depth);
+        }
+        0xde | 0xdf => {
+            let len = read_uint(input, 2 << (marker - 0xde))? as usize;
+            return read_map(input, writer, out, len, depth);
+        }
+        0xe0..=0xff => Token::Varint(marker as i8 as i64),
+    };
+    writer.write(token)
+}
+
+fn read_str<'a>(
+    input: &mut &'a [u8],
+    writer: &mut TokenWriter<'a>,
+    out: &mut Transcoded,
+    len: usize,
+) -> HpResult<()> {
+    let val = take(input, len)?;
+    match std::str::from_utf8(val) {
+        Ok(val) => writer.write(Token::Str(val)),
+        Err(_) => {
+            out.note(Lossy::InvalidUtf8);
+            writer.write(Token::Raw(val))
+        }
+    }
+}
+
+fn read_arr<'a>(
+    input: &mut &'a [u8],
+    writer: &mut TokenWriter<'a>,
+    out: &mut Transcoded,
+    len: usize,
+    depth: usize,
+) -> HpResult<()> {
+    writer.write(Token::Arr(len))?;
+    for _ in 0..len {
+        read_item(input, writer, out, depth + 1)?;
+    }
+    Ok(())
+}
+
+fn read_map<'a>(
+    input: &mut &'a [u8],
+    writer: &mut TokenWriter<'a>,
+    out: &mut Transcoded,
+    len: usize,
+    depth: usize,
+) -> HpResult<()> {
+    let escaped = len == 1 && is_tag_key(input);
+    if escaped {
+        writer.write(Token::Map(1))?;
+        writer.write(Token::Str(MAP_TAG))?;
+        writer.write(Token::Arr(1))?;
+    } else {
+        writer.write(Token::Map(len))?;
+    }
+    for _ in 0..len {
+        if escaped {
+            writer.write(Token::Arr(2))?;
+        }
+        read_item(input, writer, out, depth + 1)?;
+        read_item(input, writer, out, depth + 1)?;
+    }
+    Ok(())
+}
+
+/// Whether the next item is a `str` named after [`EXT_TAG`] or [`MAP_TAG`].
+fn is_tag_key(input: &[u8]) -> bool {
+    let mut input = input;
+    let len = match take(&mut input, 1) {
+        Ok(&[marker @ 0xa0..=0xbf]) => (marker & 0x1f) as usize,
+        Ok(&[marker @ 0xd9..=0xdb]) => match read_uint(&mut input, 1 << (marker - 0xd9)) {
+            Ok(len) => len as usize,
+            Err(_) => return false,
+        },
+        _ => return false,
+    };
+    take(&mut input, len).is_ok_and(|key| key == EXT_TAG.as_bytes() || key == MAP_TAG.as_bytes())
+}
+
+fn read_ext<'a>(input: &mut &'a [u8], writer: &mut TokenWriter<'a>, len: usize) -> HpResult<()> {
+    let ext_type = take(input, 1)?[0] as i8;
+    let data = take(input, len)?;
+    writer.write(Token::Map(1))?;
+    writer.write(Token::Str(EXT_TAG))?;
+    writer.write(Token::Arr(2))?;
+    writer.write(Token::Varint(ext_type as i64))?;
+    writer.write(Token::Raw(data))
+}
+
+/// Reads the content of a one pair map tagged with [`EXT_TAG`], `None` if it
+/// is not one.
+fn tagged_ext<'a>(reader: &mut TokenReader<'a>) -> HpResult<Option<(i8, &'a [u8])>> {
+    if reader.next()? != Token::Str(EXT_TAG) || reader.next()? != Token::Arr(2) {
+        return Ok(None);
+    }
+    let ext_type = match reader.next()?.as_i64().map(i8::try_from) {
+        Some(Ok(ext_type)) => ext_type,
+        _ => return Ok(None),
+    };
+    match reader.next()? {
+        Token::Raw(data) => Ok(Some((ext_type, data))),
+        _ => Ok(None),
+    }
+}
+
+/// Writes a length, `fix` being the marker of the short form and the bound
+/// it stays under, `sized` the markers of the 8, 16 and 32 bit forms, with 0
+/// for a form the type does not have.
+fn write_len(bytes: &mut Vec<u8>, len: usize, fix: Option<(u8, usize)>, sized: [u8; 3]) {
+    match fix {
+        Some((marker, bound)) if len < bound => bytes.push(marker | len as u8),
+        _ if len <= u8::MAX as usize && sized[0] != 0 => bytes.extend_from_slice(&[sized[0], len as u8]),
+        _ if len <= u16::MAX as usize => {
+            bytes.push(sized[1]);
+            bytes.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            bytes.push(sized[2]);
+            bytes.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+fn write_uint(bytes: &mut Vec<u8>, v: u64) {
+    if v < 0x80 {
+        bytes.push(v as u8);
+    } else if v <= u8::MAX as u64 {
+        bytes.extend_from_slice(&[0xcc, v as u8]);
+    } else if v <= u16::MAX as u64 {
+        bytes.push(0xcd);
+        bytes.extend_from_slice(&(v as u16).to_be_bytes());
+    } else if v <= u32::MAX as u64 {
+        bytes.push(0xce);
+        bytes.extend_from_slice(&(v as u32).to_be_bytes());
+    } else {
+        bytes.push(0xcf);
+        bytes.extend_from_slice(&v.to_be_bytes());
+    }
+}
+
+fn write_int(bytes: &mut Vec<u8>, v: i64) {
+    if v >= 0 {
+        write_uint(bytes, v as u64);
+    } else if v >= -32 {
+        bytes.push(v as u8);
+    } else if v >= i8::MIN as i64 {
+        bytes.extend_from_slice(&[0xd0, v as u8]);
+    } else if v >= i16::MIN as i64 {
+        bytes.push(0xd1);
+        bytes.extend_from_slice(&(v as i16).to_be_bytes());
+    } else if v >= i32::MIN as i64 {
+        bytes.push(0xd2);
+        bytes.extend_from_slice(&(v as i32).to_be_bytes());
+    } else {
+        bytes.push(0xd3);
+        bytes.extend_from_slice(&v.to_be_bytes());
+    }
+}
+
+fn write_ext(bytes: &mut Vec<u8>, ext_type: i8, data: &[u8]) {
+    match data.len() {
+        len @ (1 | 2 | 4 | 8 | 16) => bytes.push(0xd4 + len.trailing_zeros() as u8),
+        len => write_len(bytes, len, None, [0xc7, 0xc8, 0xc9]),
+    }
+    bytes.push(ext_type as u8);
+    bytes.extend_from_slice(data);
+}
+
+fn write_item(reader: &mut TokenReader, bytes: &mut Vec<u8>, out: &mut Transcoded) -> HpResult<()> {
+    match reader.next()? {
+        Token::Nil => bytes.push(0xc0),
+        Token::Bool(v) => bytes.push(if v { 0xc3 } else { 0xc2 }),
+        Token::U8(v) => bytes.extend_from_slice(&[0xcc, v]),
+        Token::I8(v) => bytes.extend_from_slice(&[0xd0, v as u8]),
+        Token::U64(v) if v > i64::MAX as u64 => write_uint(bytes, v),
+        Token::Varint(v) => write_int(bytes, v),
+        Token::F32(v) => {
+            bytes.push(0xca);
+            bytes.extend_from_slice(&v.to_be_bytes());
+        }
+        Token::F64(v) => {
+            bytes.push(0xcb);
+            bytes.extend_from_slice(&v.to_be_bytes());
+        }
+        Token::Str(v) => {
+            write_len(bytes, v.len(), Some((0xa0, 32)), [0xd9, 0xda, 0xdb]);
+            bytes.extend_from_slice(v.as_bytes());
+        }
+        Token::Raw(v) => {
+            write_len(bytes, v.len(), None, [0xc4, 0xc5, 0xc6]);
+            bytes.extend_from_slice(v);
+        }
+        Token::Arr(len) => {
+            write_len(bytes, len, Some((0x90, 16)), [0, 0xdc, 0xdd]);
+            for _ in 0..len {
+                write_item(reader, bytes, out)?;
+            }
+        }
+        Token::Map(len) => {
+            if len == 1 {
+                let mark = reader.mark();
+                if let Some((ext_type, data)) = tagged_ext(reader)? {
+                    write_ext(bytes, ext_type, data);
+                    return Ok(());
+                }
+                reader.reset(mark.clone());
+                if let Some(len) = escaped_map(reader)? {
+                    write_len(bytes, len, Some((0x80, 16)), [0, 0xde, 0xdf]);
+                    for _ in 0..len {
+                        reader.next()?;
+                        write_item(reader, bytes, out)?;
+                        write_item(reader, bytes, out)?;
+                    }
+                    return Ok(());
+                }
+                reader.reset(mark);
+            }
+            write_len(bytes, len, Some((0x80, 16)), [0, 0xde, 0xdf]);
+            for _ in 0..len * 2 {
+                write_item(reader, bytes, out)?;
+            }
+        }
+        token => {
+            out.note(Lossy::IntWidth);
+            write_int(bytes, token.as_i64().unwrap_or_default());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{msgpack_to_proto, proto_to_msgpack};
+    use crate::{from_slice, to_vec, Lossy, Value};
+
+    #[test]
+    fn test_msgpack_round_trip() {
+        let mut map = HashMap::new();
+        map.insert(Value::from("name".to_string()), Value::from("hc".to_string()));
+        map.insert(Value::U8(7), Value::Arr(vec![Value::I8(-3), Value::Varint(-300)]));
+        map.insert(Value::from("big".to_string()), Value::U64(u64::MAX));
+        map.insert(Value::from("float".to_string()), Value::Arr(vec![Value::F32(1.5), Value::F64(0.1)]));
+        map.insert(Value::Nil, Value::Raw(vec![1, 2, 3]));
+        let value = Value::Map(map);
+
+        let packed = proto_to_msgpack(&to_vec(&value).unwrap()).unwrap();
+        assert!(packed.is_lossless());
+        let back = msgpack_to_proto(&packed.bytes).unwrap();
+        // -300 is written as an `int 16`, which has no width in a `Varint`.
+        assert_eq!(back.lossy, vec![(Lossy::IntWidth, 1)]);
+        assert_eq!(from_slice::<Value>(&back.bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn test_msgpack_bytes() {
+        // {"a": [1, -1, 200], "b": ext(5, [0xff])}
+        let input = [
+            0x82, 0xa1, b'a', 0x93, 0x01, 0xff, 0xcc, 0xc8, 0xa1, b'b', 0xd4, 0x05, 0xff,
+        ];
+        let proto = msgpack_to_proto(&input).unwrap();
+        let Value::Map(map) = from_slice::<Value>(&proto.bytes).unwrap() else { panic!("not a map") };
+        assert_eq!(
+            map[&Value::from("a".to_string())],
+            Value::Arr(vec![Value::Varint(1), Value::Varint(-1), Value::U8(200)])
+        );
+        let mut ext = HashMap::new();
+        ext.insert(
+            Value::from("$ext".to_string()),
+            Value::Arr(vec![Value::Varint(5), Value::Raw(vec![0xff])]),
+        );
+        assert_eq!(map[&Value::from("b".to_string())], Value::Map(ext));
+
+        // Map order is not kept, so compare element by element.
+        let packed = proto_to_msgpack(&proto.bytes).unwrap();
+        assert_eq!(packed.bytes.len(), input.len());
+        assert!(packed.bytes.windows(3).any(|w| w == [0xd4, 0x05, 0xff]));
+
+        let proto = msgpack_to_proto(&[0xa2, 0xff, 0xfe]).unwrap();
+        assert_eq!(proto.lossy, vec![(Lossy::InvalidUtf8, 1)]);
+        // An empty string table and a fixed width U16.
+        let proto = [0x00, 0x04, 0x01, 0x2c];
+        assert_eq!(proto_to_msgpack(&proto).unwrap().lossy, vec![(Lossy::IntWidth, 1)]);
+        assert!(msgpack_to_proto(&[0x92, 0x01]).is_err());
+
+        // A `uint 64` that fits an `i64` narrows to a `Varint`.
+        let proto = msgpack_to_proto(&[0xcf, 0, 0, 0, 0, 0, 0, 0, 5]).unwrap();
+        assert_eq!(proto.lossy, vec![(Lossy::IntWidth, 1)]);
+        assert_eq!(from_slice::<Value>(&proto.bytes).unwrap(), Value::Varint(5));
+    }
+
+    #[test]
+    fn test_msgpack_escaped_map() {
+        let content = Value::Arr(vec![Value::Varint(1), Value::Raw(vec![b'x'])]);
+        // {"$ext": [1, bin "x"]} as a plain map, not an extension.
+        let input = [0x81, 0xa4, b'$', b'e', b'x', b't', 0x92, 0x01, 0xc4, 0x01, b'x'];
+        let proto = msgpack_to_proto(&input).unwrap();
+        assert!(proto.is_lossless());
+        let entry = Value::Arr(vec![Value::from("$ext".to_string()), content.clone()]);
+        let escaped = Value::Map([(Value::from("$map".to_string()), Value::Arr(vec![entry]))].into());
+        assert_eq!(from_slice::<Value>(&proto.bytes).unwrap(), escaped);
+        assert_eq!(proto_to_msgpack(&proto.bytes).unwrap().bytes, input);
+
+        // The map left unescaped is the extension.
+        let tagged = Value::Map([(Value::from("$ext".to_string()), content)].into());
+        assert_eq!(proto_to_msgpack(&to_vec(&tagged).unwrap()).unwrap().bytes, [0xd4, 0x01, b'x']);
+    }
+
+    #[test]
+    fn test_msgpack_depth() {
+        let nested = |depth: usize| {
+            let mut input = vec![0x91; depth];
+            input.push(0xc0);
+            input
+        };
+        assert!(msgpack_to_proto(&nested(128)).is_ok());
+        assert!(msgpack_to_proto(&nested(129)).is_err());
+        assert!(msgpack_to_proto(&nested(100_000)).is_err());
+
+        let mut value = Value::Nil;
+        for _ in 0..129 {
+            value = Value::Arr(vec![value]);
+        }
+        assert!(proto_to_msgpack(&to_vec(&value).unwrap()).is_err());
+    }
+}
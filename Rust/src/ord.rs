@@ -0,0 +1,252 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::Value;
+
+/// A total order over every value, so values can be sorted and used as
+/// `BTreeMap` keys.
+///
+/// Variants are ordered by group:
+/// `Nil < Bool < numbers < Str < Raw < Arr < Map < Encoded`.
+///
+/// - `false < true`
+/// - numbers compare by value, whatever their types, so `U8(3) < F32(3.5)
+///   < Varint(4)`. Integers of the same value are equal, as they are for
+///   `==`, and come before floats of that value, ordered `F32 < F64`. NaN is
+///   greater than every other number, and all NaNs of one type are equal
+/// - strings and raw bytes compare lexicographically by byte
+/// - arrays compare lexicographically by item
+/// - maps compare as their entries sorted by key, lexicographically
+/// - encoded values compare by their bytes
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Bool(l), Value::Bool(r)) => l.cmp(r),
+            (Value::Str(l), Value::Str(r)) => l.cmp(r),
+            (Value::Raw(l), Value::Raw(r)) => l.cmp(r),
+            (Value::Arr(l), Value::Arr(r)) => l.cmp(r),
+            (Value::Map(l), Value::Map(r)) => sorted(l).cmp(&sorted(r)),
+            (Value::Encoded(l), Value::Encoded(r)) => l.as_bytes().cmp(r.as_bytes()),
+            _ => match (Number::of(self), Number::of(other)) {
+                (Some(l), Some(r)) => l.cmp(&r).then_with(|| match (l, r) {
+                    (Number::Int(_), Number::Int(_)) => Ordering::Equal,
+                    _ => rank(self).cmp(&rank(other)),
+                }),
+                _ => rank(self).cmp(&rank(other)),
+            },
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Value {
+    /// The entries of a `Map` ordered by key, `None` for other values.
+    pub fn sorted_entries(&self) -> Option<Vec<(&Value, &Value)>> {
+        match self {
+            Value::Map(map) => Some(sorted(map)),
+            _ => None,
+        }
+    }
+
+    /// Turns a `Map` into a `BTreeMap`, giving the value back otherwise.
+    pub fn into_sorted_map(self) -> Result<BTreeMap<Value, Value>, Value> {
+        match self {
+            Value::Map(map) => Ok(map.into_iter().collect()),
+            value => Err(value),
+        }
+    }
+}
+
+fn sorted(map: &HashMap<Value, Value>) -> Vec<(&Value, &Value)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_unstable();
+    entries
+}
+
+/// The group of a variant, with numbers ranked by type inside theirs.
+fn rank(value: &Value) -> u8 {
+    match value {
+        Value::Nil => 0,
+        Value::Bool(_) => 1,
+        Value::U8(_) => 2,
+        Value::I8(_) => 3,
+        Value::U16(_) => 4,
+        Value::I16(_) => 5,
+        Value::U32(_) => 6,
+        Value::I32(_) => 7,
+        Value::U64(_) => 8,
+        Value::I64(_) => 9,
+        Value::Varint(_) => 10,
+        Value::F32(_) => 11,
+        Value::F64(_) => 12,
+        Value::Str(_) => 13,
+        Value::Raw(_) => 14,
+        Value::Arr(_) => 15,
+        Value::Map(_) => 16,
+        Value::Encoded(_) => 17,
+    }
+}
+
+/// A number compared by value, exactly, across integers and floats.
+enum Number {
+    Int(i128),
+    Float(f64),
+    NaN,
+}
+
+impl Number {
+    fn of(value: &Value) -> Option<Number> {
+        let float = |v: f64| if v.is_nan() { Number::NaN } else { Number::Float(v) };
+        Some(match *value {
+            Value::U8(v) => Number::Int(v as i128),
+            Value::I8(v) => Number::Int(v as i128),
+            Value::U16(v) => Number::Int(v as i128),
+            Value::I16(v) => Number::Int(v as i128),
+            Value::U32(v) => Number::Int(v as i128),
+            Value::I32(v) => Number::Int(v as i128),
+            Value::U64(v) => Number::Int(v as i128),
+            Value::I64(v) | Value::Varint(v) => Number::Int(v as i128),
+            Value::F32(v) => float(v as f64),
+            Value::F64(v) => float(v),
+            _ => return None,
+        })
+    }
+}
+
+impl Ord for Number {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Number::NaN, Number::NaN) => Ordering::Equal,
+            (Number::NaN, _) => Ordering::Greater,
+            (_, Number::NaN) => Ordering::Less,
+            (Number::Int(l), Number::Int(r)) => l.cmp(r),
+            (Number::Float(l), Number::Float(r)) => l.partial_cmp(r).unwrap_or(Ordering::Equal),
+            (Number::Int(l), Number::Float(r)) => cmp_int_float(*l, *r),
+            (Number::Float(l), Number::Int(r)) => cmp_int_float(*r, *l).reverse(),
+        }
+    }
+}
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Number {}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Compares without rounding the integer to a float.
+fn cmp_int_float(int: i128, float: f64) -> Ordering {
+    // Every integer here fits in (-2^64, 2^64), where an f64 floor converts exactly.
+    const LIMIT: f64 = 18446744073709551616.0;
+    if float >= LIMIT {
+        return Ordering::Less;
+    }
+    if float <= -LIMIT {
+        return Ordering::Greater;
+    }
+    let floor = float.floor();
+    int.cmp(&(floor as i128)).then(if float > floor {
+        Ordering::Less
+    } else {
+        Ordering::Equal
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::Value;
+
+    #[test]
+    fn test_ord() {
+        let mut values = vec![
+            Value::F64(f64::NAN),
+            Value::Str("b".to_string()),
+            Value::Varint(4),
+            Value::Raw(vec![0]),
+            Value::F32(3.5),
+            Value::Nil,
+            Value::U64(u64::MAX),
+            Value::F64(-f64::INFINITY),
+            Value::Str("a".to_string()),
+            Value::I8(-3),
+            Value::U8(3),
+            Value::Bool(true),
+            Value::F64(3.0),
+            Value::Arr(vec![Value::Nil]),
+            Value::Varint(3),
+            Value::Bool(false),
+        ];
+        values.sort();
+        assert_eq!(
+            values.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+            [
+                "nil", "false", "true", "-inff64", "-3i8", "3u8", "3v", "3.0f64", "3.5f32", "4v",
+                "18446744073709551615u64", "NaNf64", "\"a\"", "\"b\"", "b\"\\0\"", "[nil]"
+            ]
+        );
+        assert!(Value::F64(f64::NAN).cmp(&Value::F64(-f64::NAN)).is_eq());
+        assert_eq!(Value::F64(f64::NAN), Value::F64(-f64::NAN));
+        assert_ne!(Value::F32(f32::NAN), Value::F64(f64::NAN));
+        assert_eq!(crate::ValueRef::F32(f32::NAN), crate::ValueRef::F32(f32::NAN));
+        assert!(Value::U64(1 << 63) > Value::F64(9.2e18));
+        assert!(Value::I64(i64::MIN) < Value::F32(-9.2e18));
+
+        // `==` and `cmp` agree across integer widths.
+        let ints = [-1i64, 0, 5, 300, i64::MAX];
+        let ints: Vec<Value> = ints
+            .iter()
+            .flat_map(|&v| {
+                [
+                    Value::U8(v as u8),
+                    Value::I8(v as i8),
+                    Value::U16(v as u16),
+                    Value::I16(v as i16),
+                    Value::U32(v as u32),
+                    Value::I32(v as i32),
+                    Value::U64(v as u64),
+                    Value::I64(v),
+                    Value::Varint(v),
+                ]
+            })
+            .collect();
+        for a in &ints {
+            for b in &ints {
+                assert_eq!(a == b, a.cmp(b).is_eq(), "{} {}", a, b);
+            }
+        }
+        assert_eq!(Value::U8(5), Value::U16(5));
+        assert_ne!(Value::U64(u64::MAX), Value::Varint(-1));
+
+        let map = |pairs: &[(&str, i64)]| {
+            let map: HashMap<Value, Value> = pairs
+                .iter()
+                .map(|(k, v)| (Value::Str(k.to_string()), Value::Varint(*v)))
+                .collect();
+            Value::Map(map)
+        };
+        let a = map(&[("x", 1), ("y", 2)]);
+        assert!(a < map(&[("x", 1), ("y", 3)]));
+        assert!(a > map(&[("x", 1)]));
+        assert!(a < map(&[("z", 0)]));
+
+        let entries = a.sorted_entries().unwrap();
+        assert_eq!(entries[0], (&Value::Str("x".to_string()), &Value::Varint(1)));
+        let sorted = a.clone().into_sorted_map().unwrap();
+        assert_eq!(sorted.keys().next_back(), Some(&Value::Str("y".to_string())));
+        assert_eq!(Value::Nil.into_sorted_map(), Err(Value::Nil));
+    }
+}
@@ -0,0 +1,354 @@
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::values::{integer, type_not_match};
+use crate::{get_type_by_value, ErrorKind, HpError, HpResult, Value};
+
+/// One change of a [`Patch`].
+///
+/// A path lists the steps from the root: a key for a `Map`, an integer index
+/// for an `Arr`, the container met deciding which. An empty path is the root.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchOp {
+    /// Replaces the value at `path`, or adds the key to its `Map`.
+    Set { path: Vec<Value>, value: Value },
+    /// Removes the key from its `Map`, or the item from its `Arr`.
+    Remove { path: Vec<Value> },
+    /// Inserts into an `Arr` before the index, which may be its length.
+    Insert { path: Vec<Value>, value: Value },
+    /// Adds a `Varint`, `F32` or `F64` to the number at `path`, keeping its type.
+    Delta { path: Vec<Value>, delta: Value },
+}
+
+/// The changes turning one value into another, made by [`diff`] and run by
+/// [`apply`] in order.
+///
+/// It serializes as an `Arr` of `[op, path, value]` items, op `0` to `3` in
+/// the order of [`PatchOp`], so `to_vec(&patch)` gives a compact message and
+/// keys repeated across paths share the string table.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Patch {
+    pub ops: Vec<PatchOp>,
+}
+
+impl Patch {
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn to_value(&self) -> Value {
+        let op = |code: u8, path: &Vec<Value>, value: Option<&Value>| {
+            let mut item = vec![Value::U8(code), Value::Arr(path.clone())];
+            item.extend(value.cloned());
+            Value::Arr(item)
+        };
+        let ops = self.ops.iter().map(|v| match v {
+            PatchOp::Set { path, value } => op(0, path, Some(value)),
+            PatchOp::Remove { path } => op(1, path, None),
+            PatchOp::Insert { path, value } => op(2, path, Some(value)),
+            PatchOp::Delta { path, delta } => op(3, path, Some(delta)),
+        });
+        Value::Arr(ops.collect())
+    }
+
+    pub fn from_value(value: Value) -> HpResult<Patch> {
+        let bad = || HpError::from((ErrorKind::ParseError, "bad patch op"));
+        let mut ops = Vec::new();
+        for item in Vec::<Value>::try_from(value)? {
+            let mut item = Vec::<Value>::try_from(item)?.into_iter();
+            let code = u8::try_from(item.next().ok_or_else(bad)?)?;
+            let path = Vec::<Value>::try_from(item.next().ok_or_else(bad)?)?;
+            let value = item.next();
+            if item.next().is_some() {
+                return Err(bad());
+            }
+            ops.push(match (code, value) {
+                (0, Some(value)) => PatchOp::Set { path, value },
+                (1, None) => PatchOp::Remove { path },
+                (2, Some(value)) => PatchOp::Insert { path, value },
+                (3, Some(delta)) => PatchOp::Delta { path, delta },
+                _ => return Err(bad()),
+            });
+        }
+        Ok(Patch { ops })
+    }
+}
+
+impl Serialize for Patch {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_value().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Patch {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Patch, D::Error> {
+        Patch::from_value(Value::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// The patch turning `old` into `new`.
+///
+/// Maps are compared key by key and arrays item by item after their common
+/// head and tail, with the extra items inserted or removed. A number that
+/// keeps its type becomes a `Delta`, when adding it gives `new` back exactly.
+/// A value changing type is replaced whole, so `apply` keeps exact types.
+pub fn diff(old: &Value, new: &Value) -> Patch {
+    let mut patch = Patch::default();
+    diff_into(old, new, &mut Vec::new(), &mut patch.ops);
+    patch
+}
+
+/// Equal and of the same type, as `==` alone takes `U8(1)` for `Varint(1)`.
+pub(crate) fn same(old: &Value, new: &Value) -> bool {
+    get_type_by_value(old) == get_type_by_value(new) && old == new
+}
+
+fn diff_into(old: &Value, new: &Value, path: &mut Vec<Value>, ops: &mut Vec<PatchOp>) {
+    let at = |path: &Vec<Value>, step: Value| {
+        let mut path = path.clone();
+        path.push(step);
+        path
+    };
+    match (old, new) {
+        (Value::Map(old), Value::Map(new)) => {
+            for key in old.keys().filter(|key| !new.contains_key(*key)) {
+                ops.push(PatchOp::Remove { path: at(path, key.clone()) });
+            }
+            for (key, value) in new {
+                match old.get(key) {
+                    Some(old) => {
+                        path.push(key.clone());
+                        diff_into(old, value, path, ops);
+                        path.pop();
+                    }
+                    None => ops.push(PatchOp::Set {
+                        path: at(path, key.clone()),
+                        value: value.clone(),
+                    }),
+                }
+            }
+        }
+        (Value::Arr(old), Value::Arr(new)) => {
+            let head = old.iter().zip(new).take_while(|(a, b)| same(a, b)).count();
+            let tail = old[head..]
+                .iter()
+                .rev()
+                .zip(new[head..].iter().rev())
+                .take_while(|(a, b)| same(a, b))
+                .count();
+            let (old_mid, new_mid) = (old.len() - head - tail, new.len() - head - tail);
+            let common = old_mid.min(new_mid);
+            for i in head..head + common {
+                path.push(Value::Varint(i as i64));
+                diff_into(&old[i], &new[i], path, ops);
+                path.pop();
+            }
+            for i in (head + common..head + old_mid).rev() {
+                ops.push(PatchOp::Remove { path: at(path, Value::Varint(i as i64)) });
+            }
+            for (i, value) in new.iter().enumerate().take(head + new_mid).skip(head + common) {
+                ops.push(PatchOp::Insert {
+                    path: at(path, Value::Varint(i as i64)),
+                    value: value.clone(),
+                });
+            }
+        }
+        _ if same(old, new) => {}
+        _ => match delta(old, new) {
+            Some(delta) => ops.push(PatchOp::Delta { path: path.clone(), delta }),
+            None => ops.push(PatchOp::Set { path: path.clone(), value: new.clone() }),
+        },
+    }
+}
+
+/// The delta from `old` to `new` if they are numbers of one type and adding
+/// it is exact.
+fn delta(old: &Value, new: &Value) -> Option<Value> {
+    match (old, new) {
+        (Value::F32(a), Value::F32(b)) => {
+            let d = b - a;
+            (a + d).to_bits().eq(&b.to_bits()).then_some(Value::F32(d))
+        }
+        (Value::F64(a), Value::F64(b)) => {
+            let d = b - a;
+            (a + d).to_bits().eq(&b.to_bits()).then_some(Value::F64(d))
+        }
+        _ if get_type_by_value(old) == get_type_by_value(new) => {
+            let d = integer(new)? - integer(old)?;
+            i64::try_from(d).ok().map(Value::Varint)
+        }
+        _ => None,
+    }
+}
+
+/// Runs the ops of `patch` on `value` in order.
+///
+/// Stops at the first op whose path is missing or whose target has the
+/// wrong type, with `value` holding the ops before it.
+pub fn apply(value: &mut Value, patch: &Patch) -> HpResult<()> {
+    for op in &patch.ops {
+        match op {
+            PatchOp::Set { path, value: new } => match path.split_last() {
+                None => *value = new.clone(),
+                Some((last, parent)) => match walk(value, parent)? {
+                    Value::Map(map) => {
+                        map.insert(last.clone(), new.clone());
+                    }
+                    target => *item(target, last, path)? = new.clone(),
+                },
+            },
+            PatchOp::Remove { path } => {
+                let (last, parent) = path.split_last().ok_or_else(|| missing(path))?;
+                match walk(value, parent)? {
+                    Value::Map(map) => {
+                        map.remove(last).ok_or_else(|| missing(path))?;
+                    }
+                    Value::Arr(arr) => {
+                        let i = index(last, arr.len(), path)?;
+                        arr.remove(i);
+                    }
+                    target => return Err(type_not_match("map or arr", target)),
+                }
+            }
+            PatchOp::Insert { path, value: new } => {
+                let (last, parent) = path.split_last().ok_or_else(|| missing(path))?;
+                match walk(value, parent)? {
+                    Value::Arr(arr) => {
+                        let i = index(last, arr.len() + 1, path)?;
+                        arr.insert(i, new.clone());
+                    }
+                    target => return Err(type_not_match("arr", target)),
+                }
+            }
+            PatchOp::Delta { path, delta } => add(walk(value, path)?, delta)?,
+        }
+    }
+    Ok(())
+}
+
+fn missing(path: &[Value]) -> HpError {
+    HpError::from((
+        ErrorKind::MissingError,
+        "patch path not found",
+        Value::Arr(path.to_vec()).to_string(),
+    ))
+}
+
+fn index(step: &Value, len: usize, path: &[Value]) -> HpResult<usize> {
+    integer(step)
+        .and_then(|i| usize::try_from(i).ok())
+        .filter(|i| *i < len)
+        .ok_or_else(|| missing(path))
+}
+
+/// The item of a `Map` or `Arr` at `step`.
+fn item<'v>(value: &'v mut Value, step: &Value, path: &[Value]) -> HpResult<&'v mut Value> {
+    match value {
+        Value::Map(map) => map.get_mut(step).ok_or_else(|| missing(path)),
+        Value::Arr(arr) => {
+            let i = index(step, arr.len(), path)?;
+            Ok(&mut arr[i])
+        }
+        target => Err(type_not_match("map or arr", target)),
+    }
+}
+
+fn walk<'v>(mut value: &'v mut Value, path: &[Value]) -> HpResult<&'v mut Value> {
+    for step in path {
+        value = item(value, step, path)?;
+    }
+    Ok(value)
+}
+
+fn add(value: &mut Value, delta: &Value) -> HpResult<()> {
+    let overflow = || HpError::from((ErrorKind::TypeNotMatchError, "patch delta out of range"));
+    match (&mut *value, delta) {
+        (Value::F32(v), Value::F32(d)) => *v += d,
+        (Value::F64(v), Value::F64(d)) => *v += d,
+        (target, Value::Varint(d)) => {
+            let sum = integer(target).ok_or_else(|| type_not_match("integer", target))? + *d as i128;
+            *target = match *target {
+                Value::U8(_) => Value::U8(sum.try_into().map_err(|_| overflow())?),
+                Value::I8(_) => Value::I8(sum.try_into().map_err(|_| overflow())?),
+                Value::U16(_) => Value::U16(sum.try_into().map_err(|_| overflow())?),
+                Value::I16(_) => Value::I16(sum.try_into().map_err(|_| overflow())?),
+                Value::U32(_) => Value::U32(sum.try_into().map_err(|_| overflow())?),
+                Value::I32(_) => Value::I32(sum.try_into().map_err(|_| overflow())?),
+                Value::U64(_) => Value::U64(sum.try_into().map_err(|_| overflow())?),
+                Value::I64(_) => Value::I64(sum.try_into().map_err(|_| overflow())?),
+                _ => Value::Varint(sum.try_into().map_err(|_| overflow())?),
+            };
+        }
+        (target, _) => return Err(type_not_match(number_name(delta), target)),
+    }
+    Ok(())
+}
+
+fn number_name(delta: &Value) -> &'static str {
+    match delta {
+        Value::F32(_) => "f32",
+        Value::F64(_) => "f64",
+        _ => "number",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{apply, diff, from_slice, to_vec, ErrorKind, Patch, PatchOp, Value};
+
+    fn parse(text: &str) -> Value {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_diff_apply() {
+        let old = parse(
+            r#"{hp: 100u16, pos: [1.5f32, 2.0f32], name: "a", bag: [1v, 2v, 3v, 4v], gone: nil, ts: 1700000000000u64}"#,
+        );
+        let new = parse(
+            r#"{hp: 90u16, pos: [1.75f32, 2.0f32], name: 7u8, bag: [1v, 9v, 3v, 5v, 6v, 4v], extra: {a: true}, ts: 1700000000050u64}"#,
+        );
+        let patch = diff(&old, &new);
+        assert!(patch.ops.contains(&PatchOp::Delta {
+            path: vec![Value::from("hp")],
+            delta: Value::Varint(-10)
+        }));
+        assert!(patch.ops.contains(&PatchOp::Remove { path: vec![Value::from("gone")] }));
+        assert!(patch.ops.contains(&PatchOp::Set {
+            path: vec![Value::from("name")],
+            value: Value::U8(7)
+        }));
+
+        let mut target = old.clone();
+        apply(&mut target, &patch).unwrap();
+        assert_eq!(target, new);
+        assert!(diff(&new, &target).is_empty());
+        assert!(diff(&parse("{x: NaNf64}"), &parse("{x: NaNf64}")).is_empty());
+
+        let bytes = to_vec(&patch).unwrap();
+        let decoded: Patch = from_slice(&bytes).unwrap();
+        let mut target = old.clone();
+        apply(&mut target, &decoded).unwrap();
+        assert_eq!(target, new);
+
+        let mut root = Value::U8(1);
+        apply(&mut root, &diff(&Value::U8(1), &Value::Str("x".to_string()))).unwrap();
+        assert_eq!(root, Value::Str("x".to_string()));
+
+        let mut other = parse("{hp: 5u16}");
+        let err = apply(&mut other, &diff(&old, &new)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingError);
+        let patch = diff(&parse("{hp: 5u8}"), &parse("{hp: 255u8}"));
+        let mut other = parse("{hp: 10u8}");
+        assert_eq!(apply(&mut other, &patch).unwrap_err().kind(), ErrorKind::TypeNotMatchError);
+
+        let mut state = Value::Nil;
+        for i in 0..100u32 {
+            state.insert(format!("player_{}", i), parse("{hp: 100u16, name: \"some name\"}")).unwrap();
+        }
+        let mut next = state.clone();
+        next["player_42"]["hp"] = Value::U16(99);
+        let bytes = to_vec(&diff(&state, &next)).unwrap();
+        assert!(bytes.len() < 32, "{}", bytes.len());
+    }
+}
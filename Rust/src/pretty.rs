@@ -0,0 +1,329 @@
+use std::fmt::{self, Display, Write};
+
+use crate::Value;
+
+/// Options of the pretty printer, see [`Value::pretty`].
+///
+/// The defaults print everything, two spaces per level, in the text notation
+/// of `Display`, so the output parses back. Any limit, `with_raw_hex` or
+/// `with_paths` make it a log format only.
+#[derive(Debug, Clone)]
+pub struct PrettyConfig {
+    indent: String,
+    max_depth: Option<usize>,
+    max_items: Option<usize>,
+    max_bytes: Option<usize>,
+    raw_hex: bool,
+    sort_keys: bool,
+    paths: bool,
+}
+
+impl Default for PrettyConfig {
+    fn default() -> Self {
+        PrettyConfig {
+            indent: "  ".to_string(),
+            max_depth: None,
+            max_items: None,
+            max_bytes: None,
+            raw_hex: false,
+            sort_keys: false,
+            paths: false,
+        }
+    }
+}
+
+impl PrettyConfig {
+    pub fn new() -> Self {
+        PrettyConfig::default()
+    }
+
+    /// The text written once per nesting level.
+    pub fn with_indent(mut self, indent: &str) -> Self {
+        self.indent = indent.to_string();
+        self
+    }
+
+    /// Collapses the collections nested deeper than `depth` levels into a
+    /// count of their items, the top level collection being level 1.
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Prints at most `items` items of a collection, then `...N more`.
+    pub fn with_max_items(mut self, items: usize) -> Self {
+        self.max_items = Some(items);
+        self
+    }
+
+    /// Cuts strings and raw bytes after `len` bytes, followed by their length.
+    pub fn with_max_bytes(mut self, len: usize) -> Self {
+        self.max_bytes = Some(len);
+        self
+    }
+
+    /// Prints raw bytes as `hex(0aff)` instead of `b"\n\xff"`.
+    pub fn with_raw_hex(mut self, raw_hex: bool) -> Self {
+        self.raw_hex = raw_hex;
+        self
+    }
+
+    /// Prints map entries ordered by key instead of in hash order.
+    pub fn with_sorted_keys(mut self, sort_keys: bool) -> Self {
+        self.sort_keys = sort_keys;
+        self
+    }
+
+    /// Follows each value with a `// $.a[3].b` comment giving its path.
+    pub fn with_paths(mut self, paths: bool) -> Self {
+        self.paths = paths;
+        self
+    }
+}
+
+impl Value {
+    /// Formats the value on several lines as `config` says.
+    ///
+    /// `format!("{:#}", value)` is the same as `value.pretty(&PrettyConfig::default())`.
+    pub fn pretty<'a>(&'a self, config: &'a PrettyConfig) -> Pretty<'a> {
+        Pretty { value: self, config }
+    }
+}
+
+/// A [`Value`] to format with a [`PrettyConfig`], made by [`Value::pretty`].
+pub struct Pretty<'a> {
+    value: &'a Value,
+    config: &'a PrettyConfig,
+}
+
+impl Display for Pretty<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut printer = Printer {
+            config: self.config,
+            f,
+            path: String::from("$"),
+        };
+        printer.value(self.value, 0, true)
+    }
+}
+
+struct Printer<'a, 'f, 'g> {
+    config: &'a PrettyConfig,
+    f: &'f mut fmt::Formatter<'g>,
+    /// The path of the value being printed, kept only with `paths`.
+    path: String,
+}
+
+impl Printer<'_, '_, '_> {
+    fn newline(&mut self, depth: usize) -> fmt::Result {
+        self.f.write_char('\n')?;
+        for _ in 0..depth {
+            self.f.write_str(&self.config.indent)?;
+        }
+        Ok(())
+    }
+
+    /// Ends a line: the separator if more items follow, then the path.
+    fn end_line(&mut self, last: bool) -> fmt::Result {
+        if !last {
+            self.f.write_char(',')?;
+        }
+        self.annotate()
+    }
+
+    fn annotate(&mut self) -> fmt::Result {
+        if self.config.paths {
+            write!(self.f, "  // {}", self.path)?;
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, value: &Value, depth: usize, last: bool) -> fmt::Result {
+        let collapse = self.config.max_depth.is_some_and(|max| depth >= max);
+        match value {
+            Value::Arr(v) if !v.is_empty() => {
+                if collapse {
+                    write!(self.f, "[...{} items]", v.len())?;
+                    return self.end_line(last);
+                }
+                self.f.write_char('[')?;
+                self.annotate()?;
+                let shown = self.shown(v.len());
+                for (i, item) in v.iter().take(shown).enumerate() {
+                    self.newline(depth + 1)?;
+                    let len = self.push_path(|path| write!(path, "[{}]", i))?;
+                    self.value(item, depth + 1, i + 1 == v.len())?;
+                    self.path.truncate(len);
+                }
+                self.more(v.len() - shown, depth + 1)?;
+                self.newline(depth)?;
+                self.f.write_char(']')?;
+                if !last {
+                    self.f.write_char(',')?;
+                }
+                Ok(())
+            }
+            Value::Map(v) if !v.is_empty() => {
+                if collapse {
+                    write!(self.f, "{{...{} entries}}", v.len())?;
+                    return self.end_line(last);
+                }
+                self.f.write_char('{')?;
+                self.annotate()?;
+                let mut entries: Vec<_> = v.iter().collect();
+                if self.config.sort_keys {
+                    entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+                }
+                let shown = self.shown(entries.len());
+                for (i, (key, item)) in entries.iter().take(shown).enumerate() {
+                    self.newline(depth + 1)?;
+                    write!(self.f, "{}: ", key)?;
+                    let len = self.push_path(|path| match key {
+                        Value::Str(key) if is_ident(key) => write!(path, ".{}", key),
+                        Value::Str(key) => write!(path, "[{:?}]", key),
+                        key => write!(path, "[{}]", key),
+                    })?;
+                    self.value(item, depth + 1, i + 1 == entries.len())?;
+                    self.path.truncate(len);
+                }
+                self.more(entries.len() - shown, depth + 1)?;
+                self.newline(depth)?;
+                self.f.write_char('}')?;
+                if !last {
+                    self.f.write_char(',')?;
+                }
+                Ok(())
+            }
+            Value::Str(v) => {
+                match self.config.max_bytes {
+                    Some(max) if v.len() > max => {
+                        let mut end = max;
+                        while !v.is_char_boundary(end) {
+                            end -= 1;
+                        }
+                        write!(self.f, "{:?}... ({} bytes)", &v[..end], v.len())?;
+                    }
+                    _ => write!(self.f, "{:?}", v)?,
+                }
+                self.end_line(last)
+            }
+            Value::Raw(v) => {
+                let end = self.config.max_bytes.map_or(v.len(), |max| max.min(v.len()));
+                if self.config.raw_hex {
+                    self.f.write_str("hex(")?;
+                    for b in &v[..end] {
+                        write!(self.f, "{:02x}", b)?;
+                    }
+                    self.f.write_str(if end < v.len() { "...)" } else { ")" })?;
+                } else {
+                    write!(self.f, "{}", Value::Raw(v[..end].to_vec()))?;
+                    if end < v.len() {
+                        self.f.write_str("...")?;
+                    }
+                }
+                if end < v.len() {
+                    write!(self.f, " ({} bytes)", v.len())?;
+                }
+                self.end_line(last)
+            }
+            Value::Encoded(v) => {
+                let value = v.to_value().map_err(|_| fmt::Error)?;
+                self.f.write_str("encoded(")?;
+                self.value(&value, depth, true)?;
+                self.f.write_char(')')?;
+                if !last {
+                    self.f.write_char(',')?;
+                }
+                Ok(())
+            }
+            value => {
+                write!(self.f, "{}", value)?;
+                self.end_line(last)
+            }
+        }
+    }
+
+    /// How many of `len` items to print.
+    fn shown(&self, len: usize) -> usize {
+        self.config.max_items.map_or(len, |max| max.min(len))
+    }
+
+    fn more(&mut self, hidden: usize, depth: usize) -> fmt::Result {
+        if hidden > 0 {
+            self.newline(depth)?;
+            write!(self.f, "...{} more", hidden)?;
+        }
+        Ok(())
+    }
+
+    /// Appends a segment to the path, returning the length to truncate it back to.
+    fn push_path(&mut self, segment: impl FnOnce(&mut String) -> fmt::Result) -> Result<usize, fmt::Error> {
+        let len = self.path.len();
+        if self.config.paths {
+            segment(&mut self.path)?;
+        }
+        Ok(len)
+    }
+}
+
+pub(crate) fn is_ident(key: &str) -> bool {
+    key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::PrettyConfig;
+    use crate::Value;
+
+    fn sample() -> Value {
+        let mut inner = HashMap::new();
+        inner.insert(Value::Str("b".to_string()), Value::Raw(vec![0, 1, 0xff, b'x']));
+        inner.insert(Value::Str("long text".to_string()), Value::Str("héllo world".to_string()));
+        let mut map = HashMap::new();
+        map.insert(
+            Value::Str("a".to_string()),
+            Value::Arr(vec![Value::U8(1), Value::Varint(2), Value::Nil, Value::Map(inner)]),
+        );
+        map.insert(Value::U16(7), Value::Arr(vec![]));
+        Value::Map(map)
+    }
+
+    #[test]
+    fn test_pretty() {
+        let config = PrettyConfig::new().with_sorted_keys(true);
+        let text = sample().pretty(&config).to_string();
+        assert_eq!(
+            text,
+            "{\n  7u16: [],\n  \"a\": [\n    1u8,\n    2v,\n    nil,\n    {\n      \"b\": b\"\\0\\x01\\xffx\",\n      \"long text\": \"héllo world\"\n    }\n  ]\n}"
+        );
+        assert_eq!(text.parse::<Value>().unwrap(), sample());
+        assert_eq!(format!("{:#}", Value::Arr(vec![Value::Nil])), "[\n  nil\n]");
+
+        let config = PrettyConfig::new()
+            .with_indent("\t")
+            .with_sorted_keys(true)
+            .with_max_items(2)
+            .with_max_bytes(2)
+            .with_raw_hex(true)
+            .with_paths(true);
+        let Value::Map(map) = sample() else { unreachable!() };
+        let a = &map[&Value::Str("a".to_string())];
+        assert_eq!(
+            a.pretty(&config).to_string(),
+            "[  // $\n\t1u8,  // $[0]\n\t2v,  // $[1]\n\t...2 more\n]"
+        );
+        let Value::Arr(arr) = a else { unreachable!() };
+        assert_eq!(
+            arr[3].pretty(&config).to_string(),
+            "{  // $\n\t\"b\": hex(0001...) (4 bytes),  // $.b\n\t\"long text\": \"h\"... (12 bytes)  // $[\"long text\"]\n}"
+        );
+        let config = PrettyConfig::new().with_max_depth(1).with_sorted_keys(true);
+        assert_eq!(
+            sample().pretty(&config).to_string(),
+            "{\n  7u16: [],\n  \"a\": [...4 items]\n}"
+        );
+    }
+}
@@ -0,0 +1,538 @@
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+use crate::transcode::{Token, TokenReader};
+use crate::values::integer;
+use crate::{ErrorKind, HpError, HpResult, RawValue, Value, ValueRef};
+
+/// A path selecting values, parsed from JSONPath or from a JSON Pointer.
+///
+/// JSONPath starts with `$`, followed by:
+///
+/// - `.name` or `['name']` for a string key of a `Map`
+/// - `[3]` for an item of an `Arr`, `[-1]` counting from the end, or for an
+///   integer key of a `Map`
+/// - `.*` or `[*]` for every item or map value
+/// - `[start:end:step]` for a slice of an `Arr`, each part optional, the
+///   step positive
+/// - `[?(@.price > 100)]` for the items whose relative path has a value
+///   comparing true with a literal, by `==`, `!=`, `<`, `<=`, `>` or `>=`;
+///   `[?(@.name)]` for the items where it exists. Numbers compare by value
+///   across types, the literal is written in the text notation of `Value`
+///   (`100`, `1.5`, `"a"`, `true`, `nil`), or as `'a'` or `null`
+///
+/// A JSON Pointer is empty or starts with `/`, like `/items/0/price`, with
+/// `~1` for `/` and `~0` for `~` in keys. A step is a string key of a `Map`,
+/// or the index of an item of an `Arr`.
+///
+/// [`Query::select`] runs on a `Value`, [`Query::select_encoded`] on an
+/// encoded message without decoding the parts the path does not reach.
+#[derive(Debug, Clone)]
+pub struct Query {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Key(String),
+    Index(i64),
+    Wildcard,
+    Slice(Option<i64>, Option<i64>, i64),
+    Filter(Filter),
+}
+
+#[derive(Debug, Clone)]
+struct Filter {
+    path: Vec<Segment>,
+    test: Option<(Op, Value)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Query {
+    pub fn parse(path: &str) -> HpResult<Query> {
+        let segments = if path.is_empty() || path.starts_with('/') {
+            pointer(path)
+        } else {
+            let mut parser = Parser { src: path, pos: 0 };
+            parser.expect('$')?;
+            let segments = parser.path()?;
+            if parser.pos != path.len() {
+                return Err(parser.error("unexpected character"));
+            }
+            segments
+        };
+        Ok(Query { segments })
+    }
+
+    /// The values the path reaches in `value`.
+    pub fn select<'v>(&self, value: &'v Value) -> Vec<&'v Value> {
+        let mut out = Vec::new();
+        select(&self.segments, value, &mut out);
+        out
+    }
+
+    /// The values the path reaches in a message of a single value, such as
+    /// the output of `to_vec`. Subtrees off the path are skipped, and strings
+    /// and raw bytes are borrowed from `buf`.
+    pub fn select_encoded<'a>(&self, buf: &'a [u8]) -> HpResult<Vec<ValueRef<'a>>> {
+        let mut reader = TokenReader::new(buf)?;
+        let mut out = Vec::new();
+        stream(&mut reader, &self.segments, &mut out)?;
+        reader.finish()?;
+        Ok(out)
+    }
+}
+
+impl FromStr for Query {
+    type Err = HpError;
+
+    fn from_str(s: &str) -> HpResult<Query> {
+        Query::parse(s)
+    }
+}
+
+impl Value {
+    /// The values that `path` reaches, see [`Query`].
+    pub fn query(&self, path: &str) -> HpResult<Vec<&Value>> {
+        Ok(Query::parse(path)?.select(self))
+    }
+}
+
+impl RawValue {
+    /// The values that `path` reaches, decoding only those, see [`Query`].
+    pub fn query(&self, path: &str) -> HpResult<Vec<ValueRef<'_>>> {
+        Query::parse(path)?.select_encoded(self.as_bytes())
+    }
+}
+
+impl Segment {
+    /// Whether item `i` of an `Arr` of `len` items is taken, before any filter.
+    fn takes_item(&self, i: usize, len: usize) -> bool {
+        let resolve = |v: i64| if v < 0 { v + len as i64 } else { v };
+        match *self {
+            Segment::Key(ref key) => key.parse::<usize>() == Ok(i),
+            Segment::Index(v) => resolve(v) == i as i64,
+            Segment::Wildcard | Segment::Filter(_) => true,
+            Segment::Slice(start, end, step) => {
+                let start = start.map_or(0, resolve).max(0);
+                let end = end.map_or(len as i64, resolve);
+                let i = i as i64;
+                start <= i && i < end && (i - start) % step == 0
+            }
+        }
+    }
+
+    /// Whether the entry of `key` in a `Map` is taken, before any filter.
+    fn takes_entry(&self, key: &Value) -> bool {
+        match self {
+            Segment::Key(name) => key.as_str() == Some(name),
+            Segment::Index(v) => integer(key) == Some(*v as i128),
+            Segment::Wildcard | Segment::Filter(_) => true,
+            Segment::Slice(..) => false,
+        }
+    }
+}
+
+impl Filter {
+    fn test<'v>(&self, mut found: impl Iterator<Item = &'v Value>) -> bool {
+        match &self.test {
+            None => found.next().is_some(),
+            Some((op, literal)) => found.any(|v| op.holds(v, literal)),
+        }
+    }
+}
+
+impl Op {
+    fn holds(self, value: &Value, literal: &Value) -> bool {
+        let order = compare(value, literal);
+        match self {
+            Op::Eq => order == Some(Ordering::Equal),
+            Op::Ne => order != Some(Ordering::Equal),
+            Op::Lt => order == Some(Ordering::Less),
+            Op::Le => matches!(order, Some(Ordering::Less | Ordering::Equal)),
+            Op::Gt => order == Some(Ordering::Greater),
+            Op::Ge => matches!(order, Some(Ordering::Greater | Ordering::Equal)),
+        }
+    }
+}
+
+/// Numbers by value whatever their types, strings, raw bytes and booleans
+/// among themselves; anything else is only equal or not.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    if let (Some(a), Some(b)) = (integer(a), integer(b)) {
+        return Some(a.cmp(&b));
+    }
+    if let (Some(a), Some(b)) = (a.as_f64(), b.as_f64()) {
+        return a.partial_cmp(&b);
+    }
+    match (a, b) {
+        (Value::Str(_), Value::Str(_))
+        | (Value::Raw(_), Value::Raw(_))
+        | (Value::Bool(_), Value::Bool(_)) => Some(a.cmp(b)),
+        _ if a == b => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+fn select<'v>(segments: &[Segment], value: &'v Value, out: &mut Vec<&'v Value>) {
+    let Some((first, rest)) = segments.split_first() else {
+        out.push(value);
+        return;
+    };
+    let passes = |item: &Value| match first {
+        Segment::Filter(filter) => {
+            let mut found = Vec::new();
+            select(&filter.path, item, &mut found);
+            filter.test(found.into_iter())
+        }
+        _ => true,
+    };
+    match (first, value) {
+        (Segment::Key(key), Value::Map(map)) => {
+            if let Some(item) = map.get(&Value::from(key.as_str())) {
+                select(rest, item, out);
+            }
+        }
+        (Segment::Index(v), Value::Map(map)) => {
+            if let Some(item) = map.get(&Value::Varint(*v)) {
+                select(rest, item, out);
+            }
+        }
+        (_, Value::Map(map)) => {
+            for (key, item) in map {
+                if first.takes_entry(key) && passes(item) {
+                    select(rest, item, out);
+                }
+            }
+        }
+        (_, Value::Arr(arr)) => {
+            for (i, item) in arr.iter().enumerate() {
+                if first.takes_item(i, arr.len()) && passes(item) {
+                    select(rest, item, out);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Reads exactly one value, collecting what `segments` reach in it.
+fn stream<'a>(reader: &mut TokenReader<'a>, segments: &[Segment], out: &mut Vec<ValueRef<'a>>) -> HpResult<()> {
+    let Some((first, rest)) = segments.split_first() else {
+        out.push(reader.value()?);
+        return Ok(());
+    };
+    match reader.next()? {
+        Token::Arr(len) => {
+            for i in 0..len {
+                if first.takes_item(i, len) && passes(reader, first)? {
+                    stream(reader, rest, out)?;
+                } else {
+                    reader.skip()?;
+                }
+            }
+        }
+        Token::Map(pairs) => {
+            for _ in 0..pairs {
+                let key = reader.value()?.into_owned();
+                if first.takes_entry(&key) && passes(reader, first)? {
+                    stream(reader, rest, out)?;
+                } else {
+                    reader.skip()?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Runs the filter of `segment` on the next value, leaving it unread.
+fn passes(reader: &mut TokenReader, segment: &Segment) -> HpResult<bool> {
+    let Segment::Filter(filter) = segment else {
+        return Ok(true);
+    };
+    let mark = reader.mark();
+    let mut found = Vec::new();
+    stream(reader, &filter.path, &mut found)?;
+    reader.reset(mark);
+    let found: Vec<Value> = found.into_iter().map(ValueRef::into_owned).collect();
+    Ok(filter.test(found.iter()))
+}
+
+fn pointer(path: &str) -> Vec<Segment> {
+    path.split('/')
+        .skip(1)
+        .map(|key| Segment::Key(key.replace("~1", "/").replace("~0", "~")))
+        .collect()
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, desc: &str) -> HpError {
+        HpError::from((
+            ErrorKind::ParseError,
+            "query parse error",
+            format!("{} at {}", desc, self.pos),
+        ))
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_ws(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn expect(&mut self, c: char) -> HpResult<()> {
+        self.skip_ws();
+        match self.bump() {
+            Some(v) if v == c => Ok(()),
+            _ => Err(self.error(&format!("expected '{}'", c))),
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let start = self.pos;
+        while self.peek().is_some_and(&f) {
+            self.bump();
+        }
+        &self.src[start..self.pos]
+    }
+
+    /// Reads segments for as long as they follow.
+    fn path(&mut self) -> HpResult<Vec<Segment>> {
+        let mut segments = Vec::new();
+        loop {
+            match self.peek() {
+                Some('.') => {
+                    self.bump();
+                    if self.peek() == Some('*') {
+                        self.bump();
+                        segments.push(Segment::Wildcard);
+                        continue;
+                    }
+                    let key = self.take_while(|c| c.is_alphanumeric() || c == '_' || c == '-');
+                    if key.is_empty() {
+                        return Err(self.error("expected a key"));
+                    }
+                    segments.push(Segment::Key(key.to_string()));
+                }
+                Some('[') => {
+                    self.bump();
+                    self.skip_ws();
+                    let segment = match self.peek() {
+                        Some('*') => {
+                            self.bump();
+                            Segment::Wildcard
+                        }
+                        Some(quote @ ('\'' | '"')) => {
+                            self.bump();
+                            Segment::Key(self.quoted(quote)?)
+                        }
+                        Some('?') => {
+                            self.bump();
+                            self.expect('(')?;
+                            let filter = self.filter()?;
+                            self.expect(')')?;
+                            Segment::Filter(filter)
+                        }
+                        _ => self.index()?,
+                    };
+                    self.expect(']')?;
+                    segments.push(segment);
+                }
+                _ => return Ok(segments),
+            }
+        }
+    }
+
+    fn index(&mut self) -> HpResult<Segment> {
+        let text = self.take_while(|c| c == '-' || c == ':' || c == ' ' || c.is_ascii_digit());
+        let bad = || self.error(&format!("bad index {:?}", text));
+        let part = |s: &str| match s.trim() {
+            "" => Ok(None),
+            s => s.parse::<i64>().map(Some).map_err(|_| bad()),
+        };
+        let parts: Vec<&str> = text.split(':').collect();
+        match parts[..] {
+            [index] => part(index)?.map(Segment::Index).ok_or_else(bad),
+            [start, end] => Ok(Segment::Slice(part(start)?, part(end)?, 1)),
+            [start, end, step] => match part(step)?.unwrap_or(1) {
+                step if step > 0 => Ok(Segment::Slice(part(start)?, part(end)?, step)),
+                _ => Err(self.error("slice step must be positive")),
+            },
+            _ => Err(bad()),
+        }
+    }
+
+    /// Reads `@path`, then an optional comparison with a literal.
+    fn filter(&mut self) -> HpResult<Filter> {
+        self.expect('@')?;
+        let path = self.path()?;
+        self.skip_ws();
+        let op = match self.take_while(|c| "=!<>".contains(c)) {
+            "" => return Ok(Filter { path, test: None }),
+            "==" => Op::Eq,
+            "!=" => Op::Ne,
+            "<" => Op::Lt,
+            "<=" => Op::Le,
+            ">" => Op::Gt,
+            ">=" => Op::Ge,
+            op => return Err(self.error(&format!("bad operator {}", op))),
+        };
+        self.skip_ws();
+        let literal = match self.peek() {
+            Some('\'') => {
+                self.bump();
+                Value::Str(self.quoted('\'')?)
+            }
+            _ => {
+                let start = self.pos;
+                let mut quoted = false;
+                let mut escaped = false;
+                while let Some(c) = self.peek() {
+                    if !quoted && c == ')' {
+                        break;
+                    }
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        '"' => quoted = !quoted,
+                        _ => {}
+                    }
+                    self.bump();
+                }
+                match self.src[start..self.pos].trim() {
+                    "null" => Value::Nil,
+                    text => text.parse::<Value>()?,
+                }
+            }
+        };
+        Ok(Filter { path, test: Some((op, literal)) })
+    }
+
+    /// Reads up to the closing `quote`, taking `\` before any character.
+    fn quoted(&mut self, quote: char) -> HpResult<String> {
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                Some('\\') => out.extend(self.bump()),
+                Some(c) if c == quote => return Ok(out),
+                Some(c) => out.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{to_vec, ErrorKind, Query, RawValue, Value, ValueRef};
+
+    fn sample() -> Value {
+        r#"{
+            items: [
+                {name: "a", price: 50u16, tags: ["x"]},
+                {name: "b", price: 150u32},
+                {name: "c", price: 250.5f64, tags: []},
+                {name: "d/e", price: 100u8}
+            ],
+            7u8: "seven",
+            "a key": nil
+        }"#
+        .parse()
+        .unwrap()
+    }
+
+    fn names(found: Vec<&Value>) -> Vec<&str> {
+        let mut names: Vec<&str> = found.iter().filter_map(|v| v.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_query() {
+        let data = sample();
+        assert_eq!(names(data.query("$.items[*].name").unwrap()), ["a", "b", "c", "d/e"]);
+        assert_eq!(names(data.query("$.items[?(@.price > 100)].name").unwrap()), ["b", "c"]);
+        assert_eq!(names(data.query("$.items[?(@.price == 100)].name").unwrap()), ["d/e"]);
+        assert_eq!(names(data.query("$.items[?(@.name != 'a')].name").unwrap()), ["b", "c", "d/e"]);
+        assert_eq!(names(data.query("$.items[?(@.tags)].name").unwrap()), ["a", "c"]);
+        assert_eq!(names(data.query("$.items[1:3].name").unwrap()), ["b", "c"]);
+        assert_eq!(names(data.query("$.items[::2].name").unwrap()), ["a", "c"]);
+        assert_eq!(names(data.query("$.items[-1]['name']").unwrap()), ["d/e"]);
+        assert_eq!(names(data.query("$[7]").unwrap()), ["seven"]);
+        assert_eq!(data.query("$['a key']").unwrap(), [&Value::Nil]);
+        assert_eq!(data.query("/items/2/price").unwrap(), [&Value::F64(250.5)]);
+        assert_eq!(data.query("").unwrap(), [&data]);
+        assert!(data.query("$.items[9].name").unwrap().is_empty());
+
+        for path in ["items", "$.items[", "$.items[::0]", "$.items[?(@.price ~ 1)]", "$.items[a]"] {
+            assert_eq!(data.query(path).unwrap_err().kind(), ErrorKind::ParseError, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_query_encoded() {
+        let data = sample();
+        let bytes = to_vec(&data).unwrap();
+        for path in [
+            "$.items[*].name",
+            "$.items[?(@.price >= 150)]",
+            "$.items[1:]",
+            "$.items[?(@.tags)].tags",
+            "$[7]",
+            "/items/3/name",
+        ] {
+            let query: Query = path.parse().unwrap();
+            let mut expected: Vec<Value> = query.select(&data).into_iter().cloned().collect();
+            let mut found: Vec<Value> = query
+                .select_encoded(&bytes)
+                .unwrap()
+                .into_iter()
+                .map(ValueRef::into_owned)
+                .collect();
+            expected.sort();
+            found.sort();
+            assert_eq!(found, expected, "{}", path);
+        }
+
+        let raw = RawValue::from_value(&data).unwrap();
+        assert_eq!(raw.query("$.items[0].name").unwrap(), [ValueRef::Str("a")]);
+
+        // A value nested too deep is an error, not a stack overflow.
+        let mut deep = vec![0];
+        for _ in 0..200_000 {
+            deep.extend_from_slice(&[16, 2]);
+        }
+        deep.push(0);
+        assert!("$".parse::<Query>().unwrap().select_encoded(&deep).is_err());
+        assert!("$[0][0]".parse::<Query>().unwrap().select_encoded(&deep).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use std::fmt;
+
+use algorithm::buf::BtMut;
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+
+use crate::decode::{decode_field_ref, decode_str_table_ref, decode_varint};
+use crate::encode::{
+    encode_field, encode_string, encode_varint, varint_bytes, BorrowedTable, StrTable,
+};
+use crate::transcode::check_depth;
+use crate::{Buffer, BufferRef, ErrorKind, HpError, HpResult, Value, ValueType};
+
+/// The newtype struct name a [`RawValue`] serializes as, the hcproto
+/// serializer and deserializer splice and capture the bytes behind it.
+pub(crate) const TOKEN: &str = "$hcproto::private::RawValue";
+
+/// A value kept in its encoded form, for fields that are forwarded unread.
+///
+/// The bytes are a message holding a single field: its own string table
+/// followed by the field, as [`crate::to_vec`] writes one value. Capturing a
+/// `RawValue` from a message copies the field without building a [`Value`],
+/// and encoding one splices the bytes back in, only rewriting the string
+/// indices when they differ in the enclosing string table.
+///
+/// It is a serde type, so a struct field of type `RawValue` or
+/// `Box<RawValue>` is carried through untouched, and so is a
+/// `Value::Encoded`. Formats other than hcproto see it as the bytes of the
+/// message.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct RawValue {
+    bytes: Vec<u8>,
+    /// Offset of the field, after the string table.
+    body: usize,
+}
+
+impl RawValue {
+    /// Takes a message of a single field, checking that it is well formed
+    /// and nested no deeper than 128 containers.
+    pub fn from_vec(bytes: Vec<u8>) -> HpResult<RawValue> {
+        let mut buf = BufferRef::new(&bytes);
+        decode_str_table_ref(&mut buf)?;
+        let body = bytes.len() - buf.remaining();
+        let str_len = buf.str_arr.len();
+        walk_field(
+            &mut buf.buf,
+            &mut |idx| {
+                if (idx as usize) < str_len {
+                    Ok(idx)
+                } else {
+                    Err(HpError::from((ErrorKind::BufferOverMaxError, "str idx over table")))
+                }
+            },
+            &mut |_| {},
+            0,
+        )?;
+        if !buf.buf.is_empty() {
+            return Err(HpError::from((ErrorKind::ParseError, "left buffer")));
+        }
+        Ok(RawValue { bytes, body })
+    }
+
+    /// Encodes `value` on its own.
+    pub fn from_value(value: &Value) -> HpResult<RawValue> {
+        let mut buffer = Buffer::new();
+        encode_field(&mut buffer, value)?;
+        let mut bytes = Vec::new();
+        buffer.export_to(&mut bytes)?;
+        let body = bytes.len() - buffer.len();
+        Ok(RawValue { bytes, body })
+    }
+
+    /// Decodes the bytes into a [`Value`].
+    pub fn to_value(&self) -> HpResult<Value> {
+        let mut buf = BufferRef::new(&self.bytes);
+        decode_str_table_ref(&mut buf)?;
+        Ok(decode_field_ref(&mut buf)?.into_owned())
+    }
+
+    /// The type of the field, read from its first byte.
+    pub fn get_type(&self) -> ValueType {
+        ValueType::from(self.bytes[self.body])
+    }
+
+    /// The string table followed by the field.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Copies the next field of `input` along with the strings of `str_arr` it uses.
+    pub(crate) fn capture<S: AsRef<str>>(input: &mut &[u8], str_arr: &[S]) -> HpResult<RawValue> {
+        let mut table = BorrowedTable::default();
+        let mut body = Vec::new();
+        walk_field(
+            input,
+            &mut |idx| match str_arr.get(idx as usize) {
+                Some(val) => Ok(table.intern(val.as_ref())),
+                None => Err(HpError::from((ErrorKind::BufferOverMaxError, "str idx over table"))),
+            },
+            &mut |val| body.extend_from_slice(val),
+            0,
+        )?;
+
+        let mut bytes = Vec::with_capacity(body.len() + 8 * table.str_arr.len() + 2);
+        encode_varint(&mut bytes, &Value::U16(table.str_arr.len() as u16))?;
+        for val in &table.str_arr {
+            encode_string(&mut bytes, val)?;
+        }
+        let body_at = bytes.len();
+        bytes.extend_from_slice(&body);
+        Ok(RawValue { bytes, body: body_at })
+    }
+}
+
+impl fmt::Debug for RawValue {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "encoded({:?})", &self.bytes)
+    }
+}
+
+/// Writes the field of the message `bytes` into `out`, interning its strings
+/// through `table`.
+///
+/// When every string lands on the index it already had, the field is copied
+/// as is, otherwise it is walked once to rewrite the indices.
+pub(crate) fn splice<'a, W: BtMut, T: StrTable<'a>>(
+    bytes: &'a [u8],
+    out: &mut W,
+    table: &mut T,
+) -> HpResult<()> {
+    let mut buf = BufferRef::new(bytes);
+    decode_str_table_ref(&mut buf)?;
+    let map: Vec<u16> = buf.str_arr.iter().map(|val| table.intern(val)).collect();
+    if map.iter().enumerate().all(|(i, idx)| *idx as usize == i) {
+        out.put_slice(buf.buf);
+        return Ok(());
+    }
+    walk_field(
+        &mut buf.buf,
+        &mut |idx| match map.get(idx as usize) {
+            Some(idx) => Ok(*idx),
+            None => Err(HpError::from((ErrorKind::BufferOverMaxError, "str idx over table"))),
+        },
+        &mut |val| {
+            out.put_slice(val);
+        },
+        0,
+    )
+}
+
+pub(crate) fn take<'a>(input: &mut &'a [u8], len: usize) -> HpResult<&'a [u8]> {
+    if input.len() < len {
+        return Err(HpError::from((ErrorKind::NoLeftSpaceError, "space error")));
+    }
+    let (val, left) = input.split_at(len);
+    *input = left;
+    Ok(val)
+}
+
+/// Reads a varint length, returning it with the bytes it was written in.
+fn take_len<'a>(input: &mut &'a [u8]) -> HpResult<(usize, &'a [u8])> {
+    let start = *input;
+    let len = match decode_varint(input)? {
+        Value::Varint(len) if len >= 0 => len as usize,
+        _ => return Err(HpError::from((ErrorKind::ParseError, "negative length"))),
+    };
+    Ok((len, &start[..start.len() - input.len()]))
+}
+
+/// Walks one field of `input`, handing its bytes to `emit` unchanged except
+/// for string indices, which are passed through `map_idx` and re-encoded.
+/// `depth` counts the containers the field is in.
+fn walk_field<M, E>(input: &mut &[u8], map_idx: &mut M, emit: &mut E, depth: usize) -> HpResult<()>
+where
+    M: FnMut(u16) -> HpResult<u16>,
+    E: FnMut(&[u8]),
+{
+    check_depth(depth)?;
+    let start = *input;
+    let pattern = ValueType::from(take(input, 1)?[0]);
+    let fixed = match pattern {
+        ValueType::Nil => 0,
+        ValueType::Bool | ValueType::U8 | ValueType::I8 => 1,
+        ValueType::U16 | ValueType::I16 => 2,
+        ValueType::U32 | ValueType::I32 | ValueType::F32 => 4,
+        ValueType::U64 | ValueType::I64 | ValueType::F64 => 8,
+        ValueType::Varint => {
+            decode_varint(input)?;
+            emit(&start[..start.len() - input.len()]);
+            return Ok(());
+        }
+        ValueType::Str | ValueType::Raw => {
+            let (len, _) = take_len(input)?;
+            take(input, len)?;
+            emit(&start[..start.len() - input.len()]);
+            return Ok(());
+        }
+        ValueType::StrIdx => {
+            let idx = match decode_varint(input)? {
+                Value::Varint(idx) if (0..=u16::MAX as i64).contains(&idx) => idx as u16,
+                _ => return Err(HpError::from((ErrorKind::ParseError, "bad str idx"))),
+            };
+            let mut data = [0u8; 10];
+            let len = varint_bytes(&Value::U16(map_idx(idx)?), &mut data);
+            emit(&[ValueType::StrIdx as u8]);
+            emit(&data[..len]);
+            return Ok(());
+        }
+        ValueType::Arr | ValueType::Map => {
+            let (len, head) = take_len(input)?;
+            emit(&start[..1]);
+            emit(head);
+            for _ in 0..len {
+                walk_field(input, map_idx, emit, depth + 1)?;
+            }
+            return Ok(());
+        }
+    };
+    take(input, fixed)?;
+    emit(&start[..1 + fixed]);
+    Ok(())
+}
+
+struct Bytes<'a>(&'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+impl Serialize for RawValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct(TOKEN, &Bytes(&self.bytes))
+    }
+}
+
+impl<'de> Deserialize<'de> for RawValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RawValueVisitor;
+
+        impl<'de> Visitor<'de> for RawValueVisitor {
+            type Value = RawValue;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an encoded hcproto value")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<RawValue, E> {
+                self.visit_byte_buf(v.to_vec())
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<RawValue, E> {
+                RawValue::from_vec(v).map_err(de::Error::custom)
+            }
+
+            // Other formats hand over the newtype, holding the bytes as they
+            // wrote them, a byte string or a sequence of `u8`.
+            fn visit_newtype_struct<D: Deserializer<'de>>(self, deserializer: D) -> Result<RawValue, D::Error> {
+                deserializer.deserialize_byte_buf(self)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<RawValue, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+                while let Some(b) = seq.next_element()? {
+                    bytes.push(b);
+                }
+                self.visit_byte_buf(bytes)
+            }
+        }
+
+        deserializer.deserialize_newtype_struct(TOKEN, RawValueVisitor)
+    }
+}
@@ -1,30 +1,189 @@
+use std::io;
+
 use crate::{
     encode::{
-        append_and_align, encode_bool, encode_number, encode_str_idx, encode_sure_type, encode_type, encode_varint
-    }, Buffer, HpError, HpResult, Value, ValueType::{self}
+        append_and_align, encode_bool, encode_number, encode_str_idx, encode_string,
+        encode_sure_type, encode_type, encode_varint, encode_varint_padded, splice_encoded,
+        PADDED_VARINT_LEN,
+    },
+    raw_value::TOKEN, Buffer, HpError, HpResult, Value, ValueType::{self}
 };
+use algorithm::buf::{Bt, BtMut};
 use serde::ser::{self, Serialize};
 
 pub fn to_buffer<T>(value: &T) -> HpResult<Buffer>
 where
-    T: Serialize,
+    T: Serialize + ?Sized,
 {
-    let mut serializer = Serializer {
-        buf: Buffer::new(),
-    };
+    let mut serializer = Serializer::new(Buffer::new());
     value.serialize(&mut serializer)?;
-    serializer.buf.export()
+    serializer.into_buffer()
 }
 
+pub fn to_vec<T>(value: &T) -> HpResult<Vec<u8>>
+where
+    T: Serialize + ?Sized,
+{
+    let mut serializer = Serializer::new(Buffer::new());
+    value.serialize(&mut serializer)?;
+    let mut out = Vec::with_capacity(serializer.buf.len() + 16);
+    serializer.export_to(&mut out)?;
+    Ok(out)
+}
+
+pub fn to_writer<W, T>(writer: W, value: &T) -> HpResult<()>
+where
+    W: io::Write,
+    T: Serialize + ?Sized,
+{
+    let mut serializer = Serializer::new(Buffer::new());
+    value.serialize(&mut serializer)?;
+    serializer.export_to_writer(writer)
+}
+
+/// Serializes the arguments of the proto `name`, in the format of [`crate::encode_proto`].
+///
+/// `args` must serialize to an `Arr`: a tuple, a sequence, or a struct, whose
+/// fields are then written as positional arguments. Structs nested in the
+/// arguments use the default [`StructMode::Legacy`] layout, alternating field
+/// names and values, so dynamic clients can still read them by name.
+pub fn to_proto<T>(name: &str, args: &T) -> HpResult<Buffer>
+where
+    T: Serialize + ?Sized,
+{
+    let mut serializer = Serializer::new(Buffer::new());
+    serializer.proto_args = true;
+    args.serialize(&mut serializer)?;
+    if serializer.buf.chunk().first() != Some(&(ValueType::Arr as u8)) {
+        return Err(ser::Error::custom("proto args must be a tuple or struct"));
+    }
+    let mut out = Buffer::new();
+    encode_string(&mut out.buf, name)?;
+    serializer.export_to(&mut out.buf)?;
+    Ok(out)
+}
+
+/// Serializes values into a [`Buffer`] that can be reused between messages.
+///
+/// The string table of a message is written in front of its fields, so values
+/// are first serialized into the serializer's buffer and then exported with
+/// [`Serializer::export_to`] or [`Serializer::export_to_writer`]. Exporting
+/// clears the buffer but keeps its capacity for the next value.
 #[derive(Debug)]
 pub struct Serializer {
     buf: Buffer,
+    variant_mode: VariantMode,
+    struct_mode: StructMode,
+    /// Set by [`to_proto`], the struct at the very start of the body holds
+    /// the arguments and is written positionally.
+    proto_args: bool,
+    /// Set while a [`crate::RawValue`] hands over its bytes, which are then
+    /// spliced in instead of written as `Raw`.
+    encoded: bool,
+}
+
+/// How the serializer lays out a struct.
+///
+/// The deserializer reads a `Map` as a named struct whatever its mode, and
+/// an `Arr` with the layout given to
+/// [`crate::Deserializer::with_struct_mode`], so a positional struct is
+/// read back with the same mode it was written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StructMode {
+    /// A `Map` from field name to value, what generic decoders expect.
+    Named,
+    /// An `Arr` of the values only, in declaration order. Fields can not be
+    /// skipped, since their position is their name.
+    Positional,
+    /// An `Arr` of alternating field names and values, as older versions
+    /// wrote, and still the default.
+    #[default]
+    Legacy,
+}
+
+/// How the serializer identifies an enum variant.
+///
+/// Unit variants are written as the identifier alone, every other variant as a
+/// one entry map from the identifier to its content. The deserializer accepts
+/// both modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VariantMode {
+    /// The variant name, interned in the string table.
+    #[default]
+    Name,
+    /// The `variant_index` as a varint, smaller but tied to declaration order.
+    Index,
 }
 
 impl Serializer {
-    /// Creates the serializer.
-    pub fn new(buf: Buffer) -> Self {
-        Serializer { buf }
+    /// Creates the serializer on top of `buf`, any content of `buf` is dropped.
+    pub fn new(mut buf: Buffer) -> Self {
+        buf.clear();
+        Serializer {
+            buf,
+            variant_mode: VariantMode::default(),
+            struct_mode: StructMode::default(),
+            proto_args: false,
+            encoded: false,
+        }
+    }
+
+    /// Chooses how structs are laid out, see [`StructMode`].
+    pub fn with_struct_mode(mut self, struct_mode: StructMode) -> Self {
+        self.struct_mode = struct_mode;
+        self
+    }
+
+    /// Chooses how enum variants are identified, see [`VariantMode`].
+    pub fn with_variant_mode(mut self, variant_mode: VariantMode) -> Self {
+        self.variant_mode = variant_mode;
+        self
+    }
+
+    /// Writes the identifier of a variant, as its name or its index.
+    fn serialize_variant(&mut self, variant_index: u32, variant: &'static str) -> HpResult<()> {
+        match self.variant_mode {
+            VariantMode::Name => encode_str_idx(&mut self.buf, variant)?,
+            VariantMode::Index => {
+                encode_sure_type(&mut self.buf, ValueType::Varint)?;
+                encode_varint(&mut self.buf, &Value::from(variant_index))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts a variant with content, written as a one entry map `{ variant: content }`.
+    fn begin_variant(&mut self, variant_index: u32, variant: &'static str) -> HpResult<()> {
+        encode_sure_type(&mut self.buf, ValueType::Map)?;
+        encode_varint(&mut self.buf, &Value::from(2u32))?;
+        self.serialize_variant(variant_index, variant)
+    }
+
+    /// Writes the serialized message into `out` and clears the serializer.
+    pub fn export_to<W: BtMut>(&mut self, out: &mut W) -> HpResult<()> {
+        self.buf.export_to(out)?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Writes the serialized message into `writer` and clears the serializer.
+    pub fn export_to_writer<W: io::Write>(&mut self, mut writer: W) -> HpResult<()> {
+        let mut head = Vec::new();
+        self.buf.export_str_table(&mut head)?;
+        writer.write_all(&head)?;
+        writer.write_all(self.buf.chunk())?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Returns the serialized message, the same as [`to_buffer`] would.
+    pub fn into_buffer(self) -> HpResult<Buffer> {
+        self.buf.export()
+    }
+
+    /// Gives back the underlying buffer so its allocation can be reused.
+    pub fn into_inner(self) -> Buffer {
+        self.buf
     }
 }
 
@@ -32,13 +191,13 @@ impl<'s> ser::Serializer for &'s mut Serializer {
     type Ok = ();
     type Error = HpError;
 
-    type SerializeSeq = Self;
+    type SerializeSeq = Compound<'s>;
     type SerializeTuple = Self;
     type SerializeTupleStruct = Self;
     type SerializeTupleVariant = Self;
-    type SerializeMap = Self;
-    type SerializeStruct = Self;
-    type SerializeStructVariant = Self;
+    type SerializeMap = Compound<'s>;
+    type SerializeStruct = Struct<'s>;
+    type SerializeStructVariant = Struct<'s>;
 
     fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
         encode_sure_type(&mut self.buf, ValueType::Bool)?;
@@ -89,17 +248,24 @@ impl<'s> ser::Serializer for &'s mut Serializer {
     }
 
     fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
+        if v > i64::MAX as u64 {
+            encode_sure_type(&mut self.buf, ValueType::U64)?;
+            encode_number(&mut self.buf, &Value::U64(v))?;
+            return Ok(());
+        }
         encode_sure_type(&mut self.buf, ValueType::Varint)?;
         encode_varint(&mut self.buf, &Value::U64(v))?;
         Ok(())
     }
 
     fn serialize_f32(self, v: f32) -> Result<Self::Ok, Self::Error> {
+        encode_sure_type(&mut self.buf, ValueType::F32)?;
         encode_number(&mut self.buf, &Value::F32(v))?;
         Ok(())
     }
 
     fn serialize_f64(self, v: f64) -> Result<Self::Ok, Self::Error> {
+        encode_sure_type(&mut self.buf, ValueType::F64)?;
         encode_number(&mut self.buf, &Value::F64(v))?;
         Ok(())
     }
@@ -110,11 +276,14 @@ impl<'s> ser::Serializer for &'s mut Serializer {
     }
 
     fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
-        encode_str_idx(&mut self.buf, &v)?;
+        encode_str_idx(&mut self.buf, v)?;
         Ok(())
     }
 
     fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
+        if std::mem::take(&mut self.encoded) {
+            return splice_encoded(&mut self.buf, v);
+        }
         encode_sure_type(&mut self.buf, ValueType::Raw)?;
         encode_varint(&mut self.buf, &Value::U16(v.len() as u16))?;
         append_and_align(&mut self.buf, v)?;
@@ -144,20 +313,21 @@ impl<'s> ser::Serializer for &'s mut Serializer {
     fn serialize_unit_variant(
         self,
         _name: &'static str,
-        _variant_index: u32,
+        variant_index: u32,
         variant: &'static str,
     ) -> Result<Self::Ok, Self::Error> {
-        self.serialize_str(variant)
+        self.serialize_variant(variant_index, variant)
     }
 
     fn serialize_newtype_struct<T>(
         self,
-        _name: &'static str,
+        name: &'static str,
         value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
         T: ?Sized + Serialize,
     {
+        self.encoded = name == TOKEN;
         // encode_sure_type(&mut self.buf, ValueType::Kv)?;
         // encode_str_idx(&mut self.buf, &name)?;
         // encode_varint(&mut self.buf, &Value::from(1))?;
@@ -167,27 +337,26 @@ impl<'s> ser::Serializer for &'s mut Serializer {
     fn serialize_newtype_variant<T>(
         self,
         _name: &'static str,
-        _variant_index: u32,
-        _variant: &'static str,
+        variant_index: u32,
+        variant: &'static str,
         value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        encode_sure_type(&mut self.buf, ValueType::Arr)?;
-        // encode_str_idx_not_type(&mut self.buf, &variant)?;
-        encode_varint(&mut self.buf, &Value::from(1))?;
+        self.begin_variant(variant_index, variant)?;
         value.serialize(self)
     }
 
     fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
         encode_sure_type(&mut self.buf, ValueType::Arr)?;
-        encode_varint(&mut self.buf, &Value::from(len.unwrap_or(0) as u32))?;
-        Ok(self)
+        Compound::new(self, len.map(|len| len as u32))
     }
 
     fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
-        self.serialize_seq(Some(len))
+        encode_sure_type(&mut self.buf, ValueType::Arr)?;
+        encode_varint(&mut self.buf, &Value::from(len as u32))?;
+        Ok(self)
     }
 
     fn serialize_tuple_struct(
@@ -195,23 +364,23 @@ impl<'s> ser::Serializer for &'s mut Serializer {
         _name: &'static str,
         len: usize,
     ) -> Result<Self::SerializeTupleStruct, Self::Error> {
-        self.serialize_seq(Some(len))
+        self.serialize_tuple(len)
     }
 
     fn serialize_tuple_variant(
         self,
         _name: &'static str,
-        _variant_index: u32,
+        variant_index: u32,
         variant: &'static str,
         len: usize,
     ) -> Result<Self::SerializeTupleVariant, Self::Error> {
-        self.serialize_tuple_struct(variant, len)
+        self.begin_variant(variant_index, variant)?;
+        self.serialize_tuple(len)
     }
 
-    fn serialize_map(self, len: Option<usize>) -> Result<Self, Self::Error> {
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
         encode_sure_type(&mut self.buf, ValueType::Map)?;
-        encode_varint(&mut self.buf, &Value::from((len.unwrap_or(0) * 2) as u32))?;
-        Ok(self)
+        Compound::new(self, len.map(|len| (len * 2) as u32))
     }
 
     fn serialize_struct(
@@ -219,21 +388,38 @@ impl<'s> ser::Serializer for &'s mut Serializer {
         _name: &'static str,
         len: usize,
     ) -> Result<Self::SerializeStruct, Self::Error> {
-        encode_sure_type(&mut self.buf, ValueType::Arr)?;
-        encode_varint(&mut self.buf, &Value::from((len * 2) as u32))?;
-        Ok(self)
+        if self.proto_args && self.buf.is_empty() {
+            encode_sure_type(&mut self.buf, ValueType::Arr)?;
+            encode_varint(&mut self.buf, &Value::from(len as u32))?;
+            return Ok(Struct { ser: self, struct_mode: StructMode::Positional });
+        }
+        match self.struct_mode {
+            StructMode::Named => {
+                encode_sure_type(&mut self.buf, ValueType::Map)?;
+                encode_varint(&mut self.buf, &Value::from((len * 2) as u32))?;
+            }
+            StructMode::Positional => {
+                encode_sure_type(&mut self.buf, ValueType::Arr)?;
+                encode_varint(&mut self.buf, &Value::from(len as u32))?;
+            }
+            StructMode::Legacy => {
+                encode_sure_type(&mut self.buf, ValueType::Arr)?;
+                encode_varint(&mut self.buf, &Value::from((len * 2) as u32))?;
+            }
+        }
+        let struct_mode = self.struct_mode;
+        Ok(Struct { ser: self, struct_mode })
     }
 
     fn serialize_struct_variant(
         self,
-        _name: &'static str,
-        _variant_index: u32,
-        _variant: &'static str,
-        _len: usize,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        len: usize,
     ) -> Result<Self::SerializeStructVariant, Self::Error> {
-        Err(ser::Error::custom(
-            "serialize_struct_variant is not supported",
-        ))
+        self.begin_variant(variant_index, variant)?;
+        self.serialize_struct(name, len)
     }
 
     fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
@@ -251,7 +437,48 @@ impl<'s> ser::Serializer for &'s mut Serializer {
     }
 }
 
-impl ser::SerializeSeq for &mut Serializer {
+/// A sequence or map in progress.
+///
+/// When the length was not given up front, `Compound` reserves a fixed-width
+/// length, counts what gets written and patches the real length in at `end`.
+pub struct Compound<'s> {
+    ser: &'s mut Serializer,
+    /// Offset of the reserved length and the number of values written since.
+    patch: Option<(usize, u32)>,
+}
+
+impl<'s> Compound<'s> {
+    fn new(ser: &'s mut Serializer, len: Option<u32>) -> HpResult<Self> {
+        let patch = match len {
+            Some(len) => {
+                encode_varint(&mut ser.buf, &Value::from(len))?;
+                None
+            }
+            None => {
+                let offset = ser.buf.len();
+                ser.buf.put_slice(&[0; PADDED_VARINT_LEN]);
+                Some((offset, 0))
+            }
+        };
+        Ok(Compound { ser, patch })
+    }
+
+    fn count(&mut self) {
+        if let Some((_, ref mut count)) = self.patch {
+            *count += 1;
+        }
+    }
+
+    fn finish(self) -> HpResult<()> {
+        if let Some((offset, count)) = self.patch {
+            let out = &mut self.ser.buf.buf.as_mut()[offset..offset + PADDED_VARINT_LEN];
+            encode_varint_padded(out, &Value::from(count))?;
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_> {
     type Ok = ();
     type Error = HpError;
 
@@ -259,11 +486,12 @@ impl ser::SerializeSeq for &mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        value.serialize(&mut **self)
+        self.count();
+        value.serialize(&mut *self.ser)
     }
 
     fn end(self) -> Result<(), Self::Error> {
-        Ok(())
+        self.finish()
     }
 }
 
@@ -299,7 +527,7 @@ impl ser::SerializeTupleStruct for &mut Serializer {
     }
 }
 
-impl<'s> ser::SerializeTupleVariant for &mut Serializer {
+impl ser::SerializeTupleVariant for &mut Serializer {
     type Ok = ();
     type Error = HpError;
 
@@ -315,7 +543,7 @@ impl<'s> ser::SerializeTupleVariant for &mut Serializer {
     }
 }
 
-impl ser::SerializeMap for &mut Serializer {
+impl ser::SerializeMap for Compound<'_> {
     type Ok = ();
     type Error = HpError;
 
@@ -323,22 +551,52 @@ impl ser::SerializeMap for &mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        key.serialize(&mut **self)
+        self.count();
+        key.serialize(&mut *self.ser)
     }
 
     fn serialize_value<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        value.serialize(&mut **self)
+        self.count();
+        value.serialize(&mut *self.ser)
     }
 
     fn end(self) -> Result<(), Self::Error> {
+        self.finish()
+    }
+}
+
+/// A struct in progress, with the layout chosen for it.
+pub struct Struct<'s> {
+    ser: &'s mut Serializer,
+    struct_mode: StructMode,
+}
+
+impl Struct<'_> {
+    fn field<T>(&mut self, key: &'static str, value: &T) -> HpResult<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        if self.struct_mode != StructMode::Positional {
+            key.serialize(&mut *self.ser)?;
+        }
+        value.serialize(&mut *self.ser)
+    }
+
+    fn skip(&mut self, key: &'static str) -> HpResult<()> {
+        if self.struct_mode == StructMode::Positional {
+            return Err(ser::Error::custom(format!(
+                "positional struct can not skip field `{}`",
+                key
+            )));
+        }
         Ok(())
     }
 }
 
-impl ser::SerializeStruct for &mut Serializer {
+impl ser::SerializeStruct for Struct<'_> {
     type Ok = ();
     type Error = HpError;
 
@@ -346,9 +604,11 @@ impl ser::SerializeStruct for &mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        key.serialize(&mut **self)?;
-        value.serialize(&mut **self)?;
-        Ok(())
+        self.field(key, value)
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Self::Error> {
+        self.skip(key)
     }
 
     fn end(self) -> Result<(), Self::Error> {
@@ -356,7 +616,7 @@ impl ser::SerializeStruct for &mut Serializer {
     }
 }
 
-impl<'s> ser::SerializeStructVariant for &mut Serializer {
+impl ser::SerializeStructVariant for Struct<'_> {
     type Ok = ();
     type Error = HpError;
 
@@ -364,8 +624,11 @@ impl<'s> ser::SerializeStructVariant for &mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        key.serialize(&mut **self)?;
-        value.serialize(&mut **self)
+        self.field(key, value)
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Self::Error> {
+        self.skip(key)
     }
 
     fn end(self) -> Result<(), Self::Error> {
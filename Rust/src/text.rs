@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Write};
+use std::str::FromStr;
+
+use crate::transcode::MAX_DEPTH;
+use crate::{ErrorKind, HpError, HpResult, PrettyConfig, RawValue, Value};
+
+/// Writes the typed text notation, which [`FromStr`] reads back.
+///
+/// Every value keeps its exact type:
+///
+/// - `nil`, `true`, `false`
+/// - integers with a suffix naming their type: `3u8`, `-3i8`, `3u16` up to
+///   `3i64`, and `5v` for a `Varint`
+/// - floats with `f32` or `f64`: `1.5f32`, `NaNf64`, `-inff32`
+/// - strings as `"..."` and raw bytes as `b"..."`, with Rust escapes
+/// - `[a, b]` for an `Arr`, `{k: v}` for a `Map` with keys of any type
+/// - `encoded(...)` around the decoded content of a `Value::Encoded`
+///
+/// `{:#}` prints the same notation over several lines, see [`Value::pretty`].
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if f.alternate() {
+            return self.pretty(&PrettyConfig::default()).fmt(f);
+        }
+        match *self {
+            Value::Nil => f.write_str("nil"),
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::U8(v) => write!(f, "{}u8", v),
+            Value::I8(v) => write!(f, "{}i8", v),
+            Value::U16(v) => write!(f, "{}u16", v),
+            Value::I16(v) => write!(f, "{}i16", v),
+            Value::U32(v) => write!(f, "{}u32", v),
+            Value::I32(v) => write!(f, "{}i32", v),
+            Value::U64(v) => write!(f, "{}u64", v),
+            Value::I64(v) => write!(f, "{}i64", v),
+            Value::Varint(v) => write!(f, "{}v", v),
+            Value::F32(v) => write!(f, "{:?}f32", v),
+            Value::F64(v) => write!(f, "{:?}f64", v),
+            Value::Str(ref v) => write!(f, "{:?}", v),
+            Value::Raw(ref v) => write_raw(f, v),
+            Value::Arr(ref v) => {
+                f.write_char('[')?;
+                for (i, v) in v.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                f.write_char(']')
+            }
+            Value::Map(ref v) => {
+                f.write_char('{')?;
+                for (i, (k, v)) in v.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: {}", k, v)?;
+                }
+                f.write_char('}')
+            }
+            Value::Encoded(ref v) => match v.to_value() {
+                Ok(v) => write!(f, "encoded({})", v),
+                Err(_) => Err(fmt::Error),
+            },
+        }
+    }
+}
+
+fn write_raw(f: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    f.write_str("b\"")?;
+    for b in bytes {
+        match *b {
+            b'"' => f.write_str("\\\"")?,
+            b'\\' => f.write_str("\\\\")?,
+            b'\n' => f.write_str("\\n")?,
+            b'\r' => f.write_str("\\r")?,
+            b'\t' => f.write_str("\\t")?,
+            b'\0' => f.write_str("\\0")?,
+            0x20..=0x7e => f.write_char(*b as char)?,
+            _ => write!(f, "\\x{:02x}", b)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// Reads the typed text notation written by `Display`.
+///
+/// Whitespace is free, a trailing comma is accepted in arrays and maps and a
+/// map key may be a bare word, read as a `Str` (`{name: "hc"}`).
+/// An integer without a suffix reads as a `Varint`, or a `U64` when it does
+/// not fit an `i64`, and a number with a fraction or an exponent but no
+/// suffix as an `F64`. Nesting deeper than 128 containers is an error.
+impl FromStr for Value {
+    type Err = HpError;
+
+    fn from_str(s: &str) -> HpResult<Value> {
+        let mut parser = Parser { src: s, pos: 0, depth: 0 };
+        let value = parser.value()?;
+        parser.skip_ws();
+        if parser.pos != s.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+    /// How many containers are open.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, desc: &str) -> HpError {
+        HpError::from((
+            ErrorKind::ParseError,
+            "text parse error",
+            format!("{} at {}", desc, self.pos),
+        ))
+    }
+
+    fn rest(&self) -> &str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_ws(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn expect(&mut self, c: char) -> HpResult<()> {
+        self.skip_ws();
+        match self.bump() {
+            Some(v) if v == c => Ok(()),
+            _ => Err(self.error(&format!("expected '{}'", c))),
+        }
+    }
+
+    /// Opens a container, failing past [`MAX_DEPTH`] open ones.
+    fn enter(&mut self) -> HpResult<()> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error("nesting too deep"));
+        }
+        Ok(())
+    }
+
+    /// Takes the run of characters matching `f`.
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let src = self.src;
+        let start = self.pos;
+        while self.peek().is_some_and(&f) {
+            self.bump();
+        }
+        &src[start..self.pos]
+    }
+
+    fn value(&mut self) -> HpResult<Value> {
+        self.skip_ws();
+        match self.peek() {
+            Some('[') => {
+                self.bump();
+                self.enter()?;
+                let mut arr = Vec::new();
+                self.items(']', |p| {
+                    arr.push(p.value()?);
+                    Ok(())
+                })?;
+                self.depth -= 1;
+                Ok(Value::Arr(arr))
+            }
+            Some('{') => {
+                self.bump();
+                self.enter()?;
+                let mut map = HashMap::new();
+                self.items('}', |p| {
+                    let key = p.key()?;
+                    p.expect(':')?;
+                    map.insert(key, p.value()?);
+                    Ok(())
+                })?;
+                self.depth -= 1;
+                Ok(Value::Map(map))
+            }
+            Some('"') => {
+                self.bump();
+                let bytes = self.quoted(false)?;
+                String::from_utf8(bytes)
+                    .map(Value::Str)
+                    .map_err(|_| self.error("invalid utf-8"))
+            }
+            Some('b') if self.rest().starts_with("b\"") => {
+                self.pos += 2;
+                Ok(Value::Raw(self.quoted(true)?))
+            }
+            Some(c) if c == '-' || c == '+' || c.is_ascii_digit() => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => {
+                let word = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+                match word {
+                    "nil" => Ok(Value::Nil),
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    "encoded" => {
+                        self.expect('(')?;
+                        self.enter()?;
+                        let value = self.value()?;
+                        self.depth -= 1;
+                        self.expect(')')?;
+                        Ok(Value::Encoded(RawValue::from_value(&value)?))
+                    }
+                    _ => {
+                        self.pos -= word.len();
+                        self.number()
+                    }
+                }
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    /// Reads comma separated items up to `end`, a trailing comma allowed.
+    fn items<F>(&mut self, end: char, mut item: F) -> HpResult<()>
+    where
+        F: FnMut(&mut Self) -> HpResult<()>,
+    {
+        loop {
+            self.skip_ws();
+            if self.peek() == Some(end) {
+                self.bump();
+                return Ok(());
+            }
+            item(self)?;
+            self.skip_ws();
+            match self.bump() {
+                Some(',') => {}
+                Some(c) if c == end => return Ok(()),
+                _ => return Err(self.error(&format!("expected ',' or '{}'", end))),
+            }
+        }
+    }
+
+    /// A map key, which may also be a bare word standing for a `Str`. A word
+    /// that reads as a value, such as `true` or `inff32`, is that value.
+    fn key(&mut self) -> HpResult<Value> {
+        self.skip_ws();
+        let start = self.pos;
+        let word = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        let bare = word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && !matches!(word, "nil" | "true" | "false" | "encoded")
+            && !is_number(word);
+        self.skip_ws();
+        if bare && self.peek() == Some(':') {
+            return Ok(Value::Str(word.to_string()));
+        }
+        self.pos = start;
+        self.value()
+    }
+
+    fn number(&mut self) -> HpResult<Value> {
+        let start = self.pos;
+        let sign = self.take_while(|c| c == '-' || c == '+').len();
+        let digits = self.take_while(|c| c.is_ascii_digit() || c == '.').len();
+        let mut float = self.src[start..self.pos].contains('.');
+        if digits == 0 {
+            // NaN and inf only exist as floats.
+            if !self.rest().starts_with("NaN") && !self.rest().starts_with("inf") {
+                self.pos = start;
+                return Err(self.error("expected a value"));
+            }
+            self.pos += 3;
+            float = true;
+        } else if self.rest().starts_with(['e', 'E']) {
+            self.bump();
+            self.take_while(|c| c == '-' || c == '+');
+            self.take_while(|c| c.is_ascii_digit());
+            float = true;
+        }
+        if sign > 1 {
+            return Err(self.error("bad number"));
+        }
+        let text = &self.src[start..self.pos];
+        let suffix = self.take_while(|c| c.is_ascii_alphanumeric());
+        let bad = || self.error(&format!("bad number {}{}", text, suffix));
+        macro_rules! parse {
+            ($ty:ty, $var:ident) => {
+                text.parse::<$ty>().map(Value::$var).map_err(|_| bad())
+            };
+        }
+        match suffix {
+            "f32" => parse!(f32, F32),
+            "f64" => parse!(f64, F64),
+            "" if float => parse!(f64, F64),
+            _ if float => Err(bad()),
+            "u8" => parse!(u8, U8),
+            "i8" => parse!(i8, I8),
+            "u16" => parse!(u16, U16),
+            "i16" => parse!(i16, I16),
+            "u32" => parse!(u32, U32),
+            "i32" => parse!(i32, I32),
+            "u64" => parse!(u64, U64),
+            "i64" => parse!(i64, I64),
+            "v" => parse!(i64, Varint),
+            "" => parse!(i64, Varint).or_else(|_| parse!(u64, U64)),
+            _ => Err(bad()),
+        }
+    }
+
+    /// Reads up to the closing quote, unescaping. `raw` allows `\x` escapes
+    /// of any byte, a string only takes them below 0x80.
+    fn quoted(&mut self, raw: bool) -> HpResult<Vec<u8>> {
+        let mut out = Vec::new();
+        loop {
+            let c = match self.bump() {
+                Some(c) => c,
+                None => return Err(self.error("unterminated string")),
+            };
+            let c = match c {
+                '"' => return Ok(out),
+                '\\' => match self.bump() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('\'') => '\'',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('0') => '\0',
+                    Some('x') => {
+                        let hex = self.rest().get(..2).ok_or_else(|| self.error("bad escape"))?;
+                        let b = u8::from_str_radix(hex, 16).map_err(|_| self.error("bad escape"))?;
+                        if !raw && b >= 0x80 {
+                            return Err(self.error("bad escape"));
+                        }
+                        self.pos += 2;
+                        out.push(b);
+                        continue;
+                    }
+                    Some('u') => {
+                        let end = self.rest().find('}').ok_or_else(|| self.error("bad escape"))?;
+                        let hex = self.rest()[..end].strip_prefix('{');
+                        let c = hex
+                            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| self.error("bad escape"))?;
+                        self.pos += end + 1;
+                        c
+                    }
+                    _ => return Err(self.error("bad escape")),
+                },
+                c => c,
+            };
+            let mut buf = [0u8; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+        }
+    }
+}
+
+/// Whether the whole of `word` reads as a number, like `NaN` or `inff32`.
+fn is_number(word: &str) -> bool {
+    let mut parser = Parser { src: word, pos: 0, depth: 0 };
+    parser.number().is_ok() && parser.pos == word.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{get_type_by_value, RawValue, Value, ValueType};
+
+    #[test]
+    fn test_text_round_trip() {
+        let arr = Value::Arr(vec![
+            Value::Nil,
+            Value::Bool(true),
+            Value::U8(3),
+            Value::I8(-3),
+            Value::U16(4),
+            Value::I16(-4),
+            Value::U32(5),
+            Value::I32(-5),
+            Value::U64(u64::MAX),
+            Value::I64(i64::MIN),
+            Value::Varint(5),
+            Value::F32(1.5),
+            Value::F64(-0.1),
+            Value::F32(f32::INFINITY),
+            Value::F64(1e300),
+            Value::Str("a \"quoted\"\n\u{1b} 中".to_string()),
+            Value::Raw(vec![b'h', b'i', 0, 0xff, b'"']),
+            Value::Arr(vec![]),
+        ]);
+        let text = arr.to_string();
+        assert_eq!(
+            text,
+            "[nil, true, 3u8, -3i8, 4u16, -4i16, 5u32, -5i32, 18446744073709551615u64, \
+             -9223372036854775808i64, 5v, 1.5f32, -0.1f64, inff32, 1e300f64, \
+             \"a \\\"quoted\\\"\\n\\u{1b} 中\", b\"hi\\0\\xff\\\"\", []]"
+        );
+        let back: Value = text.parse().unwrap();
+        let (Value::Arr(back), Value::Arr(arr)) = (&back, &arr) else { panic!("not an arr") };
+        for (back, v) in back.iter().zip(arr) {
+            assert_eq!(get_type_by_value(back), get_type_by_value(v));
+            assert_eq!(back, v);
+        }
+        let encoded = Value::Encoded(RawValue::from_value(&Value::Str("x".to_string())).unwrap());
+        assert_eq!(encoded.to_string(), "encoded(\"x\")");
+        assert_eq!(encoded.to_string().parse::<Value>().unwrap(), encoded);
+        let nan: Value = "NaNf32".parse().unwrap();
+        assert!(matches!(nan, Value::F32(v) if v.is_nan()));
+
+        let mut map = HashMap::new();
+        map.insert(Value::U8(1), Value::Str("one".to_string()));
+        map.insert(Value::Str("k".to_string()), Value::Map(HashMap::new()));
+        map.insert(Value::F32(f32::INFINITY), Value::Varint(1));
+        map.insert(Value::F64(f64::INFINITY), Value::Varint(2));
+        map.insert(Value::Str("inf_".to_string()), Value::Varint(3));
+        let map = Value::Map(map);
+        assert_eq!(map.to_string().parse::<Value>().unwrap(), map);
+        let Value::Map(nan) = "{NaNf64: 1v}".parse().unwrap() else { panic!("not a map") };
+        assert!(matches!(nan.keys().next(), Some(Value::F64(v)) if v.is_nan()));
+    }
+
+    #[test]
+    fn test_text_parse() {
+        let value: Value = " { k : [ 1 , 2.5 , -7, ], \"s\": b\"\\x01\" , } ".parse().unwrap();
+        let Value::Map(ref v) = value else { panic!("not a map") };
+        assert_eq!(v.len(), 2);
+        assert_eq!(
+            v[&Value::Str("k".to_string())],
+            Value::Arr(vec![Value::Varint(1), Value::F64(2.5), Value::Varint(-7)])
+        );
+        assert_eq!(v[&Value::Str("s".to_string())], Value::Raw(vec![1]));
+
+        assert_eq!(get_type_by_value(&"7".parse().unwrap()), ValueType::Varint);
+        assert_eq!(get_type_by_value(&"2.5".parse().unwrap()), ValueType::F64);
+        assert_eq!("1e3f32".parse::<Value>().unwrap(), Value::F32(1000.0));
+        for bad in ["256u8", "1.5u8", "[1 2]", "\"open", "{1v}", "1v 2v", "nul", "--1", "\"\\xff\""] {
+            assert!(bad.parse::<Value>().is_err(), "{} parsed", bad);
+        }
+
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(nested(128).parse::<Value>().is_ok());
+        assert!(nested(129).parse::<Value>().is_err());
+        assert!("[".repeat(200_000).parse::<Value>().is_err());
+    }
+}
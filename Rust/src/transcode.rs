@@ -0,0 +1,417 @@
+// Each format uses only part of the token layer.
+#![cfg_attr(not(all(feature = "msgpack", feature = "cbor", feature = "json")), allow(dead_code))]
+
+use algorithm::buf::{Bt, BtMut};
+
+use crate::decode::{decode_by_pattern_ref, decode_field_ref, decode_str_table_ref, decode_varint};
+use crate::encode::{
+    encode_string, encode_varint, encode_varint_padded, BorrowedTable, StrTable, PADDED_VARINT_LEN,
+};
+use crate::{BufferRef, ErrorKind, HpError, HpResult, Value, ValueRef, ValueType};
+
+/// A kind of conversion that could not carry a value over exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Lossy {
+    /// A fixed width integer lost its width, it reads back as a `Varint`.
+    IntWidth,
+    /// Text that is not valid UTF-8, carried as `Raw`.
+    InvalidUtf8,
+    /// A value with no counterpart, such as CBOR `undefined`, carried as `Nil`.
+    Unsupported,
+    /// An integer out of the `i64` and `u64` range, carried as `F64`.
+    IntRange,
+}
+
+/// How deep containers may nest in a transcoded document, as the
+/// transcoders recurse once per level.
+pub(crate) const MAX_DEPTH: usize = 128;
+
+/// Fails when an item is nested inside more than [`MAX_DEPTH`] containers.
+pub(crate) fn check_depth(depth: usize) -> HpResult<()> {
+    if depth > MAX_DEPTH {
+        return Err(HpError::from((ErrorKind::ParseError, "nesting too deep")));
+    }
+    Ok(())
+}
+
+/// A map escaped by a transcoder, as `{"$map": [[key, value], ...]}`: one
+/// whose single key is named after a tag, so it does not read back as the
+/// tagged value, and in JSON one with keys that are not all strings.
+pub const MAP_TAG: &str = "$map";
+
+/// The output of a transcoder, with the lossy conversions it made.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transcoded {
+    pub bytes: Vec<u8>,
+    /// Each kind of lossy conversion met, with how many times.
+    pub lossy: Vec<(Lossy, usize)>,
+}
+
+impl Transcoded {
+    /// Whether every value was carried over exactly.
+    pub fn is_lossless(&self) -> bool {
+        self.lossy.is_empty()
+    }
+
+    pub(crate) fn note(&mut self, kind: Lossy) {
+        match self.lossy.iter_mut().find(|(k, _)| *k == kind) {
+            Some((_, count)) => *count += 1,
+            None => self.lossy.push((kind, 1)),
+        }
+    }
+}
+
+/// One step of a message body: a scalar, or the head of a container whose
+/// items follow as their own tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Token<'a> {
+    Nil,
+    Bool(bool),
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    Varint(i64),
+    F32(f32),
+    F64(f64),
+    Str(&'a str),
+    Raw(&'a [u8]),
+    /// An array of this many items.
+    Arr(usize),
+    /// A map of this many key and value pairs.
+    Map(usize),
+}
+
+impl Token<'_> {
+    pub(crate) fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Token::U8(v) => Some(v as i64),
+            Token::I8(v) => Some(v as i64),
+            Token::U16(v) => Some(v as i64),
+            Token::I16(v) => Some(v as i64),
+            Token::U32(v) => Some(v as i64),
+            Token::I32(v) => Some(v as i64),
+            Token::U64(v) => i64::try_from(v).ok(),
+            Token::I64(v) | Token::Varint(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Token::U64(v) => Some(v),
+            _ => self.as_i64().and_then(|v| u64::try_from(v).ok()),
+        }
+    }
+}
+
+/// Reads a message holding a single value one token at a time.
+///
+/// The containers it is in are counted, so a value nested deeper than
+/// [`MAX_DEPTH`] fails whoever walks it.
+pub(crate) struct TokenReader<'a> {
+    input: BufferRef<'a>,
+    /// The items left in each open container, the innermost last.
+    open: Vec<usize>,
+}
+
+/// A position of a [`TokenReader`], see [`TokenReader::mark`].
+#[derive(Clone)]
+pub(crate) struct Mark<'a> {
+    buf: &'a [u8],
+    open: Vec<usize>,
+}
+
+impl<'a> TokenReader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> HpResult<TokenReader<'a>> {
+        let mut input = BufferRef::new(buf);
+        decode_str_table_ref(&mut input)?;
+        Ok(TokenReader { input, open: Vec::new() })
+    }
+
+    /// Counts the next item against the containers it is in.
+    fn take_item(&mut self) {
+        while self.open.last() == Some(&0) {
+            self.open.pop();
+        }
+        if let Some(left) = self.open.last_mut() {
+            *left -= 1;
+        }
+    }
+
+    pub(crate) fn next(&mut self) -> HpResult<Token<'a>> {
+        self.take_item();
+        let token = self.read()?;
+        let items = match token {
+            Token::Arr(len) => len,
+            Token::Map(len) => len * 2,
+            _ => 0,
+        };
+        if items > 0 {
+            self.open.push(items);
+            check_depth(self.open.len())?;
+        }
+        Ok(token)
+    }
+
+    fn read(&mut self) -> HpResult<Token<'a>> {
+        let input = &mut self.input;
+        let pattern = ValueType::from(input.buf.try_get_u8()?);
+        Ok(match pattern {
+            ValueType::Arr => {
+                let len: u16 = decode_varint(&mut input.buf)?.try_into()?;
+                Token::Arr(len as usize)
+            }
+            ValueType::Map => {
+                let len: u32 = decode_varint(&mut input.buf)?.try_into()?;
+                Token::Map(len as usize / 2)
+            }
+            _ => match decode_by_pattern_ref(input, &pattern)? {
+                ValueRef::Bool(v) => Token::Bool(v),
+                ValueRef::U8(v) => Token::U8(v),
+                ValueRef::I8(v) => Token::I8(v),
+                ValueRef::U16(v) => Token::U16(v),
+                ValueRef::I16(v) => Token::I16(v),
+                ValueRef::U32(v) => Token::U32(v),
+                ValueRef::I32(v) => Token::I32(v),
+                ValueRef::U64(v) => Token::U64(v),
+                ValueRef::I64(v) => Token::I64(v),
+                ValueRef::Varint(v) => Token::Varint(v),
+                ValueRef::F32(v) => Token::F32(v),
+                ValueRef::F64(v) => Token::F64(v),
+                ValueRef::Str(v) => Token::Str(v),
+                ValueRef::Raw(v) => Token::Raw(v),
+                _ => Token::Nil,
+            },
+        })
+    }
+
+    /// Reads the whole next value.
+    pub(crate) fn value(&mut self) -> HpResult<ValueRef<'a>> {
+        // Walked first, so its depth is checked before the decoder recurses.
+        let mark = self.mark();
+        self.skip()?;
+        self.reset(mark);
+        self.take_item();
+        decode_field_ref(&mut self.input)
+    }
+
+    /// Reads past the next value.
+    pub(crate) fn skip(&mut self) -> HpResult<()> {
+        let len = match self.next()? {
+            Token::Arr(len) => len,
+            Token::Map(len) => len * 2,
+            _ => 0,
+        };
+        for _ in 0..len {
+            self.skip()?;
+        }
+        Ok(())
+    }
+
+    /// The position to come back to with [`TokenReader::reset`] after a look ahead.
+    pub(crate) fn mark(&self) -> Mark<'a> {
+        Mark {
+            buf: self.input.buf,
+            open: self.open.clone(),
+        }
+    }
+
+    pub(crate) fn reset(&mut self, mark: Mark<'a>) {
+        self.input.buf = mark.buf;
+        self.open = mark.open;
+    }
+
+    /// Checks that the whole message was read.
+    pub(crate) fn finish(&self) -> HpResult<()> {
+        if !self.input.buf.is_empty() {
+            return Err(HpError::from((ErrorKind::ParseError, "left buffer")));
+        }
+        Ok(())
+    }
+}
+
+/// Reads the head of a one pair map keyed [`MAP_TAG`] whose `Map` token was
+/// just read, returning how many pairs it escapes with the reader on the
+/// first, each an `Arr` of two. `None` if it is not one.
+pub(crate) fn escaped_map(reader: &mut TokenReader) -> HpResult<Option<usize>> {
+    if reader.next()? != Token::Str(MAP_TAG) {
+        return Ok(None);
+    }
+    let Token::Arr(len) = reader.next()? else {
+        return Ok(None);
+    };
+    let pairs = reader.mark();
+    for _ in 0..len {
+        if reader.next()? != Token::Arr(2) {
+            return Ok(None);
+        }
+        reader.skip()?;
+        reader.skip()?;
+    }
+    reader.reset(pairs);
+    Ok(Some(len))
+}
+
+/// Builds a message holding a single value from tokens.
+///
+/// Strings borrowed from the input are interned in the string table, the
+/// ones the caller had to assemble are written inline. Integers are written
+/// the way the encoder writes them: `U8` and `I8` as is, the rest as a
+/// `Varint` unless it needs the full `U64` range.
+#[derive(Default)]
+pub(crate) struct TokenWriter<'a> {
+    body: Vec<u8>,
+    table: BorrowedTable<'a>,
+}
+
+impl<'a> TokenWriter<'a> {
+    pub(crate) fn new() -> TokenWriter<'a> {
+        TokenWriter::default()
+    }
+
+    pub(crate) fn write(&mut self, token: Token<'a>) -> HpResult<()> {
+        let body = &mut self.body;
+        match token {
+            Token::Nil => {
+                body.put_u8(ValueType::Nil as u8);
+            }
+            Token::Bool(v) => {
+                body.put_slice(&[ValueType::Bool as u8, v as u8]);
+            }
+            Token::U8(v) => {
+                body.put_slice(&[ValueType::U8 as u8, v]);
+            }
+            Token::I8(v) => {
+                body.put_slice(&[ValueType::I8 as u8, v as u8]);
+            }
+            Token::U64(v) if v > i64::MAX as u64 => {
+                body.put_u8(ValueType::U64 as u8);
+                body.put_u64(v);
+            }
+            Token::F32(v) => {
+                body.put_u8(ValueType::F32 as u8);
+                body.put_f32(v);
+            }
+            Token::F64(v) => {
+                body.put_u8(ValueType::F64 as u8);
+                body.put_f64(v);
+            }
+            Token::Str(v) => {
+                // Past the last index the table can hold, strings go inline.
+                if self.table.str_arr.len() >= u16::MAX as usize {
+                    return self.write_str_inline(v);
+                }
+                check_len(v.len(), "string too long")?;
+                let idx = self.table.intern(v);
+                body.put_u8(ValueType::StrIdx as u8);
+                encode_varint(body, &Value::U16(idx))?;
+            }
+            Token::Raw(v) => return self.write_raw(v),
+            Token::Arr(len) => {
+                let len = check_len(len, "array too long")?;
+                body.put_u8(ValueType::Arr as u8);
+                encode_varint(body, &Value::U16(len))?;
+            }
+            Token::Map(len) => {
+                let len = match len.checked_mul(2).and_then(|len| u32::try_from(len).ok()) {
+                    Some(len) => len,
+                    None => return Err(HpError::from((ErrorKind::BufferOverMaxError, "map too long"))),
+                };
+                body.put_u8(ValueType::Map as u8);
+                encode_varint(body, &Value::U32(len))?;
+            }
+            token => {
+                body.put_u8(ValueType::Varint as u8);
+                encode_varint(body, &Value::Varint(token.as_i64().unwrap_or_default()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a string as an inline `Str`, for text that is not borrowed from the input.
+    pub(crate) fn write_str_inline(&mut self, val: &str) -> HpResult<()> {
+        check_len(val.len(), "string too long")?;
+        self.body.put_u8(ValueType::Str as u8);
+        encode_string(&mut self.body, val)?;
+        Ok(())
+    }
+
+    pub(crate) fn write_raw(&mut self, val: &[u8]) -> HpResult<()> {
+        let len = check_len(val.len(), "raw too long")?;
+        self.body.put_u8(ValueType::Raw as u8);
+        encode_varint(&mut self.body, &Value::U16(len))?;
+        self.body.put_slice(val);
+        Ok(())
+    }
+
+    /// Starts an `Arr` or `Map` whose length is not known yet, returning where
+    /// to patch it with [`TokenWriter::end`].
+    pub(crate) fn begin(&mut self, pattern: ValueType) -> usize {
+        let offset = self.body.len();
+        open_container(&mut self.body, pattern);
+        offset
+    }
+
+    /// Patches the length of the container started at `offset`, `len` counts
+    /// items for an `Arr` and pairs for a `Map`.
+    pub(crate) fn end(&mut self, offset: usize, len: usize) -> HpResult<()> {
+        let pattern = if self.body[offset] == ValueType::Map as u8 {
+            ValueType::Map
+        } else {
+            ValueType::Arr
+        };
+        let head = container_len(pattern, len)?;
+        self.body[offset + 1..offset + 1 + PADDED_VARINT_LEN].copy_from_slice(&head);
+        Ok(())
+    }
+
+    /// The length of the body, to [`TokenWriter::truncate`] back to.
+    pub(crate) fn len(&self) -> usize {
+        self.body.len()
+    }
+
+    /// Drops what was written past `len`, the strings it interned stay in the table.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.body.truncate(len);
+    }
+
+    /// The string table followed by the body.
+    pub(crate) fn finish(self) -> HpResult<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(self.body.len() + 8 * self.table.str_arr.len() + 2);
+        encode_varint(&mut bytes, &Value::U16(self.table.str_arr.len() as u16))?;
+        for val in &self.table.str_arr {
+            encode_string(&mut bytes, val)?;
+        }
+        bytes.extend_from_slice(&self.body);
+        Ok(bytes)
+    }
+}
+
+/// Writes the head of an `Arr` or `Map` whose length is patched in later
+/// with [`container_len`].
+pub(crate) fn open_container(out: &mut Vec<u8>, pattern: ValueType) {
+    out.put_u8(pattern as u8);
+    out.put_slice(&[0; PADDED_VARINT_LEN]);
+}
+
+/// The padded length following the type of a container opened with
+/// [`open_container`], `len` counts items for an `Arr` and pairs for a `Map`.
+pub(crate) fn container_len(pattern: ValueType, len: usize) -> HpResult<[u8; PADDED_VARINT_LEN]> {
+    let len = match pattern {
+        ValueType::Map => len * 2,
+        _ => check_len(len, "array too long")? as usize,
+    };
+    let mut head = [0u8; PADDED_VARINT_LEN];
+    encode_varint_padded(&mut head, &Value::Varint(len as i64))?;
+    Ok(head)
+}
+
+/// Strings, raws and arrays carry a `u16` length.
+pub(crate) fn check_len(len: usize, desc: &'static str) -> HpResult<u16> {
+    u16::try_from(len).map_err(|_| HpError::from((ErrorKind::BufferOverMaxError, desc)))
+}
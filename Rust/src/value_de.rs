@@ -0,0 +1,601 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::de::{
+    self, Deserialize, DeserializeOwned, DeserializeSeed, EnumAccess, Error, IntoDeserializer,
+    MapAccess, SeqAccess, VariantAccess, Visitor,
+};
+use serde::forward_to_deserialize_any;
+
+use crate::raw_value::TOKEN;
+use crate::values::decoded;
+use crate::{get_type_by_value, HpError, HpResult, RawValue, StructMode, Value, ValueDescType};
+
+/// The most elements reserved up front, as the size hint of a container may
+/// be a length read from untrusted input.
+const MAX_PREALLOC: usize = 4096;
+
+/// Builds a `T` out of a [`Value`] tree, the reverse of [`crate::to_value`].
+///
+/// It reads the same shapes the byte deserializer does, so a `Value` decoded
+/// from any message `T` was serialized into can be turned back into `T`.
+pub fn from_value<T>(value: Value) -> HpResult<T>
+where
+    T: DeserializeOwned,
+{
+    T::deserialize(value)
+}
+
+/// Like [`from_value`], but reads structs stored as an `Arr` with the layout
+/// given, see [`StructMode`]. [`from_value`] takes them as legacy pairs.
+pub fn from_value_with_struct_mode<T>(value: Value, struct_mode: StructMode) -> HpResult<T>
+where
+    T: DeserializeOwned,
+{
+    T::deserialize(WithMode { value, struct_mode })
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D>(deserializer: D) -> Result<Value, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        struct ValueVisitor;
+
+        impl<'de> Visitor<'de> for ValueVisitor {
+            type Value = Value;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("any hcproto value")
+            }
+
+            fn visit_bool<E>(self, v: bool) -> Result<Value, E> {
+                Ok(Value::Bool(v))
+            }
+
+            fn visit_i8<E>(self, v: i8) -> Result<Value, E> {
+                Ok(Value::I8(v))
+            }
+
+            fn visit_i16<E>(self, v: i16) -> Result<Value, E> {
+                Ok(Value::I16(v))
+            }
+
+            fn visit_i32<E>(self, v: i32) -> Result<Value, E> {
+                Ok(Value::I32(v))
+            }
+
+            // Every wider integer is written as a varint, decode it as one
+            // like `decode_field` does.
+            fn visit_i64<E>(self, v: i64) -> Result<Value, E> {
+                Ok(Value::Varint(v))
+            }
+
+            fn visit_u8<E>(self, v: u8) -> Result<Value, E> {
+                Ok(Value::U8(v))
+            }
+
+            fn visit_u16<E>(self, v: u16) -> Result<Value, E> {
+                Ok(Value::U16(v))
+            }
+
+            fn visit_u32<E>(self, v: u32) -> Result<Value, E> {
+                Ok(Value::U32(v))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Value, E> {
+                Ok(Value::U64(v))
+            }
+
+            fn visit_f32<E>(self, v: f32) -> Result<Value, E> {
+                Ok(Value::F32(v))
+            }
+
+            fn visit_f64<E>(self, v: f64) -> Result<Value, E> {
+                Ok(Value::F64(v))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Value, E> {
+                Ok(Value::Str(v.to_string()))
+            }
+
+            fn visit_string<E>(self, v: String) -> Result<Value, E> {
+                Ok(Value::Str(v))
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Value, E> {
+                Ok(Value::Raw(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Value, E> {
+                Ok(Value::Raw(v))
+            }
+
+            fn visit_none<E>(self) -> Result<Value, E> {
+                Ok(Value::Nil)
+            }
+
+            fn visit_some<D>(self, deserializer: D) -> Result<Value, D::Error>
+            where
+                D: de::Deserializer<'de>,
+            {
+                Deserialize::deserialize(deserializer)
+            }
+
+            fn visit_unit<E>(self) -> Result<Value, E> {
+                Ok(Value::Nil)
+            }
+
+            fn visit_newtype_struct<D>(self, deserializer: D) -> Result<Value, D::Error>
+            where
+                D: de::Deserializer<'de>,
+            {
+                Deserialize::deserialize(deserializer)
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut vec = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(MAX_PREALLOC));
+                while let Some(item) = seq.next_element()? {
+                    vec.push(item);
+                }
+                Ok(Value::Arr(vec))
+            }
+
+            fn visit_map<A>(self, mut access: A) -> Result<Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let mut map = HashMap::with_capacity(access.size_hint().unwrap_or(0).min(MAX_PREALLOC));
+                while let Some((key, value)) = access.next_entry()? {
+                    map.insert(key, value);
+                }
+                Ok(Value::Map(map))
+            }
+        }
+
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+impl IntoDeserializer<'_, HpError> for Value {
+    type Deserializer = Value;
+
+    fn into_deserializer(self) -> Value {
+        self
+    }
+}
+
+impl Value {
+    fn invalid_type<E: Error>(&self, exp: &dyn de::Expected) -> E {
+        E::invalid_type(
+            de::Unexpected::Other(ValueDescType::from(get_type_by_value(self)).val),
+            exp,
+        )
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = HpError;
+
+    fn deserialize_any<V>(self, visitor: V) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        WithMode::legacy(self).deserialize_any(visitor)
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        WithMode::legacy(self).deserialize_option(visitor)
+    }
+
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        WithMode::legacy(self).deserialize_newtype_struct(name, visitor)
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        WithMode::legacy(self).deserialize_struct(name, fields, visitor)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        WithMode::legacy(self).deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_identifier<V>(self, visitor: V) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        WithMode::legacy(self).deserialize_identifier(visitor)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map ignored_any
+    }
+}
+
+/// A `Value` with the layout its `Arr` structs were written with, handed
+/// down to every nested value.
+struct WithMode {
+    value: Value,
+    struct_mode: StructMode,
+}
+
+impl WithMode {
+    fn legacy(value: Value) -> Self {
+        WithMode {
+            value,
+            struct_mode: StructMode::Legacy,
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for WithMode {
+    type Error = HpError;
+
+    fn deserialize_any<V>(self, visitor: V) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value {
+            Value::Nil => visitor.visit_unit(),
+            Value::Bool(v) => visitor.visit_bool(v),
+            Value::U8(v) => visitor.visit_u8(v),
+            Value::I8(v) => visitor.visit_i8(v),
+            Value::U16(v) => visitor.visit_u16(v),
+            Value::I16(v) => visitor.visit_i16(v),
+            Value::U32(v) => visitor.visit_u32(v),
+            Value::I32(v) => visitor.visit_i32(v),
+            Value::U64(v) => visitor.visit_u64(v),
+            Value::I64(v) | Value::Varint(v) => visitor.visit_i64(v),
+            Value::F32(v) => visitor.visit_f32(v),
+            Value::F64(v) => visitor.visit_f64(v),
+            Value::Str(v) => visitor.visit_string(v),
+            Value::Raw(v) => visitor.visit_byte_buf(v),
+            Value::Arr(v) => {
+                let len = v.len();
+                visitor.visit_seq(SeqDeserializer::new(v.into_iter(), len, self.struct_mode))
+            }
+            Value::Map(v) => {
+                let len = v.len();
+                visitor.visit_map(MapDeserializer::new(v.into_iter(), len, self.struct_mode))
+            }
+            Value::Encoded(v) => WithMode {
+                value: v.to_value()?,
+                struct_mode: self.struct_mode,
+            }
+            .deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value {
+            Value::Nil => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if name == TOKEN {
+            if let Value::Encoded(raw) = self.value {
+                return visitor.visit_byte_buf(raw.into_vec());
+            }
+            return visitor.visit_byte_buf(RawValue::from_value(&self.value)?.into_vec());
+        }
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let struct_mode = self.struct_mode;
+        match decoded(self.value)? {
+            // A `Value` does not record the layout its structs were written
+            // with, so an `Arr` is read the way the serializer writes it by
+            // default unless the caller says otherwise.
+            Value::Arr(v) if struct_mode == StructMode::Positional => {
+                let len = v.len();
+                visitor.visit_seq(SeqDeserializer::new(v.into_iter(), len, struct_mode))
+            }
+            Value::Arr(v) => {
+                let len = v.len() / 2;
+                visitor.visit_map(MapDeserializer::new(Pairs(v.into_iter()), len, struct_mode))
+            }
+            value => WithMode { value, struct_mode }.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let struct_mode = self.struct_mode;
+        match decoded(self.value)? {
+            Value::Map(v) => {
+                let mut iter = v.into_iter();
+                match (iter.next(), iter.next()) {
+                    (Some((variant, content)), None) => visitor.visit_enum(EnumDeserializer {
+                        variant,
+                        content: Some(content),
+                        struct_mode,
+                    }),
+                    _ => Err(Error::custom("enum must be a map of one entry")),
+                }
+            }
+            variant => visitor.visit_enum(EnumDeserializer {
+                variant,
+                content: None,
+                struct_mode,
+            }),
+        }
+    }
+
+    fn deserialize_identifier<V>(self, visitor: V) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value {
+            Value::Varint(v) | Value::I64(v) if v >= 0 => visitor.visit_u64(v as u64),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map ignored_any
+    }
+}
+
+struct SeqDeserializer<I> {
+    iter: I,
+    len: usize,
+    struct_mode: StructMode,
+}
+
+impl<I> SeqDeserializer<I> {
+    fn new(iter: I, len: usize, struct_mode: StructMode) -> Self {
+        SeqDeserializer {
+            iter,
+            len,
+            struct_mode,
+        }
+    }
+}
+
+impl<'de, I> SeqAccess<'de> for SeqDeserializer<I>
+where
+    I: Iterator<Item = Value>,
+{
+    type Error = HpError;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> HpResult<Option<T::Value>>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.iter.next() {
+            Some(value) => {
+                self.len -= 1;
+                seed.deserialize(WithMode {
+                    value,
+                    struct_mode: self.struct_mode,
+                })
+                .map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+/// Reads the alternating names and values of a legacy struct as entries.
+struct Pairs<I>(I);
+
+impl<I: Iterator<Item = Value>> Iterator for Pairs<I> {
+    type Item = (Value, Value);
+
+    fn next(&mut self) -> Option<(Value, Value)> {
+        Some((self.0.next()?, self.0.next()?))
+    }
+}
+
+struct MapDeserializer<I> {
+    iter: I,
+    value: Option<Value>,
+    len: usize,
+    struct_mode: StructMode,
+}
+
+impl<I> MapDeserializer<I> {
+    fn new(iter: I, len: usize, struct_mode: StructMode) -> Self {
+        MapDeserializer {
+            iter,
+            value: None,
+            len,
+            struct_mode,
+        }
+    }
+}
+
+impl<'de, I> MapAccess<'de> for MapDeserializer<I>
+where
+    I: Iterator<Item = (Value, Value)>,
+{
+    type Error = HpError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> HpResult<Option<K::Value>>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.len -= 1;
+                self.value = Some(value);
+                seed.deserialize(WithMode {
+                    value: key,
+                    struct_mode: self.struct_mode,
+                })
+                .map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<T>(&mut self, seed: T) -> HpResult<T::Value>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.value.take() {
+            Some(value) => seed.deserialize(WithMode {
+                value,
+                struct_mode: self.struct_mode,
+            }),
+            None => Err(Error::custom("value is missing")),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+struct EnumDeserializer {
+    variant: Value,
+    content: Option<Value>,
+    struct_mode: StructMode,
+}
+
+impl<'de> EnumAccess<'de> for EnumDeserializer {
+    type Error = HpError;
+    type Variant = VariantDeserializer;
+
+    fn variant_seed<V>(self, seed: V) -> HpResult<(V::Value, VariantDeserializer)>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let variant = seed.deserialize(IdentifierDeserializer(self.variant))?;
+        let content = self.content.map(|value| WithMode {
+            value,
+            struct_mode: self.struct_mode,
+        });
+        Ok((variant, VariantDeserializer(content)))
+    }
+}
+
+/// Hands a variant tag to the derived identifier visitor, which takes
+/// indexes as `u64` only.
+struct IdentifierDeserializer(Value);
+
+impl<'de> de::Deserializer<'de> for IdentifierDeserializer {
+    type Error = HpError;
+
+    fn deserialize_any<V>(self, visitor: V) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.0.deserialize_identifier(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct VariantDeserializer(Option<WithMode>);
+
+impl<'de> VariantAccess<'de> for VariantDeserializer {
+    type Error = HpError;
+
+    fn unit_variant(self) -> HpResult<()> {
+        match self.0.map(|content| content.value) {
+            None | Some(Value::Nil) => Ok(()),
+            Some(value) => Err(value.invalid_type(&"unit variant")),
+        }
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> HpResult<T::Value>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.0 {
+            Some(content) => seed.deserialize(content),
+            None => Err(Error::invalid_type(de::Unexpected::UnitVariant, &"newtype variant")),
+        }
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.0 {
+            Some(content) if matches!(content.value, Value::Arr(_)) => {
+                de::Deserializer::deserialize_any(content, visitor)
+            }
+            Some(content) => Err(content.value.invalid_type(&"tuple variant")),
+            None => Err(Error::invalid_type(de::Unexpected::UnitVariant, &"tuple variant")),
+        }
+    }
+
+    fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> HpResult<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.0 {
+            Some(content) => de::Deserializer::deserialize_struct(content, "", fields, visitor),
+            None => Err(Error::invalid_type(de::Unexpected::UnitVariant, &"struct variant")),
+        }
+    }
+}
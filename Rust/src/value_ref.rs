@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use crate::values::{hash_f32, hash_f64, hash_int, hash_map, type_not_match_error};
+use crate::{HpError, HpResult, RawValue, Value, ValueType};
+
+/// A decoded value that borrows its strings and raw bytes from the input.
+///
+/// `Str` and `Raw` point straight into the encoded slice (or into the string
+/// table, which itself borrows from the slice), so decoding a `ValueRef`
+/// never copies string data. Use [`ValueRef::into_owned`] to detach it.
+/// Like [`Value`] it is `#[non_exhaustive]`.
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum ValueRef<'a> {
+    Nil,
+    Bool(bool),
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    Varint(i64),
+    F32(f32),
+    F64(f64),
+    Str(&'a str),
+    Raw(&'a [u8]),
+    Arr(Vec<ValueRef<'a>>),
+    Map(HashMap<ValueRef<'a>, ValueRef<'a>>),
+    Encoded(&'a RawValue),
+}
+
+impl<'a> ValueRef<'a> {
+    pub fn is_nil(&self) -> bool {
+        matches!(self, ValueRef::Nil)
+    }
+
+    pub fn get_type(&self) -> ValueType {
+        match self {
+            ValueRef::Nil => ValueType::Nil,
+            ValueRef::Bool(_) => ValueType::Bool,
+            ValueRef::U8(_) => ValueType::U8,
+            ValueRef::I8(_) => ValueType::I8,
+            ValueRef::U16(_) => ValueType::U16,
+            ValueRef::I16(_) => ValueType::I16,
+            ValueRef::U32(_) => ValueType::U32,
+            ValueRef::I32(_) => ValueType::I32,
+            ValueRef::U64(_) => ValueType::U64,
+            ValueRef::I64(_) => ValueType::I64,
+            ValueRef::Varint(_) => ValueType::Varint,
+            ValueRef::F32(_) => ValueType::F32,
+            ValueRef::F64(_) => ValueType::F64,
+            ValueRef::Str(_) => ValueType::Str,
+            ValueRef::Raw(_) => ValueType::Raw,
+            ValueRef::Arr(_) => ValueType::Arr,
+            ValueRef::Map(_) => ValueType::Map,
+            ValueRef::Encoded(v) => v.get_type(),
+        }
+    }
+
+    /// Copies every borrowed string and byte slice into an owned [`Value`].
+    pub fn into_owned(self) -> Value {
+        match self {
+            ValueRef::Nil => Value::Nil,
+            ValueRef::Bool(v) => Value::Bool(v),
+            ValueRef::U8(v) => Value::U8(v),
+            ValueRef::I8(v) => Value::I8(v),
+            ValueRef::U16(v) => Value::U16(v),
+            ValueRef::I16(v) => Value::I16(v),
+            ValueRef::U32(v) => Value::U32(v),
+            ValueRef::I32(v) => Value::I32(v),
+            ValueRef::U64(v) => Value::U64(v),
+            ValueRef::I64(v) => Value::I64(v),
+            ValueRef::Varint(v) => Value::Varint(v),
+            ValueRef::F32(v) => Value::F32(v),
+            ValueRef::F64(v) => Value::F64(v),
+            ValueRef::Str(v) => Value::Str(v.to_string()),
+            ValueRef::Raw(v) => Value::Raw(v.to_vec()),
+            ValueRef::Arr(v) => Value::Arr(v.into_iter().map(ValueRef::into_owned).collect()),
+            ValueRef::Map(v) => Value::Map(
+                v.into_iter()
+                    .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                    .collect(),
+            ),
+            ValueRef::Encoded(v) => Value::Encoded(v.clone()),
+        }
+    }
+
+    /// The value of an integer of any width.
+    fn integer(&self) -> Option<i128> {
+        match *self {
+            ValueRef::U8(v) => Some(v as i128),
+            ValueRef::I8(v) => Some(v as i128),
+            ValueRef::U16(v) => Some(v as i128),
+            ValueRef::I16(v) => Some(v as i128),
+            ValueRef::U32(v) => Some(v as i128),
+            ValueRef::I32(v) => Some(v as i128),
+            ValueRef::U64(v) => Some(v as i128),
+            ValueRef::I64(v) | ValueRef::Varint(v) => Some(v as i128),
+            _ => None,
+        }
+    }
+}
+
+/// Follows the same rules as `Value`: integers of any width are equal by
+/// value, every other variant only equals itself.
+impl PartialEq for ValueRef<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Bool(l0), Self::Bool(r0)) => l0 == r0,
+            // Integers are equal by value, whatever their widths.
+            _ if self.integer().is_some() => self.integer() == other.integer(),
+            (Self::F32(l0), Self::F32(r0)) => l0 == r0 || l0.is_nan() && r0.is_nan(),
+            (Self::F64(l0), Self::F64(r0)) => l0 == r0 || l0.is_nan() && r0.is_nan(),
+            (Self::Str(l0), Self::Str(r0)) => l0 == r0,
+            (Self::Raw(l0), Self::Raw(r0)) => l0 == r0,
+            (Self::Arr(l0), Self::Arr(r0)) => l0 == r0,
+            (Self::Map(l0), Self::Map(r0)) => l0 == r0,
+            (Self::Encoded(l0), Self::Encoded(r0)) => l0 == r0,
+            _ => core::mem::discriminant(self) == core::mem::discriminant(other),
+        }
+    }
+}
+
+impl Eq for ValueRef<'_> {}
+
+/// Hashes the content with the same rules as `Value`.
+impl Hash for ValueRef<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if let Some(v) = self.integer() {
+            return hash_int(v as i64, state);
+        }
+        match self {
+            ValueRef::Nil => 0u8.hash(state),
+            ValueRef::Bool(v) => {
+                1u8.hash(state);
+                v.hash(state);
+            }
+            ValueRef::F32(v) => hash_f32(*v, state),
+            ValueRef::F64(v) => hash_f64(*v, state),
+            ValueRef::Str(v) => {
+                13u8.hash(state);
+                v.hash(state);
+            }
+            ValueRef::Raw(v) => {
+                15u8.hash(state);
+                v.hash(state);
+            }
+            ValueRef::Arr(v) => {
+                16u8.hash(state);
+                v.hash(state);
+            }
+            ValueRef::Map(v) => hash_map(v, state),
+            ValueRef::Encoded(v) => {
+                18u8.hash(state);
+                v.hash(state);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl fmt::Debug for ValueRef<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValueRef::Nil => write!(fmt, "nil"),
+            ValueRef::Bool(val) => write!(fmt, "bool({:?})", val),
+            ValueRef::U8(val) => write!(fmt, "u8({:?})", val),
+            ValueRef::I8(val) => write!(fmt, "i8({:?})", val),
+            ValueRef::U16(val) => write!(fmt, "u16({:?})", val),
+            ValueRef::I16(val) => write!(fmt, "i16({:?})", val),
+            ValueRef::U32(val) => write!(fmt, "u32({:?})", val),
+            ValueRef::I32(val) => write!(fmt, "i32({:?})", val),
+            ValueRef::U64(val) => write!(fmt, "u64({:?})", val),
+            ValueRef::I64(val) => write!(fmt, "i64({:?})", val),
+            ValueRef::Varint(val) => write!(fmt, "varint({:?})", val),
+            ValueRef::F32(val) => write!(fmt, "float({:?})", val),
+            ValueRef::F64(val) => write!(fmt, "double({:?})", val),
+            ValueRef::Str(val) => write!(fmt, "str({:?})", val),
+            ValueRef::Raw(val) => write!(fmt, "raw({:?})", val),
+            ValueRef::Arr(ref val) => write!(fmt, "arr({:?})", val),
+            ValueRef::Map(ref val) => write!(fmt, "map({:?})", val),
+            ValueRef::Encoded(val) => val.fmt(fmt),
+        }
+    }
+}
+
+impl<'a> From<&'a Value> for ValueRef<'a> {
+    fn from(value: &'a Value) -> ValueRef<'a> {
+        match *value {
+            Value::Nil => ValueRef::Nil,
+            Value::Bool(v) => ValueRef::Bool(v),
+            Value::U8(v) => ValueRef::U8(v),
+            Value::I8(v) => ValueRef::I8(v),
+            Value::U16(v) => ValueRef::U16(v),
+            Value::I16(v) => ValueRef::I16(v),
+            Value::U32(v) => ValueRef::U32(v),
+            Value::I32(v) => ValueRef::I32(v),
+            Value::U64(v) => ValueRef::U64(v),
+            Value::I64(v) => ValueRef::I64(v),
+            Value::Varint(v) => ValueRef::Varint(v),
+            Value::F32(v) => ValueRef::F32(v),
+            Value::F64(v) => ValueRef::F64(v),
+            Value::Str(ref v) => ValueRef::Str(v),
+            Value::Raw(ref v) => ValueRef::Raw(v),
+            Value::Arr(ref v) => ValueRef::Arr(v.iter().map(ValueRef::from).collect()),
+            Value::Map(ref v) => ValueRef::Map(
+                v.iter()
+                    .map(|(k, v)| (ValueRef::from(k), ValueRef::from(v)))
+                    .collect(),
+            ),
+            Value::Encoded(ref v) => ValueRef::Encoded(v),
+        }
+    }
+}
+
+impl From<ValueRef<'_>> for Value {
+    fn from(value: ValueRef<'_>) -> Value {
+        value.into_owned()
+    }
+}
+
+/// Converts the scalars with the same checks as `Value`.
+macro_rules! try_from_value_ref {
+    ($($t:ty),*) => {
+        $(
+            impl TryFrom<ValueRef<'_>> for $t {
+                type Error = HpError;
+
+                fn try_from(value: ValueRef<'_>) -> HpResult<$t> {
+                    value.into_owned().try_into()
+                }
+            }
+        )*
+    };
+}
+
+try_from_value_ref!(bool, u8, i8, u16, i16, u32, i32, u64, i64, f32, f64);
+
+macro_rules! try_from_borrowed {
+    ($($t:ty => $variant:ident, $name:expr;)*) => {
+        $(
+            impl<'a> TryFrom<ValueRef<'a>> for $t {
+                type Error = HpError;
+
+                fn try_from(value: ValueRef<'a>) -> HpResult<$t> {
+                    match value {
+                        ValueRef::$variant(val) => Ok(val),
+                        value => Err(type_not_match_error($name, value.get_type())),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+try_from_borrowed! {
+    &'a str => Str, "str";
+    &'a [u8] => Raw, "raw";
+    Vec<ValueRef<'a>> => Arr, "arr";
+    HashMap<ValueRef<'a>, ValueRef<'a>> => Map, "map";
+}
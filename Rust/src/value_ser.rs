@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+
+use serde::ser::{self, Serialize, SerializeMap, SerializeSeq};
+
+use crate::raw_value::TOKEN;
+use crate::{HpError, HpResult, RawValue, Value};
+
+/// Converts `value` into a [`Value`] tree, without encoding it to bytes.
+///
+/// Integers keep their Rust type (`u16` becomes `Value::U16`), structs become
+/// a `Map` keyed by field name and enums follow [`crate::VariantMode::Name`].
+pub fn to_value<T>(value: &T) -> HpResult<Value>
+where
+    T: Serialize + ?Sized,
+{
+    value.serialize(ValueSerializer)
+}
+
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        match *self {
+            Value::Nil => serializer.serialize_unit(),
+            Value::Bool(v) => serializer.serialize_bool(v),
+            Value::U8(v) => serializer.serialize_u8(v),
+            Value::I8(v) => serializer.serialize_i8(v),
+            Value::U16(v) => serializer.serialize_u16(v),
+            Value::I16(v) => serializer.serialize_i16(v),
+            Value::U32(v) => serializer.serialize_u32(v),
+            Value::I32(v) => serializer.serialize_i32(v),
+            Value::U64(v) => serializer.serialize_u64(v),
+            Value::I64(v) | Value::Varint(v) => serializer.serialize_i64(v),
+            Value::F32(v) => serializer.serialize_f32(v),
+            Value::F64(v) => serializer.serialize_f64(v),
+            Value::Str(ref v) => serializer.serialize_str(v),
+            Value::Raw(ref v) => serializer.serialize_bytes(v),
+            Value::Arr(ref v) => {
+                let mut seq = serializer.serialize_seq(Some(v.len()))?;
+                for item in v {
+                    seq.serialize_element(item)?;
+                }
+                seq.end()
+            }
+            Value::Map(ref v) => {
+                let mut map = serializer.serialize_map(Some(v.len()))?;
+                for (key, value) in v {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+            Value::Encoded(ref v) => v.serialize(serializer),
+        }
+    }
+}
+
+/// The serializer behind [`to_value`].
+pub struct ValueSerializer;
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = HpError;
+
+    type SerializeSeq = SerializeVec;
+    type SerializeTuple = SerializeVec;
+    type SerializeTupleStruct = SerializeVec;
+    type SerializeTupleVariant = SerializeVariant<SerializeVec>;
+    type SerializeMap = SerializeHashMap;
+    type SerializeStruct = SerializeHashMap;
+    type SerializeStructVariant = SerializeVariant<SerializeHashMap>;
+
+    fn serialize_bool(self, v: bool) -> HpResult<Value> {
+        Ok(Value::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> HpResult<Value> {
+        Ok(Value::I8(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> HpResult<Value> {
+        Ok(Value::I16(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> HpResult<Value> {
+        Ok(Value::I32(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> HpResult<Value> {
+        Ok(Value::I64(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> HpResult<Value> {
+        Ok(Value::U8(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> HpResult<Value> {
+        Ok(Value::U16(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> HpResult<Value> {
+        Ok(Value::U32(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> HpResult<Value> {
+        Ok(Value::U64(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> HpResult<Value> {
+        Ok(Value::F32(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> HpResult<Value> {
+        Ok(Value::F64(v))
+    }
+
+    fn serialize_char(self, v: char) -> HpResult<Value> {
+        Ok(Value::Str(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> HpResult<Value> {
+        Ok(Value::Str(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> HpResult<Value> {
+        Ok(Value::Raw(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> HpResult<Value> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> HpResult<Value>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> HpResult<Value> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> HpResult<Value> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> HpResult<Value> {
+        Ok(Value::Str(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> HpResult<Value>
+    where
+        T: ?Sized + Serialize,
+    {
+        match value.serialize(self)? {
+            Value::Raw(bytes) if name == TOKEN => Ok(Value::Encoded(RawValue::from_vec(bytes)?)),
+            value => Ok(value),
+        }
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> HpResult<Value>
+    where
+        T: ?Sized + Serialize,
+    {
+        Ok(wrap_variant(variant, to_value(value)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> HpResult<SerializeVec> {
+        Ok(SerializeVec {
+            vec: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> HpResult<SerializeVec> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> HpResult<SerializeVec> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> HpResult<SerializeVariant<SerializeVec>> {
+        Ok(SerializeVariant {
+            variant,
+            content: self.serialize_seq(Some(len))?,
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> HpResult<SerializeHashMap> {
+        Ok(SerializeHashMap {
+            map: HashMap::with_capacity(len.unwrap_or(0)),
+            next_key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> HpResult<SerializeHashMap> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> HpResult<SerializeVariant<SerializeHashMap>> {
+        Ok(SerializeVariant {
+            variant,
+            content: self.serialize_map(Some(len))?,
+        })
+    }
+
+    fn serialize_i128(self, _v: i128) -> HpResult<Value> {
+        Err(ser::Error::custom("i128 is not supported"))
+    }
+
+    fn serialize_u128(self, _v: u128) -> HpResult<Value> {
+        Err(ser::Error::custom("u128 is not supported"))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+pub struct SerializeVec {
+    vec: Vec<Value>,
+}
+
+impl ser::SerializeSeq for SerializeVec {
+    type Ok = Value;
+    type Error = HpError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> HpResult<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.vec.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> HpResult<Value> {
+        Ok(Value::Arr(self.vec))
+    }
+}
+
+impl ser::SerializeTuple for SerializeVec {
+    type Ok = Value;
+    type Error = HpError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> HpResult<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> HpResult<Value> {
+        SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeVec {
+    type Ok = Value;
+    type Error = HpError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> HpResult<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> HpResult<Value> {
+        SerializeSeq::end(self)
+    }
+}
+
+pub struct SerializeHashMap {
+    map: HashMap<Value, Value>,
+    next_key: Option<Value>,
+}
+
+impl ser::SerializeMap for SerializeHashMap {
+    type Ok = Value;
+    type Error = HpError;
+
+    fn serialize_key<T>(&mut self, key: &T) -> HpResult<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.next_key = Some(to_value(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> HpResult<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let key = match self.next_key.take() {
+            Some(key) => key,
+            None => return Err(ser::Error::custom("serialize_value called before serialize_key")),
+        };
+        self.map.insert(key, to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> HpResult<Value> {
+        Ok(Value::Map(self.map))
+    }
+}
+
+impl ser::SerializeStruct for SerializeHashMap {
+    type Ok = Value;
+    type Error = HpError;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> HpResult<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.map.insert(Value::Str(key.to_string()), to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> HpResult<Value> {
+        SerializeMap::end(self)
+    }
+}
+
+/// A variant with content, collected as a one entry map `{ variant: content }`.
+pub struct SerializeVariant<C> {
+    variant: &'static str,
+    content: C,
+}
+
+fn wrap_variant(variant: &'static str, content: Value) -> Value {
+    let mut map = HashMap::with_capacity(1);
+    map.insert(Value::Str(variant.to_string()), content);
+    Value::Map(map)
+}
+
+impl ser::SerializeTupleVariant for SerializeVariant<SerializeVec> {
+    type Ok = Value;
+    type Error = HpError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> HpResult<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        SerializeSeq::serialize_element(&mut self.content, value)
+    }
+
+    fn end(self) -> HpResult<Value> {
+        Ok(wrap_variant(self.variant, SerializeSeq::end(self.content)?))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeVariant<SerializeHashMap> {
+    type Ok = Value;
+    type Error = HpError;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> HpResult<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        ser::SerializeStruct::serialize_field(&mut self.content, key, value)
+    }
+
+    fn end(self) -> HpResult<Value> {
+        Ok(wrap_variant(self.variant, SerializeMap::end(self.content)?))
+    }
+}
@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Display;
-use std::hash::Hash;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use crate::{ErrorKind, HpError, HpResult, RawValue};
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -143,7 +145,10 @@ impl From<ValueDescType> for ValueType {
     }
 }
 
+/// New variants may be added in minor releases, so matches outside this
+/// crate need a wildcard arm.
 #[derive(Clone)]
+#[non_exhaustive]
 pub enum Value {
     Nil,
     Bool(bool),
@@ -162,15 +167,14 @@ pub enum Value {
     Raw(Vec<u8>),
     Arr(Vec<Value>),
     Map(HashMap<Value, Value>),
+    /// A value left in its encoded form, see [`RawValue`].
+    Encoded(RawValue),
     // Kv(String, Vec<Value>),
 }
 
 impl Value {
     pub fn is_nil(&self) -> bool {
-        match self {
-            Value::Nil => true,
-            _ => false
-        }
+        matches!(self, Value::Nil)
     }
 }
 
@@ -178,40 +182,16 @@ impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Self::Bool(l0), Self::Bool(r0)) => l0 == r0,
-            (Self::U8(l0), Self::U8(r0)) => l0 == r0,
-            (Self::I8(l0), Self::I8(r0)) => l0 == r0,
-            (Self::U16(l0), Self::U16(r0)) => l0 == r0,
-            (Self::I16(l0), Self::I16(r0)) => l0 == r0,
-            (Self::U32(l0), Self::U32(r0)) => l0 == r0,
-            (Self::I32(l0), Self::I32(r0)) => l0 == r0,
-            (Self::U64(l0), Self::U64(r0)) => l0 == r0,
-            (Self::I64(l0), Self::I64(r0)) => l0 == r0,
-            (Self::Varint(l0), Self::Varint(r0)) => l0 == r0,
-
-            (Self::Varint(l0), Self::U8(r0)) => l0 == &(*r0 as i64),
-            (Self::Varint(l0), Self::I8(r0)) => l0 == &(*r0 as i64),
-            (Self::Varint(l0), Self::U16(r0)) => l0 == &(*r0 as i64),
-            (Self::Varint(l0), Self::I16(r0)) => l0 == &(*r0 as i64),
-            (Self::Varint(l0), Self::U32(r0)) => l0 == &(*r0 as i64),
-            (Self::Varint(l0), Self::I32(r0)) => l0 == &(*r0 as i64),
-            (Self::Varint(l0), Self::U64(r0)) => l0 == &(*r0 as i64),
-            (Self::Varint(l0), Self::I64(r0)) => l0 == &(*r0 as i64),
-
-            (Self::U8(r0), Self::Varint(l0)) => l0 == &(*r0 as i64),
-            (Self::I8(r0), Self::Varint(l0)) => l0 == &(*r0 as i64),
-            (Self::U16(r0), Self::Varint(l0)) => l0 == &(*r0 as i64),
-            (Self::I16(r0), Self::Varint(l0)) => l0 == &(*r0 as i64),
-            (Self::U32(r0), Self::Varint(l0)) => l0 == &(*r0 as i64),
-            (Self::I32(r0), Self::Varint(l0)) => l0 == &(*r0 as i64),
-            (Self::U64(r0), Self::Varint(l0)) => l0 == &(*r0 as i64),
-            (Self::I64(r0), Self::Varint(l0)) => l0 == &(*r0 as i64),
-
-            (Self::F32(l0), Self::F32(r0)) => l0 == r0,
-            (Self::F64(l0), Self::F64(r0)) => l0 == r0,
+            // Integers are equal by value, whatever their widths.
+            _ if integer(self).is_some() => integer(self) == integer(other),
+            // NaNs of one width are equal, as they are for `Ord` and `Hash`.
+            (Self::F32(l0), Self::F32(r0)) => l0 == r0 || l0.is_nan() && r0.is_nan(),
+            (Self::F64(l0), Self::F64(r0)) => l0 == r0 || l0.is_nan() && r0.is_nan(),
             (Self::Str(l0), Self::Str(r0)) => l0 == r0,
             (Self::Raw(l0), Self::Raw(r0)) => l0 == r0,
             (Self::Arr(l0), Self::Arr(r0)) => l0 == r0,
             (Self::Map(l0), Self::Map(r0)) => l0 == r0,
+            (Self::Encoded(l0), Self::Encoded(r0)) => l0 == r0,
             _ => core::mem::discriminant(self) == core::mem::discriminant(other),
         }
     }
@@ -219,10 +199,81 @@ impl PartialEq for Value {
 
 impl Eq for Value {}
 
+/// Hashes the content, agreeing with `PartialEq`:
+///
+/// - every integer hashes as its value cast to `i64`, since integers of any
+///   widths with the same value are equal
+/// - floats hash their bits, with `-0.0` as `0.0` and every NaN as one NaN,
+///   since NaNs of one width are equal
+/// - a `Map` hashes its entries in any order, as its equality does
 impl Hash for Value {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        core::mem::discriminant(self).hash(state);
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Nil => 0u8.hash(state),
+            Value::Bool(v) => {
+                1u8.hash(state);
+                v.hash(state);
+            }
+            Value::U8(v) => hash_int(*v as i64, state),
+            Value::I8(v) => hash_int(*v as i64, state),
+            Value::U16(v) => hash_int(*v as i64, state),
+            Value::I16(v) => hash_int(*v as i64, state),
+            Value::U32(v) => hash_int(*v as i64, state),
+            Value::I32(v) => hash_int(*v as i64, state),
+            Value::U64(v) => hash_int(*v as i64, state),
+            Value::I64(v) | Value::Varint(v) => hash_int(*v, state),
+            Value::F32(v) => hash_f32(*v, state),
+            Value::F64(v) => hash_f64(*v, state),
+            Value::Str(v) => {
+                13u8.hash(state);
+                v.hash(state);
+            }
+            Value::Raw(v) => {
+                15u8.hash(state);
+                v.hash(state);
+            }
+            Value::Arr(v) => {
+                16u8.hash(state);
+                v.hash(state);
+            }
+            Value::Map(v) => hash_map(v, state),
+            Value::Encoded(v) => {
+                18u8.hash(state);
+                v.hash(state);
+            }
+        }
+    }
+}
+
+pub(crate) fn hash_int<H: Hasher>(v: i64, state: &mut H) {
+    10u8.hash(state);
+    v.hash(state);
+}
+
+pub(crate) fn hash_f32<H: Hasher>(v: f32, state: &mut H) {
+    11u8.hash(state);
+    let v = if v.is_nan() { f32::NAN } else { v + 0.0 };
+    v.to_bits().hash(state);
+}
+
+pub(crate) fn hash_f64<H: Hasher>(v: f64, state: &mut H) {
+    12u8.hash(state);
+    let v = if v.is_nan() { f64::NAN } else { v + 0.0 };
+    v.to_bits().hash(state);
+}
+
+/// Sums the hashes of the entries, each made with a fixed key, so the
+/// iteration order does not matter.
+pub(crate) fn hash_map<K: Hash, V: Hash, H: Hasher>(map: &HashMap<K, V>, state: &mut H) {
+    17u8.hash(state);
+    map.len().hash(state);
+    let mut sum = 0u64;
+    for entry in map {
+        let mut hasher = DefaultHasher::new();
+        entry.hash(&mut hasher);
+        sum = sum.wrapping_add(hasher.finish());
     }
+    sum.hash(state);
 }
 
 impl fmt::Debug for Value {
@@ -242,9 +293,10 @@ impl fmt::Debug for Value {
             Value::F32(val) => write!(fmt, "float({:?})", val),
             Value::F64(val) => write!(fmt, "double({:?})", val),
             Value::Str(ref val) => write!(fmt, "str({:?})", val),
-            Value::Raw(ref val) => write!(fmt, "str({:?})", val),
+            Value::Raw(ref val) => write!(fmt, "raw({:?})", val),
             Value::Arr(ref val) => write!(fmt, "arr({:?})", val),
-            Value::Map(ref val) => write!(fmt, "str({:?})", val),
+            Value::Map(ref val) => write!(fmt, "map({:?})", val),
+            Value::Encoded(ref val) => val.fmt(fmt),
             // Value::Kv(ref key, ref val) => write!(fmt, "key:{:?}, str({:?})", key, val),
         }
     }
@@ -322,6 +374,12 @@ impl From<String> for Value {
     }
 }
 
+impl From<&str> for Value {
+    fn from(val: &str) -> Value {
+        Value::Str(val.to_string())
+    }
+}
+
 impl From<Vec<u8>> for Value {
     fn from(val: Vec<u8>) -> Value {
         Value::Raw(val)
@@ -334,6 +392,12 @@ impl From<Vec<Value>> for Value {
     }
 }
 
+impl From<RawValue> for Value {
+    fn from(val: RawValue) -> Value {
+        Value::Encoded(val)
+    }
+}
+
 impl From<HashMap<Value, Value>> for Value {
     fn from(val: HashMap<Value, Value>) -> Value {
         Value::Map(val)
@@ -347,211 +411,158 @@ impl From<HashMap<Value, Value>> for Value {
 //     }
 // }
 
-impl Into<bool> for Value {
-    fn into(self) -> bool {
-        match self {
-            Value::Bool(val) => val,
-            Value::U8(val) => val != 0,
-            Value::I8(val) => val != 0,
-            Value::Varint(val) => val != 0,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
-        }
-    }
+pub(crate) fn type_not_match(expected: &str, value: &Value) -> HpError {
+    type_not_match_error(expected, get_type_by_value(value))
 }
 
-impl Into<u8> for Value {
-    fn into(self) -> u8 {
-        match self {
-            Value::U8(val) => val,
-            Value::Varint(val) => val as u8,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
-        }
-    }
-}
-
-
-impl Into<ValueType> for Value {
-    fn into(self) -> ValueType {
-        match self {
-            Value::U8(val) => ValueType::from(val),
-            Value::Varint(val) =>  ValueType::from(val as u8),
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
-        }
-    }
-}
-
-impl Into<i8> for Value {
-    fn into(self) -> i8 {
-        match self {
-            Value::I8(val) => val,
-            Value::Varint(val) => val as i8,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
-        }
-    }
+pub(crate) fn type_not_match_error(expected: &str, found: ValueType) -> HpError {
+    HpError::from((
+        ErrorKind::TypeNotMatchError,
+        "type not match",
+        format!("expected {}, found {}", expected, ValueDescType::from(found)),
+    ))
 }
 
-impl Into<u16> for Value {
-    fn into(self) -> u16 {
-        match self {
-            Value::U16(val) => val,
-            Value::Varint(val) => val as u16,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
-        }
-    }
+pub(crate) fn out_of_range(expected: &str, value: &Value) -> HpError {
+    HpError::from((
+        ErrorKind::TypeNotMatchError,
+        "type not match",
+        format!(
+            "expected {}, found {} out of range: {}",
+            expected,
+            ValueDescType::from(get_type_by_value(value)),
+            value
+        ),
+    ))
 }
 
-impl Into<i16> for Value {
-    fn into(self) -> i16 {
-        match self {
-            Value::I16(val) => val,
-            Value::Varint(val) => val as i16,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
-        }
+/// Decodes a `Value::Encoded` to convert its content.
+pub(crate) fn decoded(value: Value) -> HpResult<Value> {
+    if let Value::Encoded(raw) = value {
+        return raw.to_value();
     }
+    Ok(value)
 }
 
-impl Into<u32> for Value {
-    fn into(self) -> u32 {
-        match self {
-            Value::U32(val) => val,
-            Value::Varint(val) => val as u32,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
+pub(crate) fn integer(value: &Value) -> Option<i128> {
+    match *value {
+        Value::U8(v) => Some(v as i128),
+        Value::I8(v) => Some(v as i128),
+        Value::U16(v) => Some(v as i128),
+        Value::I16(v) => Some(v as i128),
+        Value::U32(v) => Some(v as i128),
+        Value::I32(v) => Some(v as i128),
+        Value::U64(v) => Some(v as i128),
+        Value::I64(v) | Value::Varint(v) => Some(v as i128),
+        _ => None,
+    }
+}
+
+/// Takes an integer of any width if its value fits, so a `U64(300)` converts
+/// to `u16` and a `Varint(300)` to `u8` fails.
+macro_rules! try_from_int {
+    ($($t:ident),*) => {
+        $(
+            impl TryFrom<Value> for $t {
+                type Error = HpError;
+
+                fn try_from(value: Value) -> HpResult<$t> {
+                    let value = decoded(value)?;
+                    match integer(&value) {
+                        Some(v) => $t::try_from(v).map_err(|_| out_of_range(stringify!($t), &value)),
+                        None => Err(type_not_match(stringify!($t), &value)),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+try_from_int!(u8, i8, u16, i16, u32, i32, u64, i64);
+
+/// Takes a `Bool`, or an integer of 0 or 1.
+impl TryFrom<Value> for bool {
+    type Error = HpError;
+
+    fn try_from(value: Value) -> HpResult<bool> {
+        match decoded(value)? {
+            Value::Bool(val) => Ok(val),
+            value => match integer(&value) {
+                Some(0) => Ok(false),
+                Some(1) => Ok(true),
+                Some(_) => Err(out_of_range("bool", &value)),
+                None => Err(type_not_match("bool", &value)),
+            },
         }
     }
 }
 
-impl Into<i32> for Value {
-    fn into(self) -> i32 {
-        match self {
-            Value::I32(val) => val,
-            Value::Varint(val) => val as i32,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
-        }
-    }
-}
+impl TryFrom<Value> for ValueType {
+    type Error = HpError;
 
-impl Into<u64> for Value {
-    fn into(self) -> u64 {
-        match self {
-            Value::U64(val) => val,
-            Value::Varint(val) => val as u64,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
-        }
+    fn try_from(value: Value) -> HpResult<ValueType> {
+        u8::try_from(value).map(ValueType::from)
     }
 }
 
-impl Into<i64> for Value {
-    fn into(self) -> i64 {
-        match self {
-            Value::I64(val) => val,
-            Value::Varint(val) => val as i64,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
-        }
-    }
-}
+/// Takes an `F32`, an `F64` within the range of `f32` (rounded to the
+/// nearest), or an integer that `f32` holds exactly.
+impl TryFrom<Value> for f32 {
+    type Error = HpError;
 
-impl Into<f32> for Value {
-    fn into(self) -> f32 {
-        match self {
-            Value::F32(val) => val,
-            Value::Varint(val) => val as f32 / 1000.0,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
+    fn try_from(value: Value) -> HpResult<f32> {
+        match decoded(value)? {
+            Value::F32(val) => Ok(val),
+            Value::F64(val) if !val.is_finite() || val.abs() <= f32::MAX as f64 => Ok(val as f32),
+            value => match integer(&value) {
+                Some(v) if v.abs() <= 1 << f32::MANTISSA_DIGITS => Ok(v as f32),
+                Some(_) => Err(out_of_range("f32", &value)),
+                None if matches!(value, Value::F64(_)) => Err(out_of_range("f32", &value)),
+                None => Err(type_not_match("f32", &value)),
+            },
         }
     }
 }
 
-impl Into<f64> for Value {
-    fn into(self) -> f64 {
-        match self {
-            Value::F64(val) => val,
-            Value::Varint(val) => val as f64 / 1000000.0,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
-        }
-    }
-}
+/// Takes an `F64`, an `F32`, or an integer that `f64` holds exactly.
+impl TryFrom<Value> for f64 {
+    type Error = HpError;
 
-impl Into<String> for Value {
-    fn into(self) -> String {
-        match self {
-            Value::Str(val) => val,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
+    fn try_from(value: Value) -> HpResult<f64> {
+        match decoded(value)? {
+            Value::F64(val) => Ok(val),
+            Value::F32(val) => Ok(val as f64),
+            value => match integer(&value) {
+                Some(v) if v.abs() <= 1 << f64::MANTISSA_DIGITS => Ok(v as f64),
+                Some(_) => Err(out_of_range("f64", &value)),
+                None => Err(type_not_match("f64", &value)),
+            },
         }
     }
 }
 
-impl Into<Vec<u8>> for Value {
-    fn into(self) -> Vec<u8> {
-        match self {
-            Value::Raw(val) => val,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
-        }
-    }
-}
+macro_rules! try_from_variant {
+    ($($t:ty => $variant:ident, $name:expr;)*) => {
+        $(
+            impl TryFrom<Value> for $t {
+                type Error = HpError;
 
-impl Into<Vec<Value>> for Value {
-    fn into(self) -> Vec<Value> {
-        match self {
-            Value::Arr(val) => val,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
-        }
-    }
+                fn try_from(value: Value) -> HpResult<$t> {
+                    match decoded(value)? {
+                        Value::$variant(val) => Ok(val),
+                        value => Err(type_not_match($name, &value)),
+                    }
+                }
+            }
+        )*
+    };
 }
 
-impl Into<HashMap<Value, Value>> for Value {
-    fn into(self) -> HashMap<Value, Value> {
-        match self {
-            Value::Map(val) => val,
-            _ => panic!(
-                "into error type {}",
-                ValueDescType::from(get_type_by_value(&self))
-            ),
-        }
-    }
+try_from_variant! {
+    String => Str, "str";
+    Vec<u8> => Raw, "raw";
+    Vec<Value> => Arr, "arr";
+    HashMap<Value, Value> => Map, "map";
 }
 
 pub fn get_type_by_value(value: &Value) -> ValueType {
@@ -572,6 +583,7 @@ pub fn get_type_by_value(value: &Value) -> ValueType {
         Value::Raw(_) => ValueType::Raw,
         Value::Arr(_) => ValueType::Arr,
         Value::Map(_) => ValueType::Map,
+        Value::Encoded(ref val) => val.get_type(),
         // Value::Kv(_, _) => ValueType::Kv,
         _ => ValueType::Nil,
     }
@@ -0,0 +1,628 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+
+use crate::pretty::is_ident;
+use crate::transcode::{Token, TokenReader, TokenWriter};
+use crate::{HpResult, Value, ValueRef, ValueType};
+
+/// One step of a [`Path`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    /// An item of an `Arr`.
+    Index(usize),
+    /// The value of a key of a `Map`.
+    Key(Value),
+}
+
+/// Where a visitor is, from the root, kept up to date by the walk.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Path {
+    segments: Vec<Segment>,
+}
+
+impl Path {
+    pub fn new() -> Path {
+        Path::default()
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// The key of the value in its `Map`, if in one.
+    pub fn key(&self) -> Option<&Value> {
+        match self.segments.last() {
+            Some(Segment::Key(key)) => Some(key),
+            _ => None,
+        }
+    }
+
+    pub fn push(&mut self, segment: Segment) {
+        self.segments.push(segment);
+    }
+
+    pub fn pop(&mut self) -> Option<Segment> {
+        self.segments.pop()
+    }
+
+    /// The steps as a path of [`crate::PatchOp`].
+    pub fn to_values(&self) -> Vec<Value> {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Index(i) => Value::Varint(*i as i64),
+                Segment::Key(key) => key.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Written the way [`crate::PrettyConfig::with_paths`] does, `$.a[3].b`.
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_char('$')?;
+        for segment in &self.segments {
+            match segment {
+                Segment::Index(i) => write!(f, "[{}]", i)?,
+                Segment::Key(Value::Str(key)) if is_ident(key) => write!(f, ".{}", key)?,
+                Segment::Key(Value::Str(key)) => write!(f, "[{:?}]", key)?,
+                Segment::Key(key) => write!(f, "[{}]", key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Walks a value, every method defaulting to walking into what it is given.
+///
+/// Override a method to act on what it receives, calling the matching
+/// `walk_*` function to go on into the items, or not calling it to skip them.
+/// Scalars go to `visit_scalar`, and so do `Encoded` values, which are not
+/// decoded. Map keys are not visited, they are the last [`Segment`] of the
+/// path of their value.
+pub trait Visit {
+    fn visit_value(&mut self, path: &mut Path, value: &Value) {
+        walk_value(self, path, value);
+    }
+
+    fn visit_arr(&mut self, path: &mut Path, arr: &[Value]) {
+        walk_arr(self, path, arr);
+    }
+
+    fn visit_map(&mut self, path: &mut Path, map: &HashMap<Value, Value>) {
+        walk_map(self, path, map);
+    }
+
+    fn visit_scalar(&mut self, _path: &Path, _value: &Value) {}
+}
+
+pub fn walk_value<V: Visit + ?Sized>(visitor: &mut V, path: &mut Path, value: &Value) {
+    match value {
+        Value::Arr(arr) => visitor.visit_arr(path, arr),
+        Value::Map(map) => visitor.visit_map(path, map),
+        value => visitor.visit_scalar(path, value),
+    }
+}
+
+pub fn walk_arr<V: Visit + ?Sized>(visitor: &mut V, path: &mut Path, arr: &[Value]) {
+    for (i, item) in arr.iter().enumerate() {
+        path.push(Segment::Index(i));
+        visitor.visit_value(path, item);
+        path.pop();
+    }
+}
+
+pub fn walk_map<V: Visit + ?Sized>(visitor: &mut V, path: &mut Path, map: &HashMap<Value, Value>) {
+    for (key, value) in map {
+        path.push(Segment::Key(key.clone()));
+        visitor.visit_value(path, value);
+        path.pop();
+    }
+}
+
+/// Walks a value in place, as [`Visit`] does.
+pub trait VisitMut {
+    fn visit_value_mut(&mut self, path: &mut Path, value: &mut Value) {
+        walk_value_mut(self, path, value);
+    }
+
+    fn visit_arr_mut(&mut self, path: &mut Path, arr: &mut Vec<Value>) {
+        walk_arr_mut(self, path, arr);
+    }
+
+    fn visit_map_mut(&mut self, path: &mut Path, map: &mut HashMap<Value, Value>) {
+        walk_map_mut(self, path, map);
+    }
+
+    fn visit_scalar_mut(&mut self, _path: &Path, _value: &mut Value) {}
+}
+
+pub fn walk_value_mut<V: VisitMut + ?Sized>(visitor: &mut V, path: &mut Path, value: &mut Value) {
+    match value {
+        Value::Arr(arr) => visitor.visit_arr_mut(path, arr),
+        Value::Map(map) => visitor.visit_map_mut(path, map),
+        value => visitor.visit_scalar_mut(path, value),
+    }
+}
+
+pub fn walk_arr_mut<V: VisitMut + ?Sized>(visitor: &mut V, path: &mut Path, arr: &mut [Value]) {
+    for (i, item) in arr.iter_mut().enumerate() {
+        path.push(Segment::Index(i));
+        visitor.visit_value_mut(path, item);
+        path.pop();
+    }
+}
+
+pub fn walk_map_mut<V: VisitMut + ?Sized>(visitor: &mut V, path: &mut Path, map: &mut HashMap<Value, Value>) {
+    for (key, value) in map.iter_mut() {
+        path.push(Segment::Key(key.clone()));
+        visitor.visit_value_mut(path, value);
+        path.pop();
+    }
+}
+
+/// Builds a new value out of one, as [`Visit`] walks it; the defaults give
+/// back the same value.
+pub trait Fold {
+    fn fold_value(&mut self, path: &mut Path, value: Value) -> Value {
+        walk_fold_value(self, path, value)
+    }
+
+    fn fold_arr(&mut self, path: &mut Path, arr: Vec<Value>) -> Vec<Value> {
+        walk_fold_arr(self, path, arr)
+    }
+
+    fn fold_map(&mut self, path: &mut Path, map: HashMap<Value, Value>) -> HashMap<Value, Value> {
+        walk_fold_map(self, path, map)
+    }
+
+    fn fold_scalar(&mut self, _path: &Path, value: Value) -> Value {
+        value
+    }
+}
+
+pub fn walk_fold_value<F: Fold + ?Sized>(folder: &mut F, path: &mut Path, value: Value) -> Value {
+    match value {
+        Value::Arr(arr) => Value::Arr(folder.fold_arr(path, arr)),
+        Value::Map(map) => Value::Map(folder.fold_map(path, map)),
+        value => folder.fold_scalar(path, value),
+    }
+}
+
+pub fn walk_fold_arr<F: Fold + ?Sized>(folder: &mut F, path: &mut Path, arr: Vec<Value>) -> Vec<Value> {
+    arr.into_iter()
+        .enumerate()
+        .map(|(i, item)| {
+            path.push(Segment::Index(i));
+            let item = folder.fold_value(path, item);
+            path.pop();
+            item
+        })
+        .collect()
+}
+
+pub fn walk_fold_map<F: Fold + ?Sized>(folder: &mut F, path: &mut Path, map: HashMap<Value, Value>) -> HashMap<Value, Value> {
+    map.into_iter()
+        .map(|(key, value)| {
+            path.push(Segment::Key(key.clone()));
+            let value = folder.fold_value(path, value);
+            path.pop();
+            (key, value)
+        })
+        .collect()
+}
+
+impl Value {
+    pub fn visit<V: Visit + ?Sized>(&self, visitor: &mut V) {
+        visitor.visit_value(&mut Path::new(), self);
+    }
+
+    pub fn visit_mut<V: VisitMut + ?Sized>(&mut self, visitor: &mut V) {
+        visitor.visit_value_mut(&mut Path::new(), self);
+    }
+
+    pub fn fold<F: Fold + ?Sized>(self, folder: &mut F) -> Value {
+        folder.fold_value(&mut Path::new(), self)
+    }
+}
+
+/// Whether to walk into an `Arr` or `Map` of an encoded value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    /// Reads past its items without visiting them.
+    Skip,
+}
+
+/// Walks an encoded value as the decoder reads it, without building a
+/// [`Value`], see [`visit_encoded`].
+pub trait VisitEncoded<'a> {
+    /// Called on an `Arr` or `Map` of `len` items before them.
+    fn enter(&mut self, _path: &Path, _value_type: ValueType, _len: usize) -> Flow {
+        Flow::Continue
+    }
+
+    /// Called after the items of an `Arr` or `Map` that was not skipped.
+    fn leave(&mut self, _path: &Path, _value_type: ValueType) {}
+
+    fn visit_scalar(&mut self, _path: &Path, _value: ValueRef<'a>) {}
+}
+
+/// Walks the value of a message of a single field, as written by
+/// [`crate::to_vec`], strings borrowed from `bytes`.
+pub fn visit_encoded<'a, V: VisitEncoded<'a> + ?Sized>(bytes: &'a [u8], visitor: &mut V) -> HpResult<()> {
+    let mut reader = TokenReader::new(bytes)?;
+    visit_tokens(&mut reader, &mut Path::new(), visitor)?;
+    reader.finish()
+}
+
+fn visit_tokens<'a, V: VisitEncoded<'a> + ?Sized>(reader: &mut TokenReader<'a>, path: &mut Path, visitor: &mut V) -> HpResult<()> {
+    let mark = reader.mark();
+    let token = reader.next()?;
+    let (value_type, len) = match token {
+        Token::Arr(len) => (ValueType::Arr, len),
+        Token::Map(len) => (ValueType::Map, len),
+        token => {
+            visitor.visit_scalar(path, scalar(token));
+            return Ok(());
+        }
+    };
+    if visitor.enter(path, value_type, len) == Flow::Skip {
+        reader.reset(mark);
+        return reader.skip();
+    }
+    for i in 0..len {
+        let segment = match value_type {
+            ValueType::Map => Segment::Key(reader.value()?.into_owned()),
+            _ => Segment::Index(i),
+        };
+        path.push(segment);
+        visit_tokens(reader, path, visitor)?;
+        path.pop();
+    }
+    visitor.leave(path, value_type);
+    Ok(())
+}
+
+/// What to do with part of an encoded value, see [`transform_encoded`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    /// Writes it out, walking into an `Arr` or `Map`.
+    Keep,
+    /// Leaves the item out of its `Arr`, or the entry out of its `Map`. The
+    /// root becomes `nil`.
+    Remove,
+    Replace(Value),
+}
+
+/// Changes an encoded value as the decoder reads it, see [`transform_encoded`].
+pub trait TransformEncoded<'a> {
+    /// Called on an `Arr` or `Map` of `len` items before them.
+    fn enter(&mut self, _path: &Path, _value_type: ValueType, _len: usize) -> Edit {
+        Edit::Keep
+    }
+
+    fn transform_scalar(&mut self, _path: &Path, _value: ValueRef<'a>) -> Edit {
+        Edit::Keep
+    }
+}
+
+/// Decodes the value of a message of a single field, as written by
+/// [`crate::to_vec`], and encodes it back with the edits of `transform` in
+/// the same pass. What is kept is copied without building a [`Value`].
+pub fn transform_encoded<'a, T: TransformEncoded<'a> + ?Sized>(bytes: &'a [u8], transform: &mut T) -> HpResult<Vec<u8>> {
+    let mut reader = TokenReader::new(bytes)?;
+    let mut writer = TokenWriter::new();
+    if !transform_tokens(&mut reader, &mut writer, &mut Path::new(), transform)? {
+        writer.write(Token::Nil)?;
+    }
+    reader.finish()?;
+    writer.finish()
+}
+
+/// Copies the next value with its edits, returning whether it was kept.
+fn transform_tokens<'a, T: TransformEncoded<'a> + ?Sized>(
+    reader: &mut TokenReader<'a>,
+    writer: &mut TokenWriter<'a>,
+    path: &mut Path,
+    transform: &mut T,
+) -> HpResult<bool> {
+    let mark = reader.mark();
+    let token = reader.next()?;
+    let (value_type, len) = match token {
+        Token::Arr(len) => (ValueType::Arr, len),
+        Token::Map(len) => (ValueType::Map, len),
+        token => {
+            return match transform.transform_scalar(path, scalar(token)) {
+                Edit::Keep => writer.write(token).map(|_| true),
+                Edit::Remove => Ok(false),
+                Edit::Replace(value) => write_value(writer, &value).map(|_| true),
+            };
+        }
+    };
+    match transform.enter(path, value_type, len) {
+        Edit::Keep => {}
+        edit => {
+            reader.reset(mark);
+            reader.skip()?;
+            return match edit {
+                Edit::Replace(value) => write_value(writer, &value).map(|_| true),
+                _ => Ok(false),
+            };
+        }
+    }
+    let offset = writer.begin(value_type);
+    let mut kept = 0;
+    for i in 0..len {
+        let rollback = writer.len();
+        let segment = match value_type {
+            ValueType::Map => {
+                let key = reader.mark();
+                let segment = Segment::Key(reader.value()?.into_owned());
+                reader.reset(key);
+                copy_tokens(reader, writer)?;
+                segment
+            }
+            _ => Segment::Index(i),
+        };
+        path.push(segment);
+        if transform_tokens(reader, writer, path, transform)? {
+            kept += 1;
+        } else {
+            // Drops the key of a removed value.
+            writer.truncate(rollback);
+        }
+        path.pop();
+    }
+    writer.end(offset, kept)?;
+    Ok(true)
+}
+
+/// Copies the next value as is.
+fn copy_tokens<'a>(reader: &mut TokenReader<'a>, writer: &mut TokenWriter<'a>) -> HpResult<()> {
+    let token = reader.next()?;
+    writer.write(token)?;
+    let len = match token {
+        Token::Arr(len) => len,
+        Token::Map(len) => len * 2,
+        _ => 0,
+    };
+    for _ in 0..len {
+        copy_tokens(reader, writer)?;
+    }
+    Ok(())
+}
+
+/// Writes a value of the caller, its strings inline as they are not borrowed
+/// from the input.
+fn write_value(writer: &mut TokenWriter, value: &Value) -> HpResult<()> {
+    let token = match *value {
+        Value::Nil => Token::Nil,
+        Value::Bool(v) => Token::Bool(v),
+        Value::U8(v) => Token::U8(v),
+        Value::I8(v) => Token::I8(v),
+        Value::U16(v) => Token::U16(v),
+        Value::I16(v) => Token::I16(v),
+        Value::U32(v) => Token::U32(v),
+        Value::I32(v) => Token::I32(v),
+        Value::U64(v) => Token::U64(v),
+        Value::I64(v) => Token::I64(v),
+        Value::Varint(v) => Token::Varint(v),
+        Value::F32(v) => Token::F32(v),
+        Value::F64(v) => Token::F64(v),
+        Value::Str(ref v) => return writer.write_str_inline(v),
+        Value::Raw(ref v) => return writer.write_raw(v),
+        Value::Arr(ref arr) => {
+            writer.write(Token::Arr(arr.len()))?;
+            for item in arr {
+                write_value(writer, item)?;
+            }
+            return Ok(());
+        }
+        Value::Map(ref map) => {
+            writer.write(Token::Map(map.len()))?;
+            for (key, value) in map {
+                write_value(writer, key)?;
+                write_value(writer, value)?;
+            }
+            return Ok(());
+        }
+        Value::Encoded(ref raw) => return write_value(writer, &raw.to_value()?),
+    };
+    writer.write(token)
+}
+
+fn scalar(token: Token) -> ValueRef {
+    match token {
+        Token::Bool(v) => ValueRef::Bool(v),
+        Token::U8(v) => ValueRef::U8(v),
+        Token::I8(v) => ValueRef::I8(v),
+        Token::U16(v) => ValueRef::U16(v),
+        Token::I16(v) => ValueRef::I16(v),
+        Token::U32(v) => ValueRef::U32(v),
+        Token::I32(v) => ValueRef::I32(v),
+        Token::U64(v) => ValueRef::U64(v),
+        Token::I64(v) => ValueRef::I64(v),
+        Token::Varint(v) => ValueRef::Varint(v),
+        Token::F32(v) => ValueRef::F32(v),
+        Token::F64(v) => ValueRef::F64(v),
+        Token::Str(v) => ValueRef::Str(v),
+        Token::Raw(v) => ValueRef::Raw(v),
+        Token::Nil | Token::Arr(_) | Token::Map(_) => ValueRef::Nil,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{from_slice, to_vec};
+
+    fn users() -> Value {
+        let mut users = Value::Nil;
+        for i in 0..3u8 {
+            let mut user = Value::Nil;
+            user.insert("id", Value::U8(i)).unwrap();
+            user.insert("password", "secret").unwrap();
+            user.insert("score", Value::F64(i as f64 * 1.5)).unwrap();
+            user.insert("tags", Value::Arr(vec![Value::Nil, Value::from("a")])).unwrap();
+            users.push(user).unwrap();
+        }
+        let mut root = Value::Nil;
+        root.insert("users", users).unwrap();
+        root.insert("internal", Value::Arr(vec![Value::U8(1); 10])).unwrap();
+        root
+    }
+
+    /// Counts scalars, listing the paths of the strings, and skips `internal`.
+    #[derive(Default)]
+    struct Count {
+        scalars: usize,
+        strings: Vec<String>,
+    }
+
+    impl Visit for Count {
+        fn visit_value(&mut self, path: &mut Path, value: &Value) {
+            if path.key() != Some(&Value::from("internal")) {
+                walk_value(self, path, value);
+            }
+        }
+
+        fn visit_scalar(&mut self, path: &Path, value: &Value) {
+            self.scalars += 1;
+            if let Value::Str(_) = value {
+                self.strings.push(path.to_string());
+            }
+        }
+    }
+
+    impl<'a> VisitEncoded<'a> for Count {
+        fn enter(&mut self, path: &Path, _value_type: ValueType, _len: usize) -> Flow {
+            match path.key() {
+                Some(key) if key == &Value::from("internal") => Flow::Skip,
+                _ => Flow::Continue,
+            }
+        }
+
+        fn visit_scalar(&mut self, path: &Path, value: ValueRef<'a>) {
+            self.scalars += 1;
+            if let ValueRef::Str(_) = value {
+                self.strings.push(path.to_string());
+            }
+        }
+    }
+
+    /// Drops passwords and nils, turns whole floats into integers.
+    struct Clean;
+
+    impl VisitMut for Clean {
+        fn visit_arr_mut(&mut self, path: &mut Path, arr: &mut Vec<Value>) {
+            arr.retain(|item| !item.is_nil());
+            walk_arr_mut(self, path, arr);
+        }
+
+        fn visit_map_mut(&mut self, path: &mut Path, map: &mut HashMap<Value, Value>) {
+            map.remove(&Value::from("password"));
+            walk_map_mut(self, path, map);
+        }
+
+        fn visit_scalar_mut(&mut self, _path: &Path, value: &mut Value) {
+            if let Value::F64(v) = *value {
+                if v.fract() == 0.0 {
+                    *value = Value::Varint(v as i64);
+                }
+            }
+        }
+    }
+
+    impl Fold for Clean {
+        fn fold_arr(&mut self, path: &mut Path, arr: Vec<Value>) -> Vec<Value> {
+            let arr = arr.into_iter().filter(|item| !item.is_nil()).collect();
+            walk_fold_arr(self, path, arr)
+        }
+
+        fn fold_map(&mut self, path: &mut Path, mut map: HashMap<Value, Value>) -> HashMap<Value, Value> {
+            map.remove(&Value::from("password"));
+            walk_fold_map(self, path, map)
+        }
+
+        fn fold_scalar(&mut self, _path: &Path, value: Value) -> Value {
+            match value {
+                Value::F64(v) if v.fract() == 0.0 => Value::Varint(v as i64),
+                value => value,
+            }
+        }
+    }
+
+    impl<'a> TransformEncoded<'a> for Clean {
+        fn transform_scalar(&mut self, path: &Path, value: ValueRef<'a>) -> Edit {
+            match value {
+                _ if path.key() == Some(&Value::from("password")) => Edit::Remove,
+                ValueRef::Nil => Edit::Remove,
+                ValueRef::F64(v) if v.fract() == 0.0 => Edit::Replace(Value::Varint(v as i64)),
+                _ => Edit::Keep,
+            }
+        }
+    }
+
+    #[test]
+    fn test_visit() {
+        let value = users();
+        let mut count = Count::default();
+        value.visit(&mut count);
+        assert_eq!(count.scalars, 15);
+        count.strings.sort();
+        assert_eq!(count.strings[0], "$.users[0].password");
+        assert_eq!(count.strings[1], "$.users[0].tags[1]");
+
+        let bytes = to_vec(&value).unwrap();
+        let mut encoded = Count::default();
+        visit_encoded(&bytes, &mut encoded).unwrap();
+        encoded.strings.sort();
+        assert_eq!((encoded.scalars, encoded.strings), (count.scalars, count.strings));
+
+        let mut cleaned = value.clone();
+        cleaned.visit_mut(&mut Clean);
+        assert_eq!(cleaned["users"][0]["score"], Value::Varint(0));
+        assert_eq!(cleaned["users"][1]["score"], Value::F64(1.5));
+        assert!(cleaned["users"][2].get("password").is_none());
+        assert_eq!(cleaned["users"][2]["tags"].as_array().unwrap().len(), 1);
+        assert_eq!(value.clone().fold(&mut Clean), cleaned);
+
+        let bytes = transform_encoded(&bytes, &mut Clean).unwrap();
+        assert_eq!(from_slice::<Value>(&bytes).unwrap(), cleaned);
+
+        // Removing the root leaves nil, replacing a container skips it.
+        struct Lengths;
+        impl<'a> TransformEncoded<'a> for Lengths {
+            fn enter(&mut self, path: &Path, _value_type: ValueType, len: usize) -> Edit {
+                match path.len() {
+                    0 => Edit::Keep,
+                    _ => Edit::Replace(Value::Varint(len as i64)),
+                }
+            }
+        }
+        let bytes = transform_encoded(&to_vec(&value).unwrap(), &mut Lengths).unwrap();
+        let dropped = from_slice::<Value>(&bytes).unwrap();
+        assert_eq!((&dropped["users"], &dropped["internal"]), (&Value::Varint(3), &Value::Varint(10)));
+        let bytes = transform_encoded(&to_vec(&Value::U8(1)).unwrap(), &mut Clean).unwrap();
+        assert_eq!(from_slice::<Value>(&bytes).unwrap(), Value::U8(1));
+        struct Remove;
+        impl TransformEncoded<'_> for Remove {
+            fn transform_scalar(&mut self, _path: &Path, _value: ValueRef<'_>) -> Edit {
+                Edit::Remove
+            }
+        }
+        let bytes = transform_encoded(&to_vec(&Value::U8(1)).unwrap(), &mut Remove).unwrap();
+        assert_eq!(from_slice::<Value>(&bytes).unwrap(), Value::Nil);
+    }
+}